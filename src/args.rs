@@ -1,5 +1,14 @@
 use clap::Parser;
 
+use crate::{
+    balancer::BalancingStrategy,
+    headers::AddedHeader,
+    permission::DeviceRole,
+    preference::ServerRule,
+    scheduler::DevicePriority,
+    usage::{self, UsageGrouping},
+};
+
 #[derive(Parser)]
 #[command(name = "ollana")]
 #[command(bin_name = "ollana")]
@@ -10,10 +19,26 @@ pub enum Args {
     #[clap(subcommand)]
     /// Manage devices
     Device(DeviceCommands),
+    /// Pair with another device using a one-time code
+    Pair(PairArgs),
+    #[clap(subcommand)]
+    /// Manage pinned server certificates
+    KnownServers(KnownServersCommands),
+    /// Show how much devices have used this server
+    Stats(StatsArgs),
+    /// Show what the running daemon is doing
+    Status(ControlArgs),
+    /// Show the servers the running daemon knows
+    Servers(ControlArgs),
+    #[clap(subcommand)]
+    /// Inspect the configuration
+    Config(ConfigCommands),
 }
 
 #[derive(clap::Args)]
 pub struct ServeArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
     #[arg(
         short = 'd',
         long,
@@ -42,16 +67,288 @@ pub struct ServeArgs {
         help = "Force server mode regardless of Ollama availability (useful for boot order issues)"
     )]
     pub force_server_mode: bool,
+    #[arg(
+        long = "hybrid",
+        value_name = "PORT",
+        num_args = 0..=1,
+        default_missing_value = "11439",
+        help = "While the local Ollama is up, also use the other servers through a client proxy on 127.0.0.1:PORT (11439 if no port is given)",
+        required = false
+    )]
+    pub hybrid: Option<u16>,
+    #[arg(
+        long = "ipv4-multicast-group",
+        value_name = "ADDRESS",
+        help = "IPv4 multicast group to use for discovery in addition to broadcast",
+        required = false
+    )]
+    pub ipv4_multicast_group: Option<std::net::Ipv4Addr>,
+    #[arg(
+        long = "discovery",
+        value_name = "METHOD",
+        value_enum,
+        default_value_t = DiscoveryMethod::All,
+        help = "Discovery method to find servers and to be found by clients"
+    )]
+    pub discovery: DiscoveryMethod,
+    #[arg(
+        long = "server",
+        value_name = "HOST[:PORT]",
+        help = "Address of a server to use in client mode in addition to discovered ones (can be repeated)",
+        required = false
+    )]
+    pub servers: Vec<String>,
+    #[arg(
+        long = "balancing",
+        value_name = "STRATEGY",
+        value_enum,
+        default_value_t = BalancingStrategy::LeastInFlight,
+        help = "How to spread requests across servers in client mode"
+    )]
+    pub balancing: BalancingStrategy,
+    #[arg(
+        long = "annotate-models",
+        default_value_t = false,
+        help = "Show which servers have each model in /api/tags and /api/ps responses in client mode"
+    )]
+    pub annotate_models: bool,
+    #[arg(
+        long = "prefer",
+        value_name = "DEVICE_ID|IP[:PORT]",
+        help = "Server to prefer in client mode, most preferred first (can be repeated)",
+        required = false,
+        conflicts_with = "pin"
+    )]
+    pub prefer: Vec<ServerRule>,
+    #[arg(
+        long = "pin",
+        value_name = "DEVICE_ID|IP[:PORT]",
+        help = "The only server to use in client mode",
+        required = false
+    )]
+    pub pin: Option<ServerRule>,
+    #[arg(
+        long = "switch-back-delay",
+        value_name = "SECONDS",
+        default_value_t = 30,
+        help = "How long a preferred server has to be online before requests are switched back to it"
+    )]
+    pub switch_back_delay: u64,
+    #[arg(
+        long = "max-parallel-requests",
+        value_name = "NUMBER",
+        default_value_t = 4,
+        help = "Generations to run on Ollama at once in server mode, others are queued (0 disables queueing)"
+    )]
+    pub max_parallel_requests: usize,
+    #[arg(
+        long = "remove-header",
+        value_name = "NAME",
+        help = "Header to remove from forwarded requests, e.g. X-Forwarded-For (can be repeated)",
+        required = false
+    )]
+    pub remove_headers: Vec<reqwest::header::HeaderName>,
+    #[arg(
+        long = "add-header",
+        value_name = "NAME: VALUE",
+        help = "Header to set on forwarded requests (can be repeated)",
+        required = false
+    )]
+    pub add_headers: Vec<AddedHeader>,
+    #[arg(
+        long = "metrics",
+        value_name = "ADDRESS",
+        num_args = 0..=1,
+        default_missing_value = "127.0.0.1:11438",
+        help = "Serve Prometheus metrics at /metrics on a separate port (127.0.0.1:11438 if no address is given)",
+        required = false
+    )]
+    pub metrics: Option<std::net::SocketAddr>,
+    #[arg(
+        long = "control-socket",
+        value_name = "PATH",
+        help = "Unix socket to answer `ollana status` on (ollana.sock in the data directory by default)",
+        required = false
+    )]
+    pub control_socket: Option<std::path::PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct ConfigArgs {
+    #[arg(
+        long = "config",
+        value_name = "FILE",
+        help = "Configuration file (ollana.toml in the data directory by default)",
+        required = false
+    )]
+    pub path: Option<std::path::PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct ControlArgs {
+    #[arg(
+        long = "socket",
+        value_name = "PATH",
+        help = "Control socket of the running daemon, see `ollana serve --control-socket`",
+        required = false
+    )]
+    pub socket: Option<std::path::PathBuf>,
+}
+
+#[derive(clap::Args)]
+pub struct PairArgs {
+    #[arg(
+        value_name = "HOST[:PORT]",
+        help = "Address of the device showing the code, leave out to show a code on this device"
+    )]
+    pub address: Option<String>,
+    #[arg(
+        long = "code",
+        value_name = "CODE",
+        help = "The code shown on the other device (asked for if not given)",
+        requires = "address"
+    )]
+    pub code: Option<String>,
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(clap::Args)]
+pub struct StatsArgs {
+    #[arg(
+        long = "by",
+        value_name = "GROUPING",
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [UsageGrouping::Device, UsageGrouping::Model],
+        help = "What to group the usage by (can be repeated or comma-separated)"
+    )]
+    pub groupings: Vec<UsageGrouping>,
+    #[arg(
+        long = "since",
+        value_name = "TIME",
+        value_parser = usage::parse_time,
+        help = "Only count usage from this time on, e.g. 2025-06-01, 2025-06-01T12:00:00Z or 24h (ago)"
+    )]
+    pub since: Option<u64>,
+    #[arg(
+        long = "until",
+        value_name = "TIME",
+        value_parser = usage::parse_time,
+        help = "Only count usage before this time, in the same formats as --since"
+    )]
+    pub until: Option<u64>,
+    #[arg(
+        long = "device",
+        value_name = "DEVICE_ID",
+        help = "Only count usage of this device"
+    )]
+    pub device: Option<String>,
+    #[arg(
+        long = "model",
+        value_name = "MODEL",
+        help = "Only count usage of this model"
+    )]
+    pub model: Option<String>,
+    #[arg(
+        long = "format",
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t = StatsFormat::Text,
+        help = "Output format"
+    )]
+    pub format: StatsFormat,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+pub enum StatsFormat {
+    /// One line per group
+    Text,
+    /// Comma-separated values with a header row
+    Csv,
+    /// A JSON array
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+pub enum DiscoveryMethod {
+    /// UDP broadcast and multicast
+    Udp,
+    /// mDNS / DNS-SD (_ollana._tcp.local)
+    Mdns,
+    /// All of the above
+    All,
+}
+
+impl DiscoveryMethod {
+    pub fn uses_udp(&self) -> bool {
+        matches!(self, DiscoveryMethod::Udp | DiscoveryMethod::All)
+    }
+
+    pub fn uses_mdns(&self) -> bool {
+        matches!(self, DiscoveryMethod::Mdns | DiscoveryMethod::All)
+    }
 }
 
 #[derive(clap::Subcommand)]
 pub enum DeviceCommands {
     /// Show your Device ID
     Show,
-    /// Show list of allowed Device IDs
+    /// Show list of allowed devices
     List,
-    /// Allow a given Device ID
-    Allow { id: String },
+    /// Allow a given Device ID, or change the name, labels or permissions of an allowed one
+    Allow {
+        id: String,
+        /// A human-friendly name of the device
+        #[arg(long)]
+        name: Option<String>,
+        /// A free-form label of the device, can be repeated
+        #[arg(long = "label")]
+        labels: Vec<String>,
+        /// Which endpoints the device may use on this server
+        #[arg(long, value_enum)]
+        role: Option<DeviceRole>,
+        /// A model the device may use on this server, can be repeated, `*` allows all models
+        #[arg(long = "model")]
+        models: Vec<String>,
+        /// Generation requests per minute the device may make on this server, 0 for no limit
+        #[arg(long)]
+        requests_per_minute: Option<u32>,
+        /// Generation requests the device may have in flight on this server, 0 for no limit
+        #[arg(long)]
+        max_concurrent: Option<u32>,
+        /// Tokens the device may generate per hour on this server, 0 for no limit
+        #[arg(long)]
+        tokens_per_hour: Option<u64>,
+        /// In which order queued requests of the device are run on this server
+        #[arg(long, value_enum)]
+        priority: Option<DevicePriority>,
+    },
     /// Disable a given Device ID
     Disable { id: String },
+    /// Show devices that have tried to connect without being allowed
+    Pending,
+    /// Allow a pending Device ID
+    Approve {
+        id: String,
+        /// A human-friendly name of the device, the host name it reported by default
+        #[arg(long)]
+        name: Option<String>,
+    },
+}
+
+#[derive(clap::Subcommand)]
+pub enum KnownServersCommands {
+    /// Show pinned server certificate fingerprints
+    List,
+    /// Forget the pinned certificate of a server, e.g. after it has been reinstalled
+    Forget { address: String },
+    /// Forget all pinned certificates
+    Reset,
+}
+
+#[derive(clap::Subcommand)]
+pub enum ConfigCommands {
+    /// Show the effective configuration, after applying the file and the environment
+    Show(ConfigArgs),
 }
@@ -0,0 +1,672 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use url::Url;
+
+use crate::{http_client_builder, ollama::normalize_model_name, socket_addr_url};
+
+// Weight of the latest sample in the exponentially weighted moving average of latencies
+const LATENCY_EWMA_WEIGHT: f64 = 0.3;
+
+/// How to spread requests across servers.
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Debug)]
+pub enum BalancingStrategy {
+    /// Send requests to each server in turn
+    RoundRobin,
+    /// Send requests to the server with the fewest requests in flight
+    LeastInFlight,
+    /// Send requests to the server with the lowest response latency
+    LowestLatency,
+}
+
+/// A server the client proxy can forward requests to.
+pub struct Upstream {
+    pub server: SocketAddr,
+    pub device_id: String,
+    pub url: Url,
+    pub client: reqwest::Client,
+    // Lower values are preferred, see `ServerPreferences`
+    pub priority: usize,
+    added_at: Instant,
+    in_flight: AtomicUsize,
+    // Zero means there were no measurements yet
+    latency_micros: AtomicU64,
+    responded_at: Mutex<Option<Instant>>,
+}
+
+/// Spreads requests across all healthy upstreams according to a [`BalancingStrategy`].
+///
+/// Requests for a particular model are only spread across the upstreams which have the model
+/// loaded or, failing that, installed. Only the upstreams with the best priority are used.
+pub struct Balancer {
+    strategy: BalancingStrategy,
+    switch_back_delay: Duration,
+    upstreams: RwLock<Vec<Arc<Upstream>>>,
+    models: RwLock<ModelIndex>,
+    next: AtomicUsize,
+}
+
+/// Maps (normalized) model names to the servers that have them.
+#[derive(Default)]
+struct ModelIndex {
+    installed: HashMap<String, HashSet<SocketAddr>>,
+    loaded: HashMap<String, HashSet<SocketAddr>>,
+}
+
+/// Keeps a request counted as in-flight for an upstream until dropped.
+pub struct InFlightGuard {
+    upstream: Arc<Upstream>,
+}
+
+impl Upstream {
+    pub fn new(
+        server: SocketAddr,
+        device_id: String,
+        priority: usize,
+        tls_config: rustls::ClientConfig,
+    ) -> anyhow::Result<Self> {
+        let url = socket_addr_url("https", server)?;
+        let client = http_client_builder(server)
+            .use_preconfigured_tls(tls_config)
+            .build()?;
+
+        Ok(Self {
+            server,
+            device_id,
+            url,
+            client,
+            priority,
+            added_at: Instant::now(),
+            in_flight: AtomicUsize::new(0),
+            latency_micros: AtomicU64::new(0),
+            responded_at: Mutex::new(None),
+        })
+    }
+
+    /// Returns the number of requests currently being served by the upstream.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Returns the average latency of the upstream, if it has been measured.
+    pub fn latency(&self) -> Option<Duration> {
+        match self.latency_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// Returns when the upstream has last responded to a request or a liveness check.
+    pub fn responded_at(&self) -> Option<Instant> {
+        *self.responded_at.lock().unwrap()
+    }
+
+    /// Records a latency measurement, e.g. the time until response headers were received.
+    pub fn record_latency(&self, latency: Duration) {
+        *self.responded_at.lock().unwrap() = Some(Instant::now());
+
+        let sample = latency.as_micros().max(1) as f64;
+
+        // Lost updates under contention are fine for an estimate
+        let average = match self.latency_micros.load(Ordering::Relaxed) {
+            0 => sample,
+            current => LATENCY_EWMA_WEIGHT * sample + (1.0 - LATENCY_EWMA_WEIGHT) * current as f64,
+        };
+
+        self.latency_micros
+            .store(average.max(1.0) as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a request as in-flight until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        InFlightGuard {
+            upstream: self.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Upstream {
+    /// An upstream reached over plain HTTP, added at the given time.
+    pub(crate) fn plain(server: SocketAddr, priority: usize, added_at: Instant) -> Self {
+        Self {
+            server,
+            device_id: format!("device{}", server.port()),
+            url: socket_addr_url("http", server).unwrap(),
+            client: http_client_builder(server).build().unwrap(),
+            priority,
+            added_at,
+            in_flight: AtomicUsize::new(0),
+            latency_micros: AtomicU64::new(0),
+            responded_at: Mutex::new(None),
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.upstream.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Balancer {
+    pub fn new(strategy: BalancingStrategy, switch_back_delay: Duration) -> Self {
+        Self {
+            strategy,
+            switch_back_delay,
+            upstreams: RwLock::new(Vec::new()),
+            models: RwLock::new(ModelIndex::default()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds an upstream unless there is already one for the same server.
+    pub fn add(&self, upstream: Arc<Upstream>) {
+        let mut upstreams = self.upstreams.write().unwrap();
+
+        if !upstreams.iter().any(|u| u.server == upstream.server) {
+            upstreams.push(upstream);
+        }
+    }
+
+    /// Removes the upstream of the given server, requests already in flight are not affected.
+    pub fn remove(&self, server: SocketAddr) {
+        self.upstreams
+            .write()
+            .unwrap()
+            .retain(|upstream| upstream.server != server);
+
+        self.models.write().unwrap().remove_server(server);
+    }
+
+    /// Replaces the installed and loaded models known for the given server.
+    pub fn update_models(&self, server: SocketAddr, installed: Vec<String>, loaded: Vec<String>) {
+        let mut models = self.models.write().unwrap();
+
+        models.remove_server(server);
+
+        for model in installed {
+            models
+                .installed
+                .entry(normalize_model_name(&model))
+                .or_default()
+                .insert(server);
+        }
+
+        for model in loaded {
+            models
+                .loaded
+                .entry(normalize_model_name(&model))
+                .or_default()
+                .insert(server);
+        }
+    }
+
+    /// Returns all upstreams in the order they were added.
+    pub fn upstreams(&self) -> Vec<Arc<Upstream>> {
+        self.upstreams.read().unwrap().clone()
+    }
+
+    /// Returns the upstreams requests are currently spread across, i.e. the ones with the best
+    /// priority.
+    pub fn active(&self) -> Vec<Arc<Upstream>> {
+        self.active_at(Instant::now())
+    }
+
+    fn active_at(&self, now: Instant) -> Vec<Arc<Upstream>> {
+        let upstreams = self.upstreams.read().unwrap();
+
+        self.prioritized(upstreams.iter().collect(), now)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Picks an upstream for the next request, `None` if there are no upstreams.
+    ///
+    /// If a model is given, servers that have the model loaded are preferred, followed by servers
+    /// that have it installed. If no server is known to have the model, all servers are considered.
+    ///
+    /// Out of those, only the servers with the best priority are used. A server that has just come
+    /// online takes over from servers with a worse priority only after the switch-back delay, so
+    /// that a flapping server doesn't make requests bounce between servers.
+    ///
+    /// # Arguments
+    /// * `model` - The model requested by the client, if any.
+    /// * `excluded` - Servers that must not be picked, e.g. because they have just failed.
+    ///
+    pub fn select(&self, model: Option<&str>, excluded: &[SocketAddr]) -> Option<Arc<Upstream>> {
+        self.select_at(model, excluded, Instant::now())
+    }
+
+    fn select_at(
+        &self,
+        model: Option<&str>,
+        excluded: &[SocketAddr],
+        now: Instant,
+    ) -> Option<Arc<Upstream>> {
+        let upstreams: Vec<_> = self
+            .upstreams
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|upstream| !excluded.contains(&upstream.server))
+            .cloned()
+            .collect();
+        let upstreams = match model {
+            Some(model) => self.models.read().unwrap().candidates(&upstreams, model),
+            None => upstreams.iter().collect(),
+        };
+        let upstreams = self.prioritized(upstreams, now);
+
+        if upstreams.is_empty() {
+            return None;
+        }
+
+        // Rotate the starting point, so that ties are broken in a round-robin fashion
+        let offset = self.next.fetch_add(1, Ordering::Relaxed) % upstreams.len();
+        let rotated = upstreams.iter().cycle().skip(offset).take(upstreams.len());
+
+        let selected = match self.strategy {
+            BalancingStrategy::RoundRobin => upstreams.get(offset),
+            BalancingStrategy::LeastInFlight => rotated.min_by_key(|upstream| upstream.in_flight()),
+            BalancingStrategy::LowestLatency => {
+                // Unmeasured upstreams come first, so that they get measured
+                rotated.min_by_key(|upstream| upstream.latency().unwrap_or_default())
+            }
+        };
+
+        selected.map(|upstream| (*upstream).clone())
+    }
+
+    fn prioritized<'a>(
+        &self,
+        upstreams: Vec<&'a Arc<Upstream>>,
+        now: Instant,
+    ) -> Vec<&'a Arc<Upstream>> {
+        // Servers that have been online for long enough decide, unless there are none of them yet
+        let settled_priority = upstreams
+            .iter()
+            .filter(|upstream| {
+                now.saturating_duration_since(upstream.added_at) >= self.switch_back_delay
+            })
+            .map(|upstream| upstream.priority)
+            .min();
+        let priority =
+            settled_priority.or_else(|| upstreams.iter().map(|upstream| upstream.priority).min());
+
+        upstreams
+            .into_iter()
+            .filter(|upstream| Some(upstream.priority) == priority)
+            .collect()
+    }
+}
+
+impl ModelIndex {
+    fn remove_server(&mut self, server: SocketAddr) {
+        for servers in self.installed.values_mut().chain(self.loaded.values_mut()) {
+            servers.remove(&server);
+        }
+
+        self.installed.retain(|_, servers| !servers.is_empty());
+        self.loaded.retain(|_, servers| !servers.is_empty());
+    }
+
+    fn candidates<'a>(
+        &self,
+        upstreams: &'a [Arc<Upstream>],
+        model: &str,
+    ) -> Vec<&'a Arc<Upstream>> {
+        let model = normalize_model_name(model);
+
+        [self.loaded.get(&model), self.installed.get(&model)]
+            .into_iter()
+            .flatten()
+            .map(|servers| {
+                upstreams
+                    .iter()
+                    .filter(|upstream| servers.contains(&upstream.server))
+                    .collect::<Vec<_>>()
+            })
+            .find(|candidates| !candidates.is_empty())
+            .unwrap_or_else(|| upstreams.iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 20], port))
+    }
+
+    fn upstream(port: u16, priority: usize) -> Arc<Upstream> {
+        upstream_added_at(port, priority, Instant::now())
+    }
+
+    fn upstream_added_at(port: u16, priority: usize, added_at: Instant) -> Arc<Upstream> {
+        Arc::new(Upstream::plain(server(port), priority, added_at))
+    }
+
+    fn balancer(strategy: BalancingStrategy, upstreams: &[&Arc<Upstream>]) -> Balancer {
+        let balancer = Balancer::new(strategy, Duration::ZERO);
+
+        for upstream in upstreams {
+            balancer.add((*upstream).clone());
+        }
+
+        balancer
+    }
+
+    fn select_port(balancer: &Balancer, excluded: &[SocketAddr]) -> Option<u16> {
+        balancer
+            .select(None, excluded)
+            .map(|upstream| upstream.server.port())
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let (a, b, c) = (upstream(1, 0), upstream(2, 0), upstream(3, 0));
+        let balancer = balancer(BalancingStrategy::RoundRobin, &[&a, &b, &c]);
+
+        let ports: Vec<_> = (0..6)
+            .map(|_| select_port(&balancer, &[]).unwrap())
+            .collect();
+
+        assert_eq!(ports, [1, 2, 3, 1, 2, 3]);
+    }
+
+    #[test]
+    fn least_in_flight_picks_the_least_busy_server() {
+        let (a, b, c) = (upstream(1, 0), upstream(2, 0), upstream(3, 0));
+        let balancer = balancer(BalancingStrategy::LeastInFlight, &[&a, &b, &c]);
+        let _a1 = a.track();
+        let _a2 = a.track();
+        let _c1 = c.track();
+
+        for _ in 0..3 {
+            assert_eq!(select_port(&balancer, &[]), Some(2));
+        }
+
+        let _b1 = b.track();
+        let _b2 = b.track();
+
+        assert_eq!(select_port(&balancer, &[]), Some(3));
+    }
+
+    #[test]
+    fn least_in_flight_breaks_ties_in_turns() {
+        let (a, b) = (upstream(1, 0), upstream(2, 0));
+        let balancer = balancer(BalancingStrategy::LeastInFlight, &[&a, &b]);
+
+        let ports: Vec<_> = (0..4)
+            .map(|_| select_port(&balancer, &[]).unwrap())
+            .collect();
+
+        assert_eq!(ports, [1, 2, 1, 2]);
+    }
+
+    #[test]
+    fn lowest_latency_measures_new_servers_first() {
+        let (a, b, c) = (upstream(1, 0), upstream(2, 0), upstream(3, 0));
+        let balancer = balancer(BalancingStrategy::LowestLatency, &[&a, &b, &c]);
+
+        a.record_latency(Duration::from_millis(100));
+        b.record_latency(Duration::from_millis(10));
+
+        assert_eq!(select_port(&balancer, &[]), Some(3));
+
+        c.record_latency(Duration::from_millis(50));
+
+        for _ in 0..3 {
+            assert_eq!(select_port(&balancer, &[]), Some(2));
+        }
+    }
+
+    #[test]
+    fn excluded_servers_are_never_picked() {
+        let (a, b, c) = (upstream(1, 0), upstream(2, 0), upstream(3, 0));
+
+        for strategy in [
+            BalancingStrategy::RoundRobin,
+            BalancingStrategy::LeastInFlight,
+            BalancingStrategy::LowestLatency,
+        ] {
+            let balancer = balancer(strategy, &[&a, &b, &c]);
+
+            for _ in 0..3 {
+                assert_eq!(select_port(&balancer, &[server(1), server(3)]), Some(2));
+            }
+
+            assert_eq!(
+                select_port(&balancer, &[server(1), server(2), server(3)]),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn no_upstreams() {
+        let balancer = balancer(BalancingStrategy::RoundRobin, &[]);
+
+        assert!(balancer.select(None, &[]).is_none());
+        assert!(balancer.select(Some("llama3"), &[]).is_none());
+    }
+
+    #[test]
+    fn removed_servers_are_not_picked() {
+        let (a, b) = (upstream(1, 0), upstream(2, 0));
+        let balancer = balancer(BalancingStrategy::RoundRobin, &[&a, &b]);
+
+        balancer.remove(server(1));
+
+        for _ in 0..2 {
+            assert_eq!(select_port(&balancer, &[]), Some(2));
+        }
+    }
+
+    #[test]
+    fn adding_a_server_twice_keeps_one() {
+        let balancer = balancer(
+            BalancingStrategy::RoundRobin,
+            &[&upstream(1, 0), &upstream(1, 0)],
+        );
+
+        assert_eq!(balancer.upstreams().len(), 1);
+    }
+
+    fn candidate_ports(balancer: &Balancer, model: &str) -> Vec<u16> {
+        let upstreams = balancer.upstreams();
+        let mut ports: Vec<_> = balancer
+            .models
+            .read()
+            .unwrap()
+            .candidates(&upstreams, model)
+            .into_iter()
+            .map(|upstream| upstream.server.port())
+            .collect();
+
+        ports.sort();
+        ports
+    }
+
+    fn models(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn candidates_normalize_tags() {
+        let (a, b) = (upstream(1, 0), upstream(2, 0));
+        let balancer = balancer(BalancingStrategy::RoundRobin, &[&a, &b]);
+
+        balancer.update_models(server(1), models(&["llama3:latest"]), vec![]);
+        balancer.update_models(server(2), models(&["llama3:8b", "library/qwen"]), vec![]);
+
+        assert_eq!(candidate_ports(&balancer, "llama3"), [1]);
+        assert_eq!(candidate_ports(&balancer, "llama3:latest"), [1]);
+        assert_eq!(candidate_ports(&balancer, "llama3:8b"), [2]);
+        assert_eq!(candidate_ports(&balancer, "library/qwen:latest"), [2]);
+    }
+
+    #[test]
+    fn candidates_prefer_loaded_models() {
+        let (a, b, c) = (upstream(1, 0), upstream(2, 0), upstream(3, 0));
+        let balancer = balancer(BalancingStrategy::RoundRobin, &[&a, &b, &c]);
+
+        balancer.update_models(server(1), models(&["llama3"]), vec![]);
+        balancer.update_models(server(2), models(&["llama3"]), models(&["llama3"]));
+
+        assert_eq!(candidate_ports(&balancer, "llama3"), [2]);
+
+        for _ in 0..3 {
+            assert_eq!(
+                balancer
+                    .select(Some("llama3"), &[])
+                    .map(|upstream| upstream.server.port()),
+                Some(2)
+            );
+        }
+
+        // Servers that only have the model installed are next in line
+        assert_eq!(
+            balancer
+                .select(Some("llama3"), &[server(2)])
+                .map(|upstream| upstream.server.port()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn candidates_fall_back_to_any_server_for_unknown_models() {
+        let (a, b) = (upstream(1, 0), upstream(2, 0));
+        let balancer = balancer(BalancingStrategy::RoundRobin, &[&a, &b]);
+
+        balancer.update_models(server(1), models(&["llama3"]), vec![]);
+
+        assert_eq!(candidate_ports(&balancer, "mistral"), [1, 2]);
+
+        // Also when the only servers with the model are excluded
+        assert_eq!(
+            balancer
+                .select(Some("llama3"), &[server(1)])
+                .map(|upstream| upstream.server.port()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn candidates_forget_dropped_models() {
+        let (a, b) = (upstream(1, 0), upstream(2, 0));
+        let balancer = balancer(BalancingStrategy::RoundRobin, &[&a, &b]);
+
+        balancer.update_models(server(1), models(&["llama3", "mistral"]), vec![]);
+        balancer.update_models(server(2), models(&["llama3"]), vec![]);
+
+        assert_eq!(candidate_ports(&balancer, "llama3"), [1, 2]);
+
+        balancer.update_models(server(1), models(&["mistral"]), vec![]);
+
+        assert_eq!(candidate_ports(&balancer, "llama3"), [2]);
+
+        balancer.remove(server(1));
+
+        // Nobody is known to have the model anymore
+        assert_eq!(candidate_ports(&balancer, "mistral"), [2]);
+        assert!(!balancer
+            .models
+            .read()
+            .unwrap()
+            .installed
+            .contains_key("mistral:latest"));
+    }
+
+    fn ports(upstreams: Vec<Arc<Upstream>>) -> Vec<u16> {
+        upstreams
+            .iter()
+            .map(|upstream| upstream.server.port())
+            .collect()
+    }
+
+    #[test]
+    fn recovered_preferred_server_is_held_back() {
+        let start = Instant::now();
+        let delay = Duration::from_secs(30);
+        let balancer = Balancer::new(BalancingStrategy::RoundRobin, delay);
+        let select_at = |now| {
+            balancer
+                .select_at(None, &[], now)
+                .map(|upstream| upstream.server.port())
+        };
+
+        // The preferred server went offline, the fallback took over
+        balancer.add(upstream_added_at(2, 1, start));
+
+        let recovered_at = start + Duration::from_secs(60);
+        balancer.add(upstream_added_at(1, 0, recovered_at));
+
+        for elapsed in [0, 1, 29] {
+            let now = recovered_at + Duration::from_secs(elapsed);
+
+            assert_eq!(select_at(now), Some(2), "{}s after recovery", elapsed);
+            assert_eq!(ports(balancer.active_at(now)), [2]);
+        }
+
+        for elapsed in [30, 31, 600] {
+            let now = recovered_at + Duration::from_secs(elapsed);
+
+            assert_eq!(select_at(now), Some(1), "{}s after recovery", elapsed);
+            assert_eq!(ports(balancer.active_at(now)), [1]);
+        }
+    }
+
+    #[test]
+    fn preferred_server_is_used_right_away_when_nothing_is_settled() {
+        let start = Instant::now();
+        let balancer = Balancer::new(BalancingStrategy::RoundRobin, Duration::from_secs(30));
+
+        balancer.add(upstream_added_at(2, 1, start));
+        balancer.add(upstream_added_at(1, 0, start));
+
+        assert_eq!(ports(balancer.active_at(start)), [1]);
+        assert_eq!(
+            balancer
+                .select_at(None, &[], start)
+                .map(|upstream| upstream.server.port()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn failed_preferred_server_falls_back_immediately() {
+        let start = Instant::now();
+        let balancer = Balancer::new(BalancingStrategy::RoundRobin, Duration::from_secs(30));
+        let now = start + Duration::from_secs(60);
+
+        balancer.add(upstream_added_at(1, 0, start));
+        balancer.add(upstream_added_at(2, 1, start));
+        balancer.add(upstream_added_at(3, 2, now));
+
+        assert_eq!(
+            balancer
+                .select_at(None, &[server(1)], now)
+                .map(|upstream| upstream.server.port()),
+            Some(2)
+        );
+
+        balancer.remove(server(1));
+        balancer.remove(server(2));
+
+        // The only server left has just come online, but there is no other choice
+        assert_eq!(ports(balancer.active_at(now)), [3]);
+    }
+}
@@ -5,24 +5,36 @@ use std::{
 };
 
 use log::{debug, info};
-use rustls::pki_types::{pem::PemObject, PrivatePkcs8KeyDer};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::WebPkiSupportedAlgorithms,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme,
+};
 
 use crate::get_local_dir;
 
 const DEVICE_CERT_PEM: &str = "device_cert.pem";
 const DEVICE_KEY_PEM: &str = "device_key.pem";
-const HTTP_SERVER_CERT_PEM: &str = "http_server_cert.pem";
-const HTTP_SERVER_KEY_PEM: &str = "http_server_key.pem";
 
 pub struct Certs {
     dir: PathBuf,
 }
 
+/// The device certificate and private key, used to authenticate to servers.
+pub struct DeviceIdentity {
+    pub cert: CertificateDer<'static>,
+    pub key: PrivateKeyDer<'static>,
+}
+
 impl Certs {
     pub fn new() -> anyhow::Result<Self> {
-        let dir = get_local_dir()?;
+        Ok(Self::with_dir(get_local_dir()?))
+    }
 
-        Ok(Self { dir })
+    pub(crate) fn with_dir(dir: PathBuf) -> Self {
+        Self { dir }
     }
 
     /// Generates a device certificate and key.
@@ -37,41 +49,39 @@ impl Certs {
         self.gen_x509(&cert_path, &signing_key_path)
     }
 
-    /// Retrieves the device key bytes from the PEM file.
+    /// Computes the Device ID from the device certificate.
     ///
-    /// This function reads the private PKCS#8 DER-encoded secret key from the PEM file located at `DEVICE_KEY_PEM`
-    /// within the configured directory. It returns a vector containing the DER-encoded key bytes.
+    /// See [`device_id`] for how the ID is derived.
     ///
     /// # Errors
     ///
     /// Returns an error if there is any issue reading or parsing the PEM file.
-    pub fn get_device_key_bytes(&self) -> anyhow::Result<Vec<u8>> {
-        let signing_key_path = self.dir.join(DEVICE_KEY_PEM);
-        let der = PrivatePkcs8KeyDer::from_pem_file(signing_key_path)?;
+    pub fn get_device_id(&self) -> anyhow::Result<String> {
+        let cert = CertificateDer::from_pem_file(self.dir.join(DEVICE_CERT_PEM))?;
 
-        Ok(der.secret_pkcs8_der().to_vec())
+        device_id(&cert)
     }
 
-    /// Generates an HTTP server certificate and key.
+    /// Loads the device certificate and key as an identity for authenticating to servers.
     ///
-    /// This function creates a HTTP server certificate and signing key, storing them in files named
-    /// "http_server_cert.pem" and "http_server_key.pem" respectively within the directory specified by `self.dir`.
+    /// # Errors
     ///
-    pub fn gen_http_server(&self) -> anyhow::Result<()> {
-        let cert_path = self.dir.join(HTTP_SERVER_CERT_PEM);
-        let signing_key_path = self.dir.join(HTTP_SERVER_KEY_PEM);
+    /// Returns an error if there is any issue reading or parsing the PEM files.
+    pub fn get_device_identity(&self) -> anyhow::Result<DeviceIdentity> {
+        let cert = CertificateDer::from_pem_file(self.dir.join(DEVICE_CERT_PEM))?;
+        let key = PrivateKeyDer::from_pem_file(self.dir.join(DEVICE_KEY_PEM))?;
 
-        self.gen_x509(&cert_path, &signing_key_path)
+        Ok(DeviceIdentity { cert, key })
     }
 
-    /// Gets the HTTP server's certificate and private key files.
+    /// Gets the device certificate and private key files.
     ///
-    /// This function retrieves the PEM-encoded X.509 certificate file and the corresponding RSA or ECDSA
-    /// private key file used for HTTPS communication by the server.
+    /// The server proxy serves HTTPS with the device certificate, so that clients can tell the
+    /// server's Device ID from the TLS handshake.
     ///
-    pub fn get_http_server_files(&self) -> anyhow::Result<(File, File)> {
-        let cert_file = File::open(self.dir.join(HTTP_SERVER_CERT_PEM))?;
-        let signing_key_file = File::open(self.dir.join(HTTP_SERVER_KEY_PEM))?;
+    pub fn get_device_files(&self) -> anyhow::Result<(File, File)> {
+        let cert_file = File::open(self.dir.join(DEVICE_CERT_PEM))?;
+        let signing_key_file = File::open(self.dir.join(DEVICE_KEY_PEM))?;
 
         Ok((cert_file, signing_key_file))
     }
@@ -114,3 +124,135 @@ impl Certs {
         Ok(())
     }
 }
+
+impl Clone for DeviceIdentity {
+    fn clone(&self) -> Self {
+        Self {
+            cert: self.cert.clone(),
+            key: self.key.clone_key(),
+        }
+    }
+}
+
+/// Derives a Device ID from a device certificate: the SHA-256 digest of its public key
+/// (DER-encoded SubjectPublicKeyInfo).
+///
+/// Only the owner of the matching private key can present the certificate during a TLS handshake,
+/// which is what makes the ID usable for authorization.
+///
+/// # Errors
+///
+/// Returns an error if the certificate cannot be parsed.
+pub fn device_id(cert: &CertificateDer) -> anyhow::Result<String> {
+    let cert = webpki::EndEntityCert::try_from(cert)
+        .map_err(|error| anyhow::anyhow!("Invalid device certificate: {}", error))?;
+
+    Ok(sha256::digest(cert.subject_public_key_info().as_ref()))
+}
+
+/// Asks TLS clients for their (self-signed) device certificates.
+///
+/// Certificates aren't checked against any CA, only the proof of possession of the private key is,
+/// and devices are authorized by their Device IDs afterwards. Clients without a certificate are
+/// still accepted, so that unauthenticated endpoints keep working.
+///
+/// As a server certificate verifier it accepts any device certificate the same way, for exchanges
+/// that authenticate the peer by other means (see [`crate::pairing`]).
+#[derive(Debug)]
+pub struct DeviceCertVerifier {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl Default for DeviceCertVerifier {
+    fn default() -> Self {
+        Self {
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ClientCertVerifier for DeviceCertVerifier {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+impl ServerCertVerifier for DeviceCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{constants, get_local_dir, proxy::PROXY_DEFAULT_WORKERS_NUMBER};
+
+const CONFIG_TOML: &str = "ollana.toml";
+const ENV_PREFIX: &str = "OLLANA_";
+
+/// Settings of the daemon, read from `ollana.toml` and overridden by `OLLANA_*` environment
+/// variables.
+///
+/// Every setting has a default, so the file only needs the ones to change. The variable of a
+/// setting is named after its table and key, e.g. `OLLANA_SERVER_PROXY_PORT` for `port` in
+/// `[server_proxy]`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub ollama: OllamaConfig,
+    pub client_proxy: ClientProxyConfig,
+    pub server_proxy: ServerProxyConfig,
+    pub discovery: DiscoveryConfig,
+    pub manager: ManagerConfig,
+    pub pairing: PairingConfig,
+    /// The file the settings were read from, if it exists
+    #[serde(skip)]
+    pub file: Option<PathBuf>,
+    /// The environment variables that overrode settings
+    #[serde(skip)]
+    pub env_overrides: Vec<String>,
+}
+
+/// The local Ollama.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OllamaConfig {
+    pub url: String,
+    /// How often to check whether Ollama is up, to switch between client and server mode
+    pub check_interval_secs: u64,
+    /// Failed checks in a row before switching to client mode
+    pub down_checks: u32,
+}
+
+/// The proxy forwarding requests of local applications to servers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientProxyConfig {
+    pub address: IpAddr,
+    /// The port in client mode, see `ollana serve --hybrid` for the hybrid mode
+    pub port: u16,
+    pub workers: usize,
+}
+
+/// The proxy serving the local Ollama to clients.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerProxyConfig {
+    /// IPv6 clients are served as well on the unspecified address `0.0.0.0`
+    pub address: IpAddr,
+    pub port: u16,
+    pub workers: usize,
+}
+
+/// The UDP discovery of servers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// The UDP port servers answer on
+    pub port: u16,
+    pub ipv6_multicast_group: Ipv6Addr,
+    /// How often clients look for servers
+    pub broadcast_interval_secs: u64,
+    /// How often servers check whether the local Ollama is up, they don't answer while it isn't
+    pub liveness_interval_secs: u64,
+}
+
+/// The client's handling of servers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ManagerConfig {
+    /// How often registered servers are checked
+    pub liveness_interval_secs: u64,
+    /// How often servers given with `--server` are retried
+    pub static_servers_interval_secs: u64,
+}
+
+/// `ollana pair`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PairingConfig {
+    pub port: u16,
+}
+
+impl Default for OllamaConfig {
+    fn default() -> Self {
+        Self {
+            url: format!(
+                "http://{}:{}",
+                constants::OLLAMA_DEFAULT_ADDRESS,
+                constants::OLLAMA_DEFAULT_PORT
+            ),
+            check_interval_secs: 5,
+            down_checks: 3,
+        }
+    }
+}
+
+impl Default for ClientProxyConfig {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: constants::OLLANA_CLIENT_PROXY_DEFAULT_PORT,
+            workers: PROXY_DEFAULT_WORKERS_NUMBER,
+        }
+    }
+}
+
+impl Default for ServerProxyConfig {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: constants::OLLANA_SERVER_PROXY_DEFAULT_PORT,
+            workers: PROXY_DEFAULT_WORKERS_NUMBER,
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            port: constants::OLLANA_SERVER_DEFAULT_DISCOVERY_PORT,
+            ipv6_multicast_group: constants::OLLANA_DISCOVERY_IPV6_MULTICAST_GROUP,
+            broadcast_interval_secs: 5,
+            liveness_interval_secs: 10,
+        }
+    }
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        Self {
+            liveness_interval_secs: 10,
+            static_servers_interval_secs: 30,
+        }
+    }
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        Self {
+            port: constants::OLLANA_PAIRING_DEFAULT_PORT,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the settings from a file and the environment.
+    ///
+    /// # Arguments
+    /// * `path` - The file to read, `ollana.toml` in the local data directory if not given. Only
+    ///   an explicitly given file has to exist.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read or parsed, has unknown settings, or a setting
+    /// from the file or the environment is invalid.
+    ///
+    pub fn load(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let is_explicit = path.is_some();
+        let path = match path {
+            Some(path) => path,
+            None => get_local_dir()?.join(CONFIG_TOML),
+        };
+
+        let mut config = if is_explicit || path.exists() {
+            let mut config = Self::read(&path)?;
+            config.file = Some(path);
+            config
+        } else {
+            Self::default()
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Returns the settings as TOML, as they would be written to `ollana.toml`.
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        toml::to_string_pretty(self).map_err(anyhow::Error::new)
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let toml_str = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Couldn't read {}: {}", path.display(), e))?;
+
+        toml::from_str(&toml_str).map_err(|e| anyhow::anyhow!("Invalid {}: {}", path.display(), e))
+    }
+
+    /// Overrides settings with the `OLLANA_<TABLE>_<KEY>` environment variables that are set.
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        let mut table = toml::Table::try_from(&*self)?;
+        let mut overrides = Vec::new();
+
+        for (section, values) in table.iter_mut() {
+            let Some(values) = values.as_table_mut() else {
+                continue;
+            };
+
+            for (key, value) in values.iter_mut() {
+                let name = format!("{}{}_{}", ENV_PREFIX, section, key).to_uppercase();
+                let Ok(raw) = std::env::var(&name) else {
+                    continue;
+                };
+
+                *value = match value {
+                    toml::Value::Integer(_) => {
+                        raw.trim().parse().map(toml::Value::Integer).map_err(|_| {
+                            anyhow::anyhow!("{} must be a number, got '{}'", name, raw)
+                        })?
+                    }
+                    _ => toml::Value::String(raw),
+                };
+                overrides.push(name);
+            }
+        }
+
+        if overrides.is_empty() {
+            return Ok(());
+        }
+
+        let config: Self = table
+            .try_into()
+            .map_err(|e| anyhow::anyhow!("Invalid {}: {}", overrides.join(" or "), e))?;
+
+        self.ollama = config.ollama;
+        self.client_proxy = config.client_proxy;
+        self.server_proxy = config.server_proxy;
+        self.discovery = config.discovery;
+        self.manager = config.manager;
+        self.pairing = config.pairing;
+        self.env_overrides = overrides;
+
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+
+        if let Err(error) = self.ollama.url() {
+            errors.push(error.to_string());
+        }
+
+        for (name, value) in [
+            (
+                "ollama.check_interval_secs",
+                self.ollama.check_interval_secs,
+            ),
+            ("ollama.down_checks", u64::from(self.ollama.down_checks)),
+            ("client_proxy.workers", self.client_proxy.workers as u64),
+            ("server_proxy.workers", self.server_proxy.workers as u64),
+            (
+                "discovery.broadcast_interval_secs",
+                self.discovery.broadcast_interval_secs,
+            ),
+            (
+                "discovery.liveness_interval_secs",
+                self.discovery.liveness_interval_secs,
+            ),
+            (
+                "manager.liveness_interval_secs",
+                self.manager.liveness_interval_secs,
+            ),
+            (
+                "manager.static_servers_interval_secs",
+                self.manager.static_servers_interval_secs,
+            ),
+            ("client_proxy.port", u64::from(self.client_proxy.port)),
+            ("server_proxy.port", u64::from(self.server_proxy.port)),
+            ("discovery.port", u64::from(self.discovery.port)),
+            ("pairing.port", u64::from(self.pairing.port)),
+        ] {
+            if value == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+
+        errors.extend(port_conflicts(&self.tcp_ports()));
+
+        if !self.discovery.ipv6_multicast_group.is_multicast() {
+            errors.push(format!(
+                "discovery.ipv6_multicast_group must be a multicast address (ff00::/8), got {}",
+                self.discovery.ipv6_multicast_group
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            let source = match (&self.file, self.env_overrides.is_empty()) {
+                (Some(file), true) => file.display().to_string(),
+                (Some(file), false) => format!("{} and the environment", file.display()),
+                (None, _) => "the environment".to_string(),
+            };
+
+            anyhow::bail!(
+                "Invalid configuration in {}:\n  {}",
+                source,
+                errors.join("\n  ")
+            )
+        }
+    }
+
+    /// Checks that the ports given to `ollana serve` don't clash with the configured ones, so that
+    /// a clash is reported at startup rather than once the mode that binds the port starts.
+    ///
+    /// # Arguments
+    /// * `hybrid_port` - The port of the client proxy in hybrid mode, if enabled.
+    /// * `metrics_address` - The address metrics are served on, if enabled.
+    ///
+    /// # Errors
+    /// Returns an error listing the ports that clash.
+    ///
+    pub fn validate_serve(
+        &self,
+        hybrid_port: Option<u16>,
+        metrics_address: Option<SocketAddr>,
+    ) -> anyhow::Result<()> {
+        let mut tcp_ports = self.tcp_ports().to_vec();
+
+        tcp_ports.extend(hybrid_port.map(|port| ("the --hybrid port", port)));
+        tcp_ports.extend(metrics_address.map(|address| ("the --metrics port", address.port())));
+
+        let errors = port_conflicts(&tcp_ports);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!("Invalid ports:\n  {}", errors.join("\n  "))
+        }
+    }
+
+    /// Returns the TCP ports that have to differ, as the proxies run side by side in hybrid mode
+    /// and `ollana pair` runs next to the daemon.
+    fn tcp_ports(&self) -> [(&'static str, u16); 3] {
+        [
+            ("client_proxy.port", self.client_proxy.port),
+            ("server_proxy.port", self.server_proxy.port),
+            ("pairing.port", self.pairing.port),
+        ]
+    }
+}
+
+/// Returns an error message for each pair of named ports that are the same.
+fn port_conflicts(ports: &[(&str, u16)]) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    for (i, (name, port)) in ports.iter().enumerate() {
+        for (other_name, other_port) in &ports[i + 1..] {
+            if port == other_port {
+                errors.push(format!(
+                    "{} and {} must differ, both are {}",
+                    name, other_name, port
+                ));
+            }
+        }
+    }
+
+    errors
+}
+
+impl OllamaConfig {
+    /// Returns the URL of the local Ollama.
+    ///
+    /// # Errors
+    /// Returns an error if the URL isn't an absolute `http` or `https` URL.
+    ///
+    pub fn url(&self) -> anyhow::Result<Url> {
+        let url = Url::parse(&self.url)
+            .map_err(|e| anyhow::anyhow!("ollama.url '{}' is not a URL: {}", self.url, e))?;
+
+        if !["http", "https"].contains(&url.scheme()) || url.host().is_none() {
+            anyhow::bail!(
+                "ollama.url must be an http:// or https:// URL with a host, got '{}'",
+                self.url
+            );
+        }
+
+        Ok(url)
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs)
+    }
+}
+
+impl ClientProxyConfig {
+    /// Returns the address the client proxy listens on in client mode.
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+}
+
+impl ServerProxyConfig {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn broadcast_interval(&self) -> Duration {
+        Duration::from_secs(self.broadcast_interval_secs)
+    }
+
+    pub fn liveness_interval(&self) -> Duration {
+        Duration::from_secs(self.liveness_interval_secs)
+    }
+}
+
+impl ManagerConfig {
+    pub fn liveness_interval(&self) -> Duration {
+        Duration::from_secs(self.liveness_interval_secs)
+    }
+
+    pub fn static_servers_interval(&self) -> Duration {
+        Duration::from_secs(self.static_servers_interval_secs)
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::Ipv6Addr;
+
 pub const OLLAMA_DEFAULT_PORT: u16 = 11434;
 pub const OLLAMA_DEFAULT_ADDRESS: &str = "127.0.0.1";
 
@@ -8,3 +10,11 @@ pub const OLLANA_SERVER_PROXY_DEFAULT_ADDRESS: &str = "0.0.0.0";
 pub const OLLANA_SERVER_PROXY_DEFAULT_PORT: u16 = 11435;
 
 pub const OLLANA_SERVER_DEFAULT_DISCOVERY_PORT: u16 = 11436;
+
+pub const OLLANA_PAIRING_DEFAULT_PORT: u16 = 11437;
+
+pub const OLLANA_MDNS_SERVICE_TYPE: &str = "_ollana._tcp.local.";
+
+// Link-local scope multicast group, the last 32 bits spell "LANA"
+pub const OLLANA_DISCOVERY_IPV6_MULTICAST_GROUP: Ipv6Addr =
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x4c41, 0x4e41);
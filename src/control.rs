@@ -0,0 +1,368 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader},
+    net::UnixListener,
+};
+
+use crate::{
+    balancer::Balancer,
+    device::{AllowedDevice, Device},
+    get_local_dir,
+    scheduler::{Scheduler, SchedulerStats},
+};
+
+const CONTROL_SOCKET: &str = "ollana.sock";
+const STATUS_COMMAND: &str = "status";
+// Clients that don't send a command in time are disconnected, so they can't block others
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+// Accepting fails e.g. when running out of file descriptors, which usually gets better soon
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Answers questions about the running daemon on a Unix domain socket, see `ollana status`.
+///
+/// The socket is only accessible to the user running the daemon. Clients send a command on a single
+/// line and get a single line of JSON back.
+pub struct Control {
+    socket_path: PathBuf,
+    started_at: Instant,
+    device: Arc<Device>,
+    mode: RwLock<ModeState>,
+}
+
+/// What the daemon is currently running.
+#[derive(Clone, Default)]
+pub enum ModeState {
+    /// The mode hasn't been detected yet
+    #[default]
+    Starting,
+    Client(ClientState),
+    Server(ServerState),
+    Hybrid(ServerState, ClientState),
+}
+
+/// The manager forwarding requests to servers.
+#[derive(Clone)]
+pub struct ClientState {
+    pub balancer: Arc<Balancer>,
+    /// The address the client proxy listens on while there are servers
+    pub proxy_address: String,
+}
+
+/// The server proxy serving the local Ollama.
+#[derive(Clone)]
+pub struct ServerState {
+    pub address: String,
+    pub scheduler: Option<Arc<Scheduler>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ControlResponse {
+    Status(Box<Status>),
+    Error(String),
+}
+
+/// A snapshot of what the daemon is doing.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Status {
+    pub version: String,
+    pub pid: u32,
+    pub device_id: String,
+    /// `starting`, `client`, `server` or `hybrid`
+    pub mode: String,
+    pub uptime_secs: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_proxy: Option<ProxyStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_proxy: Option<ProxyStatus>,
+    /// The generation queue of the server proxy, if queueing is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue: Option<SchedulerStats>,
+    /// The servers the client knows, healthy as of their last liveness check
+    #[serde(default)]
+    pub servers: Vec<ServerStatus>,
+    #[serde(default)]
+    pub allowed_devices: Vec<AllowedDevice>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProxyStatus {
+    pub address: String,
+    pub is_running: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerStatus {
+    pub address: String,
+    pub device_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Lower values are preferred
+    pub priority: usize,
+    /// Whether requests are currently sent to the server
+    pub is_active: bool,
+    pub in_flight: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// Seconds since the server has last responded to a request or a liveness check
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub responded_secs_ago: Option<u64>,
+}
+
+/// Removes the socket file when the control server stops.
+struct SocketFile(PathBuf);
+
+impl Control {
+    /// Creates a control server.
+    ///
+    /// # Arguments
+    /// * `socket_path` - Where to create the socket, `ollana.sock` in the local data directory if
+    ///   not given.
+    /// * `device` - This device.
+    ///
+    pub fn new(socket_path: Option<PathBuf>, device: Arc<Device>) -> anyhow::Result<Self> {
+        Ok(Self {
+            socket_path: socket_path.map_or_else(default_socket_path, Ok)?,
+            started_at: Instant::now(),
+            device,
+            mode: RwLock::new(ModeState::default()),
+        })
+    }
+
+    /// Reports a new mode in the status.
+    pub fn set_mode(&self, mode: ModeState) {
+        *self.mode.write().unwrap() = mode;
+    }
+
+    /// Serves the status on the socket until cancelled.
+    ///
+    /// A stale socket of a previous run is replaced. If the socket can't be created, the daemon
+    /// keeps running without it.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let (listener, _socket_file) = match self.bind() {
+            Ok(listener) => listener,
+            Err(error) => {
+                warn!(
+                    "Couldn't create the control socket {}: {}",
+                    self.socket_path.display(),
+                    error
+                );
+
+                return std::future::pending().await;
+            }
+        };
+
+        info!(
+            "Listening for control commands on {}",
+            self.socket_path.display()
+        );
+
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    warn!("Couldn't accept a control connection: {}", error);
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+
+                    continue;
+                }
+            };
+
+            if let Err(error) = self.handle(stream).await {
+                debug!("Control command failed: {}", error);
+            }
+        }
+    }
+
+    /// Asks a running daemon for its status.
+    ///
+    /// # Arguments
+    /// * `socket_path` - The socket of the daemon, the default one if not given.
+    ///
+    /// # Errors
+    /// Returns an error if the daemon isn't running or can't be reached.
+    ///
+    pub fn status(socket_path: Option<PathBuf>) -> anyhow::Result<Status> {
+        let socket_path = socket_path.map_or_else(default_socket_path, Ok)?;
+        let mut stream = UnixStream::connect(&socket_path).map_err(|e| {
+            anyhow::anyhow!(
+                "Couldn't connect to {}, is `ollana serve` running? {}",
+                socket_path.display(),
+                e
+            )
+        })?;
+
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        writeln!(stream, "{}", STATUS_COMMAND)?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+
+        match serde_json::from_str(&line)? {
+            ControlResponse::Status(status) => Ok(*status),
+            ControlResponse::Error(error) => Err(anyhow::Error::msg(error)),
+        }
+    }
+
+    fn bind(&self) -> anyhow::Result<(UnixListener, SocketFile)> {
+        if let Some(dir) = self.socket_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // Only one daemon can listen, a socket nobody listens on is left over from a previous run
+        if UnixStream::connect(&self.socket_path).is_ok() {
+            anyhow::bail!("another daemon is listening on it");
+        }
+
+        if self.socket_path.exists() {
+            std::fs::remove_file(&self.socket_path)?;
+        }
+
+        // The socket is created in a directory only this user can access and moved into place
+        // once its permissions are restricted, so nobody else can connect in between
+        let private_dir =
+            self.socket_path
+                .with_file_name(format!(".{}.{}", CONTROL_SOCKET, std::process::id()));
+
+        if private_dir.exists() {
+            std::fs::remove_dir_all(&private_dir)?;
+        }
+
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&private_dir)?;
+
+        let private_path = private_dir.join(CONTROL_SOCKET);
+        let result = UnixListener::bind(&private_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|listener| {
+                std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+                std::fs::rename(&private_path, &self.socket_path)?;
+
+                Ok(listener)
+            });
+
+        let _ = std::fs::remove_dir_all(&private_dir);
+
+        Ok((result?, SocketFile(self.socket_path.clone())))
+    }
+
+    async fn handle(&self, stream: tokio::net::UnixStream) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut command = String::new();
+
+        tokio::time::timeout(
+            COMMAND_TIMEOUT,
+            AsyncBufReader::new(reader).read_line(&mut command),
+        )
+        .await??;
+
+        let response = match command.trim() {
+            STATUS_COMMAND => ControlResponse::Status(Box::new(self.snapshot())),
+            command => ControlResponse::Error(format!("Unknown command: {}", command)),
+        };
+        let mut line = serde_json::to_vec(&response)?;
+
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Status {
+        let mode = self.mode.read().unwrap().clone();
+        let mut status = Status {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pid: std::process::id(),
+            device_id: self.device.id.clone(),
+            mode: mode.name().to_string(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            server_proxy: None,
+            client_proxy: None,
+            queue: None,
+            servers: Vec::new(),
+            allowed_devices: self.device.allowed(),
+        };
+
+        match mode {
+            ModeState::Starting => {}
+            ModeState::Client(client) => self.add_client_status(&mut status, client),
+            ModeState::Server(server) => Self::add_server_status(&mut status, server),
+            ModeState::Hybrid(server, client) => {
+                Self::add_server_status(&mut status, server);
+                self.add_client_status(&mut status, client);
+            }
+        }
+
+        status
+    }
+
+    fn add_client_status(&self, status: &mut Status, client: ClientState) {
+        let active = client.balancer.active();
+
+        status.servers = client
+            .balancer
+            .upstreams()
+            .iter()
+            .map(|upstream| ServerStatus {
+                address: upstream.server.to_string(),
+                device_id: upstream.device_id.clone(),
+                name: self
+                    .device
+                    .allowed_device(&upstream.device_id)
+                    .and_then(|d| d.name),
+                priority: upstream.priority,
+                is_active: active.iter().any(|a| a.server == upstream.server),
+                in_flight: upstream.in_flight(),
+                latency_ms: upstream.latency().map(|l| l.as_millis() as u64),
+                responded_secs_ago: upstream.responded_at().map(|t| t.elapsed().as_secs()),
+            })
+            .collect();
+        // The manager runs the client proxy as long as there are servers to forward to
+        status.client_proxy = Some(ProxyStatus {
+            address: client.proxy_address,
+            is_running: !status.servers.is_empty(),
+        });
+    }
+
+    fn add_server_status(status: &mut Status, server: ServerState) {
+        status.server_proxy = Some(ProxyStatus {
+            address: server.address,
+            is_running: true,
+        });
+        status.queue = server.scheduler.map(|scheduler| scheduler.stats());
+    }
+}
+
+impl ModeState {
+    fn name(&self) -> &'static str {
+        match self {
+            ModeState::Starting => "starting",
+            ModeState::Client(_) => "client",
+            ModeState::Server(_) => "server",
+            ModeState::Hybrid(..) => "hybrid",
+        }
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn default_socket_path() -> anyhow::Result<PathBuf> {
+    get_local_dir().map(|dir| dir.join(CONTROL_SOCKET))
+}
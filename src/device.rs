@@ -1,24 +1,149 @@
 use std::{
-    fs::OpenOptions,
-    io::Write,
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use log::{error, info};
 use serde::{Deserialize, Serialize};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
 
-use crate::{certs::Certs, get_local_dir};
+use crate::{
+    certs::{Certs, DeviceIdentity},
+    get_local_dir,
+    permission::{self, DeviceRole},
+    rate_limit::DeviceLimits,
+    scheduler::DevicePriority,
+    write_atomically,
+};
 
 const DEVICE_CONFIG_TOML: &str = "device_allowed.toml";
+const DEVICE_PENDING_TOML: &str = "device_pending.toml";
+// Last seen times of allowed devices, kept apart so that requests never rewrite the allowed devices
+const DEVICE_SEEN_TOML: &str = "device_seen.toml";
+
+// Anyone can make up new Device IDs, the least recently seen ones are dropped beyond this
+const MAX_PENDING_DEVICES: usize = 100;
+const MAX_HOSTNAME_LEN: usize = 64;
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// How often the last seen time of an allowed or pending device is written to its file
+const LAST_SEEN_RESOLUTION: Duration = Duration::from_secs(60);
+// Length of the Device ID prefix shown next to device names
+const SHORT_ID_LEN: usize = 12;
 
 pub struct Device {
     pub id: String,
-    pub allowed: Vec<String>,
+    allowed: RwLock<Vec<AllowedDevice>>,
+    // Notified whenever the allowed devices change
+    changes: watch::Sender<()>,
+    identity: DeviceIdentity,
     dir: PathBuf,
+    // Serializes updates of the configuration file within the process
+    config_lock: Mutex<()>,
+    // Serializes updates of the pending devices file within the process
+    pending_lock: Mutex<()>,
+    // When pending devices were last written to their file, to avoid rewriting it on every request
+    pending_recorded: Mutex<HashMap<String, u64>>,
+    // Serializes updates of the last seen times file within the process
+    seen_lock: Mutex<()>,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Default)]
 struct DeviceConfig {
-    allowed: Vec<String>,
+    allowed: Vec<AllowedDevice>,
+}
+
+/// The configuration as stored on disk, which may still be in the format of older versions.
+#[derive(Deserialize)]
+struct StoredDeviceConfig {
+    // Required, so that an empty or truncated file doesn't revoke all devices
+    allowed: Vec<StoredAllowedDevice>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredAllowedDevice {
+    // Older versions stored a flat list of Device IDs
+    Id(String),
+    Device(AllowedDevice),
+}
+
+/// A device that is allowed to use this device, or to be used by it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AllowedDevice {
+    pub id: String,
+    /// A human-friendly name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Free-form labels
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+    /// Which endpoints the device may use on this server
+    #[serde(default)]
+    pub role: DeviceRole,
+    /// The models the device may use on this server, all models if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<String>,
+    /// How much the device may use this server
+    #[serde(default, skip_serializing_if = "DeviceLimits::is_unlimited")]
+    pub limits: DeviceLimits,
+    /// In which order queued requests of the device are run on this server
+    #[serde(default)]
+    pub priority: DevicePriority,
+    /// Seconds since the Unix epoch, unknown for devices allowed by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added: Option<u64>,
+    /// Seconds since the Unix epoch, stored in a file of its own. Older versions stored it in
+    /// the configuration file.
+    #[serde(default, skip_serializing)]
+    pub last_seen: Option<u64>,
+}
+
+/// Changes to apply to an allowed device, fields left empty keep their current values.
+#[derive(Default)]
+pub struct DeviceUpdate {
+    pub name: Option<String>,
+    pub labels: Vec<String>,
+    pub role: Option<DeviceRole>,
+    pub models: Vec<String>,
+    /// Zero removes the limit
+    pub requests_per_minute: Option<u32>,
+    /// Zero removes the limit
+    pub max_concurrent: Option<u32>,
+    /// Zero removes the limit
+    pub tokens_per_hour: Option<u64>,
+    pub priority: Option<DevicePriority>,
+}
+
+/// A device that has tried to use this server without being allowed.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingDevice {
+    pub id: String,
+    /// The address the device connected from most recently
+    pub address: String,
+    /// The host name reported by the device itself, if any
+    pub hostname: Option<String>,
+    /// Seconds since the Unix epoch
+    pub first_seen: u64,
+    /// Seconds since the Unix epoch
+    pub last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PendingConfig {
+    pending: Vec<PendingDevice>,
+}
+
+/// The last seen times of allowed devices by Device ID.
+#[derive(Serialize, Deserialize, Default)]
+struct SeenConfig {
+    #[serde(default)]
+    seen: BTreeMap<String, u64>,
 }
 
 impl Device {
@@ -30,7 +155,7 @@ impl Device {
     /// - Retrieves the local directory path.
     /// - Initializes the configuration file if not already done.
     /// - Generates the device certificate using provided certs.
-    /// - Computes a unique identifier for the device based on its key bytes.
+    /// - Computes a unique identifier for the device based on its public key.
     /// - Loads allowed device IDs from the configuration directory.
     ///
     /// # Arguments
@@ -43,36 +168,84 @@ impl Device {
     /// If any step fails, an error is returned with detailed information about what went wrong.
     ///
     pub fn new(certs: &Certs) -> anyhow::Result<Self> {
-        let dir = get_local_dir()?;
+        Self::with_dir(get_local_dir()?, certs)
+    }
 
+    pub(crate) fn with_dir(dir: PathBuf, certs: &Certs) -> anyhow::Result<Self> {
         Self::init_config(&dir)?;
+        Self::migrate_config(&dir)?;
         certs.gen_device()?;
 
-        let id = sha256::digest(certs.get_device_key_bytes()?);
-        let allowed = Self::load_allowed_device_ids(&dir)?;
+        let id = certs.get_device_id()?;
+        let identity = certs.get_device_identity()?;
+        let allowed = Self::load_config(&dir)?.allowed;
+
+        Ok(Self {
+            id,
+            allowed: RwLock::new(allowed),
+            changes: watch::Sender::new(()),
+            identity,
+            dir,
+            config_lock: Mutex::new(()),
+            pending_lock: Mutex::new(()),
+            pending_recorded: Mutex::new(HashMap::new()),
+            seen_lock: Mutex::new(()),
+        })
+    }
 
-        Ok(Self { id, allowed, dir })
+    /// Returns the identity (device certificate and key) to authenticate to servers with.
+    pub fn identity(&self) -> DeviceIdentity {
+        self.identity.clone()
     }
 
     /// Allows a device with the specified ID.
     ///
     /// If the device is not already allowed, it will be added to the list and the configuration saved.
-    /// Returns `true` if the operation was successful and the device was added; otherwise returns `false`.
+    /// Otherwise the given changes are applied to the allowed device.
+    /// Returns `true` if the device was added; otherwise returns `false`.
     ///
     /// # Arguments
     /// * `id`: The unique identifier of the device to allow.
+    /// * `update`: The name, labels and permissions of the device.
     ///
-    pub fn allow(&self, id: String) -> anyhow::Result<bool> {
+    pub fn allow(&self, id: String, update: DeviceUpdate) -> anyhow::Result<bool> {
+        let _lock = self.config_lock.lock().unwrap();
         let mut config = Self::load_config(&self.dir)?;
 
-        if !config.allowed.contains(&id) {
-            config.allowed.push(id);
-            Self::save_config(&self.dir, &config)?;
+        let is_added = match config.allowed.iter_mut().find(|d| d.id == id) {
+            Some(device) => {
+                if update.is_empty() {
+                    return Ok(false);
+                }
 
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+                update.apply(device);
+
+                false
+            }
+            None => {
+                let mut device = AllowedDevice {
+                    id,
+                    name: None,
+                    labels: Vec::new(),
+                    role: DeviceRole::default(),
+                    models: Vec::new(),
+                    limits: DeviceLimits::default(),
+                    priority: DevicePriority::default(),
+                    added: Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+                    last_seen: None,
+                };
+
+                update.apply(&mut device);
+                config.allowed.push(device);
+
+                true
+            }
+        };
+
+        Self::save_config(&self.dir, &config)?;
+        self.reload()?;
+
+        Ok(is_added)
     }
 
     /// Disables a device with the specified ID.
@@ -84,11 +257,13 @@ impl Device {
     /// * `id`: The unique identifier of the device to disable.
     ///
     pub fn disable(&self, id: String) -> anyhow::Result<bool> {
+        let _lock = self.config_lock.lock().unwrap();
         let mut config = Self::load_config(&self.dir)?;
 
-        if config.allowed.contains(&id) {
-            config.allowed.retain(|x| x != &id);
+        if config.allowed.iter().any(|d| d.id == id) {
+            config.allowed.retain(|d| d.id != id);
             Self::save_config(&self.dir, &config)?;
+            self.reload()?;
 
             Ok(true)
         } else {
@@ -104,18 +279,277 @@ impl Device {
     /// * `id`: The unique identifier of the device to check.
     ///
     pub fn is_allowed(&self, id: String) -> bool {
-        self.allowed.contains(&id)
+        self.allowed.read().unwrap().iter().any(|d| d.id == id)
+    }
+
+    /// Returns the allowed devices.
+    pub fn allowed(&self) -> Vec<AllowedDevice> {
+        self.allowed.read().unwrap().clone()
+    }
+
+    /// Returns an allowed device, if the device is allowed.
+    pub fn allowed_device(&self, id: &str) -> Option<AllowedDevice> {
+        self.allowed
+            .read()
+            .unwrap()
+            .iter()
+            .find(|d| d.id == id)
+            .cloned()
+    }
+
+    /// Returns a name to show a device by in logs, see [`AllowedDevice::display_name`].
+    ///
+    /// Devices that aren't allowed are shown by their IDs.
+    pub fn display_name(&self, id: &str) -> String {
+        self.allowed
+            .read()
+            .unwrap()
+            .iter()
+            .find(|d| d.id == id)
+            .map(AllowedDevice::display_name)
+            .unwrap_or_else(|| id.to_string())
+    }
+
+    /// Records that an allowed device has just been seen.
+    ///
+    /// To avoid rewriting the last seen times on every request, the last seen time of a device is
+    /// updated at most once a minute. The file is written on a blocking thread, and the
+    /// configuration file isn't touched.
+    ///
+    /// # Arguments
+    /// * `id`: The unique identifier of the device.
+    ///
+    pub async fn touch(self: &Arc<Self>, id: &str) -> anyhow::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let is_outdated = |device: &AllowedDevice| {
+            device.id == id
+                && device
+                    .last_seen
+                    .is_none_or(|t| now.saturating_sub(t) >= LAST_SEEN_RESOLUTION.as_secs())
+        };
+
+        if !self.allowed.read().unwrap().iter().any(is_outdated) {
+            return Ok(());
+        }
+
+        // Concurrent requests of the device must not write the file again
+        match self
+            .allowed
+            .write()
+            .unwrap()
+            .iter_mut()
+            .find(|d| is_outdated(d))
+        {
+            Some(device) => device.last_seen = Some(now),
+            None => return Ok(()),
+        }
+
+        let device = self.clone();
+        let id = id.to_string();
+
+        tokio::task::spawn_blocking(move || device.save_seen(&id, now)).await?
+    }
+
+    /// Writes the last seen time of an allowed device to its file.
+    ///
+    /// # Arguments
+    /// * `id`: The unique identifier of the device.
+    /// * `now`: When the device has been seen, in seconds since the Unix epoch.
+    ///
+    fn save_seen(&self, id: &str, now: u64) -> anyhow::Result<()> {
+        let _lock = self.seen_lock.lock().unwrap();
+        let mut seen = Self::load_seen_config(&self.dir)?;
+        let allowed = self.allowed.read().unwrap();
+
+        // Devices that are no longer allowed are forgotten
+        seen.seen
+            .retain(|seen_id, _| allowed.iter().any(|d| &d.id == seen_id));
+        drop(allowed);
+        seen.seen.insert(id.to_string(), now);
+
+        Self::save_seen_config(&self.dir, &seen)
+    }
+
+    /// Returns a receiver notified whenever the allowed devices change.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Reloads the allowed devices from the configuration file.
+    ///
+    /// Returns `true` if the allowed device IDs have changed, subscribers are notified only then.
+    pub fn reload(&self) -> anyhow::Result<bool> {
+        let allowed = Self::load_config(&self.dir)?.allowed;
+        let mut current = self.allowed.write().unwrap();
+        let is_changed = current
+            .iter()
+            .map(|d| &d.id)
+            .ne(allowed.iter().map(|d| &d.id));
+
+        *current = allowed;
+        drop(current);
+
+        if is_changed {
+            self.changes.send_replace(());
+        }
+
+        Ok(is_changed)
     }
 
-    // Loads a vector containing all allowed device IDs from the configuration file.
+    /// Reloads the allowed devices whenever the configuration file changes or SIGHUP is received.
+    ///
+    /// The file is polled, so that it keeps working when the file is replaced by editors.
+    pub async fn watch_config(&self) -> anyhow::Result<()> {
+        let config_path = self.dir.join(DEVICE_CONFIG_TOML);
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+        let mut last_modified = modified(&config_path);
+
+        loop {
+            let is_forced = tokio::select! {
+                _ = sighup.recv() => true,
+                _ = interval.tick() => false,
+            };
+            let current_modified = modified(&config_path);
+
+            if is_forced || current_modified != last_modified {
+                last_modified = current_modified;
+
+                match self.reload() {
+                    Ok(true) => info!("Reloaded allowed devices"),
+                    Ok(false) => {}
+                    Err(error) => error!("Couldn't reload allowed devices: {}", error),
+                }
+            }
+        }
+    }
+
+    /// Records an attempt of a device that is not allowed to use this server.
+    ///
+    /// To avoid rewriting the pending devices file on every request, an attempt of a device is
+    /// recorded at most once a minute, and only for so many devices a minute. The file is written
+    /// on a blocking thread.
     ///
     /// # Arguments
-    /// * `dir`: A reference to the directory where the configuration file resides.
+    /// * `id`: The unique identifier of the device.
+    /// * `address`: The address the device connected from.
+    /// * `hostname`: The host name reported by the device, if any.
     ///
-    fn load_allowed_device_ids(dir: &Path) -> anyhow::Result<Vec<String>> {
-        let config = Self::load_config(dir)?;
+    pub async fn record_pending(
+        self: &Arc<Self>,
+        id: String,
+        address: Option<SocketAddr>,
+        hostname: Option<String>,
+    ) -> anyhow::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        {
+            let mut recorded = self.pending_recorded.lock().unwrap();
+
+            recorded.retain(|_, at| now.saturating_sub(*at) < LAST_SEEN_RESOLUTION.as_secs());
+
+            // Anyone can make up new Device IDs, they'd be dropped from the file anyway
+            if recorded.contains_key(&id) || recorded.len() >= MAX_PENDING_DEVICES {
+                return Ok(());
+            }
 
-        Ok(config.allowed)
+            recorded.insert(id.clone(), now);
+        }
+
+        let device = self.clone();
+
+        tokio::task::spawn_blocking(move || device.save_pending(id, address, hostname, now)).await?
+    }
+
+    /// Adds or updates a pending device in its file, keeping the most recently seen ones only.
+    ///
+    /// # Arguments
+    /// * `id`: The unique identifier of the device.
+    /// * `address`: The address the device connected from.
+    /// * `hostname`: The host name reported by the device, if any.
+    /// * `now`: When the device has been seen, in seconds since the Unix epoch.
+    ///
+    fn save_pending(
+        &self,
+        id: String,
+        address: Option<SocketAddr>,
+        hostname: Option<String>,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let _lock = self.pending_lock.lock().unwrap();
+        let mut config = Self::load_pending_config(&self.dir)?;
+        let address = address.map(|a| a.to_string()).unwrap_or_default();
+        let hostname = hostname.map(|h| {
+            h.chars()
+                .filter(|c| !c.is_control())
+                .take(MAX_HOSTNAME_LEN)
+                .collect()
+        });
+
+        match config.pending.iter_mut().find(|d| d.id == id) {
+            Some(device) => {
+                device.address = address;
+                device.hostname = hostname.or(device.hostname.take());
+                device.last_seen = now;
+            }
+            None => {
+                config.pending.push(PendingDevice {
+                    id,
+                    address,
+                    hostname,
+                    first_seen: now,
+                    last_seen: now,
+                });
+
+                if config.pending.len() > MAX_PENDING_DEVICES {
+                    config
+                        .pending
+                        .sort_by_key(|d| std::cmp::Reverse(d.last_seen));
+                    config.pending.truncate(MAX_PENDING_DEVICES);
+                }
+            }
+        }
+
+        Self::save_pending_config(&self.dir, &config)
+    }
+
+    /// Returns the devices that have tried to use this server without being allowed.
+    pub fn pending(&self) -> anyhow::Result<Vec<PendingDevice>> {
+        Ok(Self::load_pending_config(&self.dir)?.pending)
+    }
+
+    /// Allows a pending device and removes it from the pending devices.
+    ///
+    /// Returns `true` if the device was pending; otherwise returns `false`.
+    ///
+    /// # Arguments
+    /// * `id`: The unique identifier of the device to approve.
+    /// * `name`: A human-friendly name of the device, its reported host name by default.
+    ///
+    pub fn approve(&self, id: String, name: Option<String>) -> anyhow::Result<bool> {
+        let _lock = self.pending_lock.lock().unwrap();
+        let mut config = Self::load_pending_config(&self.dir)?;
+
+        if let Some(pending) = config.pending.iter().find(|d| d.id == id) {
+            let name = name.or(pending.hostname.clone());
+
+            self.allow(
+                id.clone(),
+                DeviceUpdate {
+                    name,
+                    ..Default::default()
+                },
+            )?;
+
+            config.pending.retain(|d| d.id != id);
+            Self::save_pending_config(&self.dir, &config)?;
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
     }
 
     /// Initializes the device configuration if it does not already exist in the specified directory.
@@ -135,15 +569,84 @@ impl Device {
         Ok(())
     }
 
+    /// Rewrites a device configuration in the format of older versions in the current one.
+    ///
+    /// # Arguments
+    /// * `dir`: A reference to the directory where the configuration file is located.
+    ///
+    fn migrate_config(dir: &Path) -> anyhow::Result<()> {
+        let (config, is_outdated) = Self::read_config(dir)?;
+
+        if is_outdated {
+            info!("Migrating {} to the current format", DEVICE_CONFIG_TOML);
+
+            let seen = SeenConfig {
+                seen: config
+                    .allowed
+                    .iter()
+                    .filter_map(|d| d.last_seen.map(|t| (d.id.clone(), t)))
+                    .collect(),
+            };
+
+            Self::save_seen_config(dir, &seen)?;
+            Self::save_config(dir, &config)?;
+        }
+
+        Ok(())
+    }
+
     /// Loads the device configuration from a TOML file.
     ///
     /// # Arguments
     /// * `dir`: A reference to the directory where the configuration file is located.
     ///
     fn load_config(dir: &Path) -> anyhow::Result<DeviceConfig> {
+        Ok(Self::read_config(dir)?.0)
+    }
+
+    /// Reads the device configuration from a TOML file, converting it from the format of older
+    /// versions if needed, along with the last seen times of the devices.
+    ///
+    /// Returns the configuration and whether it was in an older format.
+    ///
+    /// # Arguments
+    /// * `dir`: A reference to the directory where the configuration file is located.
+    ///
+    fn read_config(dir: &Path) -> anyhow::Result<(DeviceConfig, bool)> {
         let toml_str = std::fs::read_to_string(dir.join(DEVICE_CONFIG_TOML))?;
+        let stored: StoredDeviceConfig = toml::from_str(&toml_str)?;
+        let seen = Self::load_seen_config(dir)?.seen;
+        let mut is_outdated = false;
 
-        toml::from_str(&toml_str).map_err(anyhow::Error::from)
+        let allowed = stored
+            .allowed
+            .into_iter()
+            .map(|device| match device {
+                StoredAllowedDevice::Id(id) => {
+                    is_outdated = true;
+
+                    AllowedDevice {
+                        id,
+                        name: None,
+                        labels: Vec::new(),
+                        role: DeviceRole::default(),
+                        models: Vec::new(),
+                        limits: DeviceLimits::default(),
+                        priority: DevicePriority::default(),
+                        added: None,
+                        last_seen: None,
+                    }
+                }
+                StoredAllowedDevice::Device(mut device) => {
+                    is_outdated |= device.last_seen.is_some();
+                    device.last_seen = seen.get(&device.id).copied().max(device.last_seen);
+
+                    device
+                }
+            })
+            .collect();
+
+        Ok((DeviceConfig { allowed }, is_outdated))
     }
 
     /// Saves the device configuration to a TOML file.
@@ -153,16 +656,382 @@ impl Device {
     /// * `config`: The device configuration object to serialize and save.
     ///
     fn save_config(dir: &Path, config: &DeviceConfig) -> anyhow::Result<()> {
-        let mut config_file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(dir.join(DEVICE_CONFIG_TOML))
-            .map_err(|e| anyhow::anyhow!("Failed to create/open a device config file: {}", e))?;
         let toml_str = toml::to_string_pretty(&config)?;
 
-        config_file.write_all(toml_str.as_bytes())?;
+        write_atomically(&dir.join(DEVICE_CONFIG_TOML), toml_str.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to write a device config file: {}", e))
+    }
 
-        Ok(())
+    /// Loads the pending devices from a TOML file, there are none if the file doesn't exist.
+    ///
+    /// # Arguments
+    /// * `dir`: A reference to the directory where the file is located.
+    ///
+    fn load_pending_config(dir: &Path) -> anyhow::Result<PendingConfig> {
+        let path = dir.join(DEVICE_PENDING_TOML);
+
+        if !path.exists() {
+            return Ok(PendingConfig::default());
+        }
+
+        let toml_str = std::fs::read_to_string(path)?;
+
+        toml::from_str(&toml_str).map_err(anyhow::Error::from)
+    }
+
+    /// Saves the pending devices to a TOML file.
+    ///
+    /// # Arguments
+    /// * `dir`: A reference to the directory where the file should be saved.
+    /// * `config`: The pending devices to serialize and save.
+    ///
+    fn save_pending_config(dir: &Path, config: &PendingConfig) -> anyhow::Result<()> {
+        let toml_str = toml::to_string_pretty(&config)?;
+
+        write_atomically(&dir.join(DEVICE_PENDING_TOML), toml_str.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to write a pending devices file: {}", e))
+    }
+
+    /// Loads the last seen times of allowed devices, there are none if the file doesn't exist.
+    ///
+    /// # Arguments
+    /// * `dir`: A reference to the directory where the file is located.
+    ///
+    fn load_seen_config(dir: &Path) -> anyhow::Result<SeenConfig> {
+        let path = dir.join(DEVICE_SEEN_TOML);
+
+        if !path.exists() {
+            return Ok(SeenConfig::default());
+        }
+
+        let toml_str = std::fs::read_to_string(path)?;
+
+        toml::from_str(&toml_str).map_err(anyhow::Error::from)
+    }
+
+    /// Saves the last seen times of allowed devices to a TOML file.
+    ///
+    /// # Arguments
+    /// * `dir`: A reference to the directory where the file should be saved.
+    /// * `config`: The last seen times to serialize and save.
+    ///
+    fn save_seen_config(dir: &Path, config: &SeenConfig) -> anyhow::Result<()> {
+        let toml_str = toml::to_string_pretty(&config)?;
+
+        write_atomically(&dir.join(DEVICE_SEEN_TOML), toml_str.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to write a last seen devices file: {}", e))
+    }
+}
+
+impl AllowedDevice {
+    /// Returns the name of the device followed by a short Device ID, or the Device ID if the
+    /// device has no name.
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} ({})", name, &self.id[..SHORT_ID_LEN.min(self.id.len())]),
+            None => self.id.clone(),
+        }
+    }
+
+    /// Checks whether the device may use a model, see [`permission::allows_model`].
+    pub fn allows_model(&self, model: &str) -> bool {
+        permission::allows_model(&self.models, model)
+    }
+
+    /// Checks whether the device may only use some of the models.
+    pub fn is_model_restricted(&self) -> bool {
+        !self.models.is_empty() && !self.models.iter().any(|m| m == permission::ANY_MODEL)
+    }
+}
+
+#[cfg(test)]
+impl AllowedDevice {
+    /// An inference device that may use the given models.
+    pub(crate) fn with_models(models: &[&str]) -> Self {
+        Self {
+            id: "device".to_string(),
+            name: None,
+            labels: Vec::new(),
+            role: DeviceRole::Inference,
+            models: models.iter().map(|m| m.to_string()).collect(),
+            limits: DeviceLimits::default(),
+            priority: DevicePriority::default(),
+            added: None,
+            last_seen: None,
+        }
+    }
+}
+
+impl DeviceUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.labels.is_empty()
+            && self.role.is_none()
+            && self.models.is_empty()
+            && self.requests_per_minute.is_none()
+            && self.max_concurrent.is_none()
+            && self.tokens_per_hour.is_none()
+            && self.priority.is_none()
+    }
+
+    fn apply(self, device: &mut AllowedDevice) {
+        if self.name.is_some() {
+            device.name = self.name;
+        }
+
+        if !self.labels.is_empty() {
+            device.labels = self.labels;
+        }
+
+        if let Some(role) = self.role {
+            device.role = role;
+        }
+
+        if !self.models.is_empty() {
+            device.models = self.models;
+        }
+
+        if let Some(limit) = self.requests_per_minute {
+            device.limits.requests_per_minute = Some(limit).filter(|n| *n > 0);
+        }
+
+        if let Some(limit) = self.max_concurrent {
+            device.limits.max_concurrent = Some(limit).filter(|n| *n > 0);
+        }
+
+        if let Some(limit) = self.tokens_per_hour {
+            device.limits.tokens_per_hour = Some(limit).filter(|n| *n > 0);
+        }
+
+        if let Some(priority) = self.priority {
+            device.priority = priority;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::TempDir;
+
+    use super::*;
+
+    fn device(dir: &TempDir) -> Arc<Device> {
+        let certs = Certs::with_dir(dir.to_path_buf());
+
+        Arc::new(Device::with_dir(dir.to_path_buf(), &certs).unwrap())
+    }
+
+    fn pending_hostnames(device: &Device) -> Vec<(String, Option<String>)> {
+        device
+            .pending()
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.id, d.hostname))
+            .collect()
+    }
+
+    #[test]
+    fn reload_notifies_when_allowed_devices_change() {
+        let dir = TempDir::new();
+        let device = device(&dir);
+        let mut changes = device.subscribe();
+        let write_config = |contents: &str| {
+            std::fs::write(dir.join(DEVICE_CONFIG_TOML), contents).unwrap();
+        };
+
+        assert!(!device.reload().unwrap());
+        assert!(!changes.has_changed().unwrap());
+
+        write_config("[[allowed]]\nid = \"a\"\n\n[[allowed]]\nid = \"b\"\n");
+
+        assert!(device.reload().unwrap());
+        assert!(changes.has_changed().unwrap());
+        assert!(device.is_allowed("b".to_string()));
+        changes.mark_unchanged();
+
+        // Other changes are applied, but nobody has to reconnect
+        write_config("[[allowed]]\nid = \"a\"\nname = \"laptop\"\n\n[[allowed]]\nid = \"b\"\n");
+
+        assert!(!device.reload().unwrap());
+        assert!(!changes.has_changed().unwrap());
+        assert_eq!(device.display_name("a"), "laptop (a)");
+
+        write_config("[[allowed]]\nid = \"a\"\n");
+
+        assert!(device.reload().unwrap());
+        assert!(changes.has_changed().unwrap());
+        assert!(!device.is_allowed("b".to_string()));
+    }
+
+    #[test]
+    fn reload_keeps_devices_when_the_config_is_invalid() {
+        let dir = TempDir::new();
+        let device = device(&dir);
+        let mut changes = device.subscribe();
+
+        device
+            .allow("a".to_string(), DeviceUpdate::default())
+            .unwrap();
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+
+        std::fs::write(dir.join(DEVICE_CONFIG_TOML), "[[allowed]\n").unwrap();
+
+        assert!(device.reload().is_err());
+        assert!(device.is_allowed("a".to_string()));
+        assert!(!changes.has_changed().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn record_pending_is_throttled() {
+        let dir = TempDir::new();
+        let device = device(&dir);
+        let record = |id: &str, hostname: &str| {
+            device.record_pending(id.to_string(), None, Some(hostname.to_string()))
+        };
+
+        record("a", "laptop").await.unwrap();
+        record("a", "renamed").await.unwrap();
+
+        assert_eq!(
+            pending_hostnames(&device),
+            [("a".to_string(), Some("laptop".to_string()))]
+        );
+
+        // Recorded again once a minute has passed
+        *device
+            .pending_recorded
+            .lock()
+            .unwrap()
+            .get_mut("a")
+            .unwrap() -= LAST_SEEN_RESOLUTION.as_secs();
+        record("a", "renamed").await.unwrap();
+
+        assert_eq!(
+            pending_hostnames(&device),
+            [("a".to_string(), Some("renamed".to_string()))]
+        );
+    }
+
+    #[actix_web::test]
+    async fn record_pending_limits_new_devices_per_minute() {
+        let dir = TempDir::new();
+        let device = device(&dir);
+
+        for i in 0..MAX_PENDING_DEVICES + 5 {
+            device
+                .record_pending(format!("{:04x}", i), None, None)
+                .await
+                .unwrap();
+        }
+
+        let pending = device.pending().unwrap();
+
+        assert_eq!(pending.len(), MAX_PENDING_DEVICES);
+        assert!(pending
+            .iter()
+            .all(|d| d.id < format!("{:04x}", MAX_PENDING_DEVICES)));
+    }
+
+    #[test]
+    fn pending_devices_are_capped() {
+        let dir = TempDir::new();
+        let device = device(&dir);
+
+        for i in 0..MAX_PENDING_DEVICES as u64 + 5 {
+            device
+                .save_pending(format!("{:04x}", i), None, None, 1000 + i)
+                .unwrap();
+        }
+
+        // The most recently seen devices are kept
+        device
+            .save_pending("0001".to_string(), None, None, 2000)
+            .unwrap();
+        device
+            .save_pending("ffff".to_string(), None, None, 2001)
+            .unwrap();
+
+        let mut ids: Vec<_> = device
+            .pending()
+            .unwrap()
+            .into_iter()
+            .map(|d| d.id)
+            .collect();
+        ids.sort();
+
+        assert_eq!(ids.len(), MAX_PENDING_DEVICES);
+        assert_eq!(ids[..2], ["0001", "0007"]);
+        assert_eq!(ids.last().unwrap(), "ffff");
+    }
+
+    #[test]
+    fn pending_hostnames_are_sanitized() {
+        let dir = TempDir::new();
+        let device = device(&dir);
+        let address = SocketAddr::from(([192, 168, 1, 20], 50000));
+
+        device
+            .save_pending(
+                "a".to_string(),
+                Some(address),
+                Some(format!("evil\n\x1b[31m{}", "x".repeat(100))),
+                1000,
+            )
+            .unwrap();
+
+        let pending = device.pending().unwrap();
+        let hostname = pending[0].hostname.as_deref().unwrap();
+
+        assert_eq!(pending[0].address, "192.168.1.20:50000");
+        assert_eq!(hostname.chars().count(), MAX_HOSTNAME_LEN);
+        assert!(hostname.starts_with("evil[31mxxx"));
+    }
+
+    #[actix_web::test]
+    async fn touch_is_throttled() {
+        let dir = TempDir::new();
+        let device = device(&dir);
+        let seen_path = dir.join(DEVICE_SEEN_TOML);
+
+        device
+            .allow("a".to_string(), DeviceUpdate::default())
+            .unwrap();
+        device.touch("a").await.unwrap();
+
+        let last_seen = device.allowed_device("a").unwrap().last_seen;
+        assert!(last_seen.is_some());
+        assert_eq!(
+            Device::load_seen_config(&dir).unwrap().seen["a"],
+            last_seen.unwrap()
+        );
+
+        std::fs::remove_file(&seen_path).unwrap();
+        device.touch("a").await.unwrap();
+
+        assert!(!seen_path.exists());
+
+        // Written again once a minute has passed
+        for d in device.allowed.write().unwrap().iter_mut() {
+            d.last_seen = d.last_seen.map(|t| t - LAST_SEEN_RESOLUTION.as_secs());
+        }
+        device.touch("a").await.unwrap();
+
+        assert!(seen_path.exists());
+
+        // Unknown devices are ignored
+        std::fs::remove_file(&seen_path).unwrap();
+        device.touch("b").await.unwrap();
+
+        assert!(!seen_path.exists());
+    }
+
+    #[test]
+    fn model_restriction() {
+        assert!(!AllowedDevice::with_models(&[]).is_model_restricted());
+        assert!(!AllowedDevice::with_models(&["*"]).is_model_restricted());
+        assert!(!AllowedDevice::with_models(&["llama3", "*"]).is_model_restricted());
+        assert!(AllowedDevice::with_models(&["llama3"]).is_model_restricted());
+        assert!(AllowedDevice::with_models(&["llama3:8b", "mistral"]).is_model_restricted());
     }
 }
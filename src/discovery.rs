@@ -1,12 +1,13 @@
 use std::{
     io,
-    net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
     sync::Arc,
     time::Duration,
 };
 
 use futures_util::StreamExt;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
     net::UdpSocket,
     sync::{mpsc::Sender, Mutex},
@@ -15,68 +16,276 @@ use tokio::{
 use tokio_stream::wrappers::IntervalStream;
 
 use crate::{
-    constants::{self, OLLANA_SERVER_PROXY_DEFAULT_PORT},
+    config::DiscoveryConfig,
+    constants::OLLANA_SERVER_PROXY_DEFAULT_PORT,
+    device::Device,
     manager::ManagerCommand,
+    mdns::MdnsBrowser,
+    metrics::{metrics, DiscoveryRole},
     ollama::Ollama,
 };
 
 const PROTO_MAGIC_NUMBER: u32 = 0x4C414E41; // LANA
+const PROTO_VERSION: u8 = 2;
+const PROTO_KIND_REQUEST: u8 = 1;
+const PROTO_KIND_RESPONSE: u8 = 2;
+// magic (4) + version (1) + kind (1) + payload length (2)
+const PROTO_HEADER_LEN: usize = 8;
+const PROTO_MAX_PACKET_LEN: usize = 512;
 const RANDOM_UDP_PORT: u16 = 0;
-const DEFAULT_CLIENT_BROADCAST_INTERVAL: Duration = Duration::from_secs(5);
-const DEFAULT_SERVER_LIVENESS_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct ClientDiscovery {
     server_port: u16,
-    broadcast_interval: std::time::Duration,
+    broadcast_interval: Duration,
+    ipv4_multicast_group: Option<Ipv4Addr>,
+    ipv6_multicast_group: Option<Ipv6Addr>,
+    // This device's own server proxy answers as well in hybrid mode
+    device_id: String,
 }
 
 pub struct ServerDiscovery {
     port: u16,
+    ipv4_multicast_group: Option<Ipv4Addr>,
+    ipv6_multicast_group: Option<Ipv6Addr>,
+    proxy_port: u16,
+    device_id: String,
     local_ollama: Arc<Ollama>,
-    liveness_interval: std::time::Duration,
+    liveness_interval: Duration,
     alive: Mutex<bool>,
 }
 
-impl Default for ClientDiscovery {
-    fn default() -> Self {
-        Self {
-            server_port: constants::OLLANA_SERVER_DEFAULT_DISCOVERY_PORT,
-            broadcast_interval: DEFAULT_CLIENT_BROADCAST_INTERVAL,
+/// A mechanism used by the manager to find servers on the network.
+pub enum DiscoveryBackend {
+    /// The UDP broadcast/multicast protocol
+    Udp(ClientDiscovery),
+    /// DNS-SD browsing of `_ollana._tcp.local.` services
+    Mdns(MdnsBrowser),
+}
+
+/// A discovery packet exchanged between clients and servers.
+///
+/// Version 2 packets have the following layout (all integers are big-endian):
+///
+/// ```text
+/// magic: u32 | version: u8 | kind: u8 | payload length: u16 | payload
+/// ```
+///
+/// A request carries an empty payload. A response payload consists of the server proxy port (`u16`)
+/// followed by the server device ID and the Ollana version, each prefixed with its length (`u8`).
+///
+/// Packets consisting of the bare 4-byte magic number are treated as version 1 messages, which is
+/// what older Ollana releases send and understand.
+#[derive(Debug, PartialEq)]
+pub enum DiscoveryMessage {
+    LegacyHello,
+    Request,
+    Response(ServerAnnouncement),
+}
+
+/// Metadata a server announces about itself in a version 2 discovery response.
+#[derive(Debug, PartialEq)]
+pub struct ServerAnnouncement {
+    pub proxy_port: u16,
+    pub device_id: String,
+    pub version: String,
+}
+
+impl DiscoveryMessage {
+    /// Encodes the message into a discovery packet.
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut packet = PROTO_MAGIC_NUMBER.to_be_bytes().to_vec();
+
+        let (kind, payload) = match self {
+            DiscoveryMessage::LegacyHello => return Ok(packet),
+            DiscoveryMessage::Request => (PROTO_KIND_REQUEST, Vec::new()),
+            DiscoveryMessage::Response(announcement) => {
+                let mut payload = announcement.proxy_port.to_be_bytes().to_vec();
+
+                Self::put_str(&mut payload, &announcement.device_id)?;
+                Self::put_str(&mut payload, &announcement.version)?;
+
+                (PROTO_KIND_RESPONSE, payload)
+            }
+        };
+
+        packet.push(PROTO_VERSION);
+        packet.push(kind);
+        packet.extend_from_slice(&u16::try_from(payload.len())?.to_be_bytes());
+        packet.extend_from_slice(&payload);
+
+        Ok(packet)
+    }
+
+    /// Decodes a discovery packet.
+    ///
+    /// Returns an error if the packet doesn't start with the magic number, has an unsupported version,
+    /// or is malformed.
+    pub fn decode(packet: &[u8]) -> anyhow::Result<Self> {
+        let magic = packet
+            .get(0..4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .ok_or_else(|| anyhow::Error::msg("Discovery packet is too short"))?;
+
+        if magic != PROTO_MAGIC_NUMBER {
+            return Err(anyhow::anyhow!(
+                "Discovery packet has unknown magic number: 0X{:X}",
+                magic
+            ));
+        }
+
+        if packet.len() == 4 {
+            return Ok(DiscoveryMessage::LegacyHello);
         }
+
+        if packet.len() < PROTO_HEADER_LEN {
+            return Err(anyhow::Error::msg("Discovery packet header is truncated"));
+        }
+
+        let version = packet[4];
+        let kind = packet[5];
+        let payload_len = u16::from_be_bytes([packet[6], packet[7]]) as usize;
+
+        if version != PROTO_VERSION {
+            return Err(anyhow::anyhow!(
+                "Discovery packet has unsupported version: {}",
+                version
+            ));
+        }
+
+        let mut payload = packet
+            .get(PROTO_HEADER_LEN..PROTO_HEADER_LEN + payload_len)
+            .ok_or_else(|| anyhow::Error::msg("Discovery packet payload is truncated"))?;
+
+        match kind {
+            PROTO_KIND_REQUEST => Ok(DiscoveryMessage::Request),
+            PROTO_KIND_RESPONSE => {
+                let proxy_port = Self::take(&mut payload, 2)
+                    .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))?;
+                let device_id = Self::take_str(&mut payload)?;
+                let version = Self::take_str(&mut payload)?;
+
+                Ok(DiscoveryMessage::Response(ServerAnnouncement {
+                    proxy_port,
+                    device_id,
+                    version,
+                }))
+            }
+            kind => Err(anyhow::anyhow!(
+                "Discovery packet has unknown kind: {}",
+                kind
+            )),
+        }
+    }
+
+    fn put_str(payload: &mut Vec<u8>, value: &str) -> anyhow::Result<()> {
+        payload.push(u8::try_from(value.len())?);
+        payload.extend_from_slice(value.as_bytes());
+
+        Ok(())
+    }
+
+    fn take<'a>(payload: &mut &'a [u8], len: usize) -> anyhow::Result<&'a [u8]> {
+        if payload.len() < len {
+            return Err(anyhow::Error::msg("Discovery packet payload is truncated"));
+        }
+
+        let (head, tail) = payload.split_at(len);
+        *payload = tail;
+
+        Ok(head)
+    }
+
+    fn take_str(payload: &mut &[u8]) -> anyhow::Result<String> {
+        let len = Self::take(payload, 1)?[0] as usize;
+        let bytes = Self::take(payload, len)?;
+
+        String::from_utf8(bytes.to_vec()).map_err(anyhow::Error::from)
     }
 }
 
-impl Default for ServerDiscovery {
-    fn default() -> Self {
-        Self {
-            port: constants::OLLANA_SERVER_DEFAULT_DISCOVERY_PORT,
-            local_ollama: Arc::new(Ollama::default()),
-            liveness_interval: DEFAULT_SERVER_LIVENESS_INTERVAL,
-            alive: Mutex::new(true),
+impl DiscoveryBackend {
+    pub async fn run(&self, cmd_tx: &Sender<ManagerCommand>) -> anyhow::Result<()> {
+        match self {
+            DiscoveryBackend::Udp(client_discovery) => client_discovery.run(cmd_tx).await,
+            DiscoveryBackend::Mdns(mdns_browser) => mdns_browser.run(cmd_tx).await,
         }
     }
 }
 
 impl ClientDiscovery {
+    pub fn new(
+        device: Arc<Device>,
+        ipv4_multicast_group: Option<Ipv4Addr>,
+        config: &DiscoveryConfig,
+    ) -> Self {
+        Self {
+            server_port: config.port,
+            broadcast_interval: config.broadcast_interval(),
+            ipv4_multicast_group,
+            ipv6_multicast_group: Some(config.ipv6_multicast_group),
+            device_id: device.id.clone(),
+        }
+    }
+
     pub async fn run(&self, cmd_tx: &Sender<ManagerCommand>) -> anyhow::Result<()> {
-        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, RANDOM_UDP_PORT)).await?;
-        let local_addr = socket.local_addr()?;
-        socket.set_broadcast(true)?;
+        let socket_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, RANDOM_UDP_PORT)).await?;
+        let local_addr = socket_v4.local_addr()?;
+        socket_v4.set_broadcast(true)?;
 
         info!("Running client discovery on {}...", local_addr);
 
+        let socket_v6 = match self.ipv6_multicast_group {
+            Some(_) => bind_ipv6(RANDOM_UDP_PORT)
+                .inspect(|socket| {
+                    if let Ok(local_addr) = socket.local_addr() {
+                        info!("Running client discovery on {}...", local_addr);
+                    }
+                })
+                .inspect_err(|error| warn!("Client discovery couldn't use IPv6: {}", error))
+                .ok(),
+            None => None,
+        };
+
         tokio::select! {
-            val = self.broadcast_periodically(&socket) => val,
-            val = self.handle_messages(&socket, cmd_tx) => val,
+            val = self.broadcast_periodically(&socket_v4, socket_v6.as_ref()) => val,
+            val = self.handle_messages(&socket_v4, cmd_tx) => val,
+            val = async {
+                match &socket_v6 {
+                    Some(socket) => self.handle_messages(socket, cmd_tx).await,
+                    None => std::future::pending().await,
+                }
+            } => val,
         }
     }
 
-    async fn broadcast_periodically(&self, socket: &UdpSocket) -> anyhow::Result<()> {
+    async fn broadcast_periodically(
+        &self,
+        socket_v4: &UdpSocket,
+        socket_v6: Option<&UdpSocket>,
+    ) -> anyhow::Result<()> {
         let mut stream = IntervalStream::new(time::interval(self.broadcast_interval));
 
+        let mut targets = vec![(socket_v4, (Ipv4Addr::BROADCAST, self.server_port).into())];
+
+        if let Some(group) = self.ipv4_multicast_group {
+            targets.push((socket_v4, (group, self.server_port).into()));
+        }
+
+        if let (Some(socket), Some(group)) = (socket_v6, self.ipv6_multicast_group) {
+            // The scope ID picks the interface a link-local group is sent out of
+            for interface in ipv6_interfaces_or_default() {
+                targets.push((
+                    socket,
+                    SocketAddrV6::new(group, self.server_port, 0, interface).into(),
+                ));
+            }
+        }
+
         while stream.next().await.is_some() {
-            if let Ok(len) = self.send(socket).await {
-                debug!("Client discovery sent {} bytes", len);
+            for (socket, target) in &targets {
+                if let Ok(len) = self.send(socket, *target).await {
+                    debug!("Client discovery sent {} bytes to {}", len, target);
+                }
             }
         }
 
@@ -88,77 +297,148 @@ impl ClientDiscovery {
         socket: &UdpSocket,
         cmd_tx: &Sender<ManagerCommand>,
     ) -> anyhow::Result<()> {
-        let mut buf: [u8; 4] = [0u8; 4];
+        let mut buf = [0u8; PROTO_MAX_PACKET_LEN];
 
         loop {
             if let Ok((len, addr)) = self.recv(socket, &mut buf).await {
                 debug!("Client discovery received {} bytes from {}", len, addr);
 
-                let magic = u32::from_be_bytes(buf);
-
-                if magic == PROTO_MAGIC_NUMBER {
-                    debug!("Client discovery found a server with address {}", addr);
-
-                    // TODO: replace default port with the actual server proxy port received from a server
-                    let http_addr = (addr.ip(), OLLANA_SERVER_PROXY_DEFAULT_PORT)
-                        .to_socket_addrs()?
-                        .next()
-                        .ok_or_else(|| {
-                            anyhow::Error::msg("Server proxy address is invalid".to_string())
-                        })?;
-
-                    cmd_tx
-                        .send(ManagerCommand::Add(http_addr))
-                        .await
-                        .unwrap_or(());
-                } else {
-                    let hex = format!("0X{:X}", magic);
-                    debug!("Client discovery skipped message: {}", hex);
-                }
+                let (proxy_port, device_id) = match DiscoveryMessage::decode(&buf[..len]) {
+                    Ok(DiscoveryMessage::Response(announcement)) => {
+                        debug!(
+                            "Client discovery found a server with address {}: proxy_port = {}, device_id = {}, version = {}",
+                            addr, announcement.proxy_port, announcement.device_id, announcement.version
+                        );
+
+                        if announcement.device_id == self.device_id {
+                            continue;
+                        }
+
+                        (announcement.proxy_port, Some(announcement.device_id))
+                    }
+                    Ok(DiscoveryMessage::LegacyHello) => {
+                        debug!(
+                            "Client discovery found a legacy server with address {}",
+                            addr
+                        );
+
+                        // Legacy servers don't announce their proxy port
+                        (OLLANA_SERVER_PROXY_DEFAULT_PORT, None)
+                    }
+                    Ok(DiscoveryMessage::Request) => continue,
+                    Err(error) => {
+                        debug!("Client discovery skipped message: {}", error);
+                        continue;
+                    }
+                };
+
+                // Keep the IPv6 scope ID of link-local addresses
+                let mut http_addr = addr;
+                http_addr.set_port(proxy_port);
+
+                cmd_tx
+                    .send(ManagerCommand::Add(http_addr, device_id))
+                    .await
+                    .unwrap_or(());
             }
         }
     }
 
-    async fn send(&self, socket: &UdpSocket) -> io::Result<usize> {
+    async fn send(&self, socket: &UdpSocket, target: SocketAddr) -> io::Result<usize> {
+        let packet = DiscoveryMessage::Request
+            .encode()
+            .map_err(io::Error::other)?;
+
         socket
-            .send_to(
-                &PROTO_MAGIC_NUMBER.to_be_bytes(),
-                (Ipv4Addr::BROADCAST, self.server_port),
-            )
+            .send_to(&packet, target)
             .await
+            .inspect(|_| metrics().observe_discovery_packet(DiscoveryRole::Client, true))
             .inspect_err(|error| error!("Client discovery error while sending: {}", error))
     }
 
-    async fn recv(&self, socket: &UdpSocket, buf: &mut [u8; 4]) -> io::Result<(usize, SocketAddr)> {
+    async fn recv(&self, socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
         socket
             .recv_from(buf)
             .await
-            .inspect_err(|error| error!("Server discovery error while receiving: {}", error))
+            .inspect(|_| metrics().observe_discovery_packet(DiscoveryRole::Client, false))
+            .inspect_err(|error| error!("Client discovery error while receiving: {}", error))
     }
 }
 
 impl ServerDiscovery {
-    pub fn new(local_ollama: Arc<Ollama>) -> Self {
+    /// Creates a discovery responder for the server proxy.
+    ///
+    /// # Arguments
+    /// * `local_ollama` - The local Ollama, discovery requests are only answered while it is up.
+    /// * `device` - This device.
+    /// * `ipv4_multicast_group` - An IPv4 multicast group to join in addition to broadcast.
+    /// * `config` - The discovery settings.
+    /// * `proxy_port` - The port of the server proxy to announce.
+    ///
+    pub fn new(
+        local_ollama: Arc<Ollama>,
+        device: Arc<Device>,
+        ipv4_multicast_group: Option<Ipv4Addr>,
+        config: &DiscoveryConfig,
+        proxy_port: u16,
+    ) -> Self {
         Self {
+            port: config.port,
+            ipv4_multicast_group,
+            ipv6_multicast_group: Some(config.ipv6_multicast_group),
+            proxy_port,
+            device_id: device.id.clone(),
             local_ollama,
-            ..Default::default()
+            liveness_interval: config.liveness_interval(),
+            alive: Mutex::new(true),
         }
     }
 
     pub async fn run(&self) -> anyhow::Result<()> {
-        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, self.port)).await?;
-        let local_addr = socket.local_addr()?;
+        let socket_v4 = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, self.port)).await?;
+        let local_addr = socket_v4.local_addr()?;
+
+        if let Some(group) = self.ipv4_multicast_group {
+            socket_v4.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED)?;
+
+            info!("Server discovery joined multicast group {}", group);
+        }
 
         info!("Running server discovery on {}...", local_addr);
 
+        let socket_v6 = self.ipv6_multicast_group.and_then(|group| {
+            bind_ipv6(self.port)
+                .and_then(|socket| {
+                    let interfaces =
+                        join_multicast_v6(&socket, &group, &ipv6_interfaces_or_default())?;
+
+                    info!(
+                        "Running server discovery on {} (multicast group {} on interfaces {:?})...",
+                        socket.local_addr()?,
+                        group,
+                        interfaces
+                    );
+
+                    Ok(socket)
+                })
+                .inspect_err(|error| warn!("Server discovery couldn't use IPv6: {}", error))
+                .ok()
+        });
+
         tokio::select! {
-            val = self.handle_messages(&socket) => val,
+            val = self.handle_messages(&socket_v4) => val,
+            val = async {
+                match &socket_v6 {
+                    Some(socket) => self.handle_messages(socket).await,
+                    None => std::future::pending().await,
+                }
+            } => val,
             val = self.run_liveness_check() => Ok(val),
         }
     }
 
     async fn handle_messages(&self, socket: &UdpSocket) -> anyhow::Result<()> {
-        let mut buf: [u8; 4] = [0u8; 4];
+        let mut buf = [0u8; PROTO_MAX_PACKET_LEN];
 
         loop {
             if let Ok((len, addr)) = self.recv(socket, &mut buf).await {
@@ -167,15 +447,25 @@ impl ServerDiscovery {
                 if *alive {
                     debug!("Server discovery received {} bytes from {}", len, addr);
 
-                    let magic = u32::from_be_bytes(buf);
-
-                    if magic == PROTO_MAGIC_NUMBER {
-                        if let Ok(len) = self.send(socket, addr).await {
-                            debug!("Server discovery sent {} bytes to {}", len, addr);
+                    // Reply using the same protocol version the client has used
+                    let reply = match DiscoveryMessage::decode(&buf[..len]) {
+                        Ok(DiscoveryMessage::Request) => {
+                            DiscoveryMessage::Response(ServerAnnouncement {
+                                proxy_port: self.proxy_port,
+                                device_id: self.device_id.clone(),
+                                version: env!("CARGO_PKG_VERSION").to_string(),
+                            })
+                        }
+                        Ok(DiscoveryMessage::LegacyHello) => DiscoveryMessage::LegacyHello,
+                        Ok(DiscoveryMessage::Response(_)) => continue,
+                        Err(error) => {
+                            debug!("Server discovery skipped message: {}", error);
+                            continue;
                         }
-                    } else {
-                        let hex = format!("0X{:X}", magic);
-                        debug!("Server discovery skipped message: {}", hex);
+                    };
+
+                    if let Ok(len) = self.send(socket, addr, &reply).await {
+                        debug!("Server discovery sent {} bytes to {}", len, addr);
                     }
                 }
             }
@@ -209,17 +499,252 @@ impl ServerDiscovery {
         }
     }
 
-    async fn recv(&self, socket: &UdpSocket, buf: &mut [u8; 4]) -> io::Result<(usize, SocketAddr)> {
+    async fn recv(&self, socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
         socket
             .recv_from(buf)
             .await
+            .inspect(|_| metrics().observe_discovery_packet(DiscoveryRole::Server, false))
             .inspect_err(|error| error!("Server discovery error while receiving: {}", error))
     }
 
-    async fn send(&self, socket: &UdpSocket, addr: SocketAddr) -> io::Result<usize> {
+    async fn send(
+        &self,
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        message: &DiscoveryMessage,
+    ) -> io::Result<usize> {
+        let packet = message.encode().map_err(io::Error::other)?;
+
         socket
-            .send_to(&PROTO_MAGIC_NUMBER.to_be_bytes(), addr)
+            .send_to(&packet, addr)
             .await
+            .inspect(|_| metrics().observe_discovery_packet(DiscoveryRole::Server, true))
             .inspect_err(|error| error!("Server discovery error while sending: {}", error))
     }
 }
+
+/// Binds an IPv6-only UDP socket, so it can share the port with an IPv4 socket.
+///
+/// # Arguments
+/// * `port` - The port to bind to, `0` picks a random one.
+///
+fn bind_ipv6(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+
+    socket.set_only_v6(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+/// Lists the indexes of the non-loopback interfaces with an IPv6 address.
+///
+/// # Returns
+/// The interface indexes, or just `0` (the default interface) if there are none or they
+/// can't be listed.
+///
+fn ipv6_interfaces_or_default() -> Vec<u32> {
+    let mut interfaces = if_addrs::get_if_addrs()
+        .inspect_err(|error| warn!("Couldn't list the network interfaces: {}", error))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|interface| matches!(interface.ip(), IpAddr::V6(_)) && !interface.is_loopback())
+        .filter_map(|interface| interface.index)
+        .collect::<Vec<_>>();
+
+    interfaces.sort_unstable();
+    interfaces.dedup();
+
+    if interfaces.is_empty() {
+        interfaces.push(0);
+    }
+
+    interfaces
+}
+
+/// Joins an IPv6 multicast group on each of the given interfaces.
+///
+/// # Arguments
+/// * `socket` - The socket to join the group with.
+/// * `group` - The multicast group.
+/// * `interfaces` - The interface indexes, `0` being the default interface.
+///
+/// # Returns
+/// The interfaces the group has been joined on, an interface failing is skipped.
+///
+/// # Errors
+/// If the group couldn't be joined on any of the interfaces.
+///
+fn join_multicast_v6(
+    socket: &UdpSocket,
+    group: &Ipv6Addr,
+    interfaces: &[u32],
+) -> io::Result<Vec<u32>> {
+    let mut joined = Vec::new();
+    let mut last_error = None;
+
+    for &interface in interfaces {
+        match socket.join_multicast_v6(group, interface) {
+            Ok(()) => joined.push(interface),
+            Err(error) => {
+                debug!(
+                    "Couldn't join multicast group {} on interface {}: {}",
+                    group, interface, error
+                );
+
+                last_error = Some(error);
+            }
+        }
+    }
+
+    match (joined.is_empty(), last_error) {
+        (true, Some(error)) => Err(error),
+        (true, None) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no interface to join the multicast group on",
+        )),
+        (false, _) => Ok(joined),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants;
+
+    use super::*;
+
+    fn response() -> DiscoveryMessage {
+        DiscoveryMessage::Response(ServerAnnouncement {
+            proxy_port: 11435,
+            device_id: "1d33566afe18293f".to_string(),
+            version: "0.6.2".to_string(),
+        })
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let packet = DiscoveryMessage::Request.encode().unwrap();
+
+        assert_eq!(packet.len(), PROTO_HEADER_LEN);
+        assert_eq!(
+            DiscoveryMessage::decode(&packet).unwrap(),
+            DiscoveryMessage::Request
+        );
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let packet = response().encode().unwrap();
+
+        assert_eq!(DiscoveryMessage::decode(&packet).unwrap(), response());
+    }
+
+    #[test]
+    fn legacy_hello_decodes() {
+        let packet = PROTO_MAGIC_NUMBER.to_be_bytes();
+
+        assert_eq!(
+            DiscoveryMessage::decode(&packet).unwrap(),
+            DiscoveryMessage::LegacyHello
+        );
+        assert_eq!(DiscoveryMessage::LegacyHello.encode().unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_bad_magic_number() {
+        let mut packet = DiscoveryMessage::Request.encode().unwrap();
+        packet[0] ^= 0xFF;
+
+        assert!(DiscoveryMessage::decode(&packet).is_err());
+        assert!(DiscoveryMessage::decode(&packet[..4]).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut packet = response().encode().unwrap();
+        packet[4] = PROTO_VERSION + 1;
+
+        assert!(DiscoveryMessage::decode(&packet).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        let packet = DiscoveryMessage::Request.encode().unwrap();
+
+        for len in [0, 2, 5, PROTO_HEADER_LEN - 1] {
+            assert!(DiscoveryMessage::decode(&packet[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_payload_length_beyond_packet() {
+        let mut packet = response().encode().unwrap();
+        let payload_len = (packet.len() - PROTO_HEADER_LEN + 1) as u16;
+        packet[6..8].copy_from_slice(&payload_len.to_be_bytes());
+
+        assert!(DiscoveryMessage::decode(&packet).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_response_payload() {
+        let packet = response().encode().unwrap();
+        let mut truncated = packet[..packet.len() - 1].to_vec();
+        let payload_len = (truncated.len() - PROTO_HEADER_LEN) as u16;
+        truncated[6..8].copy_from_slice(&payload_len.to_be_bytes());
+
+        assert!(DiscoveryMessage::decode(&truncated).is_err());
+    }
+
+    #[actix_web::test]
+    async fn ipv6_socket_shares_port_with_ipv4() {
+        let socket_v6 = bind_ipv6(RANDOM_UDP_PORT).unwrap();
+        let local_addr = socket_v6.local_addr().unwrap();
+
+        assert!(local_addr.is_ipv6());
+        assert!(socket2::SockRef::from(&socket_v6).only_v6().unwrap());
+
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, local_addr.port()))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn ipv6_interfaces_exclude_loopback() {
+        let loopback = if_addrs::get_if_addrs()
+            .unwrap()
+            .into_iter()
+            .filter(|interface| interface.is_loopback())
+            .filter_map(|interface| interface.index)
+            .collect::<Vec<_>>();
+        let interfaces = ipv6_interfaces_or_default();
+
+        assert!(!interfaces.is_empty());
+        assert!(interfaces.iter().all(|index| !loopback.contains(index)));
+    }
+
+    #[actix_web::test]
+    async fn joins_multicast_group_on_every_interface() {
+        let socket = bind_ipv6(RANDOM_UDP_PORT).unwrap();
+        let group = constants::OLLANA_DISCOVERY_IPV6_MULTICAST_GROUP;
+        let interfaces = ipv6_interfaces_or_default();
+
+        assert_eq!(
+            join_multicast_v6(&socket, &group, &interfaces).unwrap(),
+            interfaces
+        );
+    }
+
+    #[actix_web::test]
+    async fn skips_interfaces_failing_to_join() {
+        let socket = bind_ipv6(RANDOM_UDP_PORT).unwrap();
+        let group = constants::OLLANA_DISCOVERY_IPV6_MULTICAST_GROUP;
+
+        assert_eq!(
+            join_multicast_v6(&socket, &group, &[u32::MAX, 0]).unwrap(),
+            vec![0]
+        );
+        assert!(join_multicast_v6(&socket, &group, &[u32::MAX]).is_err());
+        assert!(join_multicast_v6(&socket, &group, &[]).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::{net::IpAddr, str::FromStr};
+
+use actix_web::{HttpRequest, HttpResponseBuilder};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+// Headers that only apply to a single connection and must not be forwarded, see RFC 9110
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+// Headers set by the HTTP client from the URL and the body of a forwarded request or response
+const MESSAGE_HEADERS: [&str; 2] = ["host", "content-length"];
+// The origin of a browser page calling the proxy, which would make Ollama reject the request
+// unless it is listed in the `OLLAMA_ORIGINS` of every server
+const ORIGIN: &str = "origin";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const FORWARDED: &str = "forwarded";
+
+/// Which headers of forwarded requests to remove or add, on top of the standard proxy behavior.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderRules {
+    /// Headers to remove, including the forwarding headers added by the proxy.
+    pub remove: Vec<HeaderName>,
+    /// Headers to set, replacing the ones of the request.
+    pub add: Vec<AddedHeader>,
+}
+
+/// A header to set on forwarded requests, given as `NAME: VALUE`.
+#[derive(Clone, Debug)]
+pub struct AddedHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for AddedHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("'{}' is not of the form NAME: VALUE", s))?;
+        let name = HeaderName::from_str(name.trim())
+            .map_err(|_| format!("'{}' is not a valid header name", name.trim()))?;
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("'{}' is not a valid header value", value.trim()))?;
+
+        Ok(AddedHeader { name, value })
+    }
+}
+
+impl HeaderRules {
+    /// Builds the headers of a request forwarded to another server.
+    ///
+    /// Hop-by-hop headers and `Origin` are left out, the client address is appended to
+    /// `X-Forwarded-For` and `Forwarded`, and then the rules are applied. `Origin` can be set
+    /// again with a rule.
+    ///
+    /// # Arguments
+    /// * `req` - The incoming request.
+    /// * `proto` - The protocol the request came in over, `http` or `https`.
+    ///
+    pub fn request_headers(&self, req: &HttpRequest, proto: &str) -> HeaderMap {
+        let connection_headers = connection_headers(req.headers().get_all("connection"));
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in req.headers() {
+            let name = name.as_str();
+
+            if is_hop_by_hop(name, &connection_headers)
+                || MESSAGE_HEADERS.contains(&name)
+                || name == ORIGIN
+            {
+                continue;
+            }
+
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_str(name),
+                HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                headers.append(name, value);
+            }
+        }
+
+        if let Some(peer_addr) = req.peer_addr() {
+            let host = req
+                .headers()
+                .get("host")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+
+            append_list(&mut headers, X_FORWARDED_FOR, &peer_addr.ip().to_string());
+            append_list(
+                &mut headers,
+                FORWARDED,
+                &format!(
+                    "for={};host=\"{}\";proto={}",
+                    forwarded_node(peer_addr.ip()),
+                    host.replace(['"', '\\'], ""),
+                    proto
+                ),
+            );
+        }
+
+        for name in &self.remove {
+            headers.remove(name);
+        }
+
+        for header in &self.add {
+            headers.insert(header.name.clone(), header.value.clone());
+        }
+
+        headers
+    }
+}
+
+/// Copies the headers of a response from another server, except for the hop-by-hop ones.
+///
+/// # Arguments
+/// * `from` - The headers of the upstream response.
+/// * `to` - The response to the client.
+///
+pub fn copy_response_headers(from: &HeaderMap, to: &mut HttpResponseBuilder) {
+    let connection_headers = connection_headers(from.get_all("connection").iter());
+
+    for (name, value) in from {
+        let name = name.as_str();
+
+        if !is_hop_by_hop(name, &connection_headers) && !MESSAGE_HEADERS.contains(&name) {
+            to.append_header((name, value.as_bytes()));
+        }
+    }
+}
+
+/// Returns the headers named in `Connection`, which are hop-by-hop as well.
+fn connection_headers<'a, V>(values: impl Iterator<Item = &'a V>) -> Vec<String>
+where
+    V: AsRef<[u8]> + 'a + ?Sized,
+{
+    values
+        .filter_map(|value| std::str::from_utf8(value.as_ref()).ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+fn is_hop_by_hop(name: &str, connection_headers: &[String]) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name) || connection_headers.iter().any(|h| h == name)
+}
+
+/// Appends an element to a comma-separated list header, merging all of its existing values.
+fn append_list(headers: &mut HeaderMap, name: &'static str, element: &str) {
+    let mut elements: Vec<String> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(String::from)
+        .collect();
+
+    elements.push(element.to_string());
+
+    if let Ok(value) = HeaderValue::from_str(&elements.join(", ")) {
+        headers.insert(name, value);
+    }
+}
+
+/// Formats an address as a node of the `Forwarded` header, IPv6 addresses have to be quoted.
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use actix_web::{test::TestRequest, HttpResponse};
+
+    use super::*;
+
+    fn peer(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 50000)
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn request_headers_leave_out_hop_by_hop_headers() {
+        let req = TestRequest::default()
+            .insert_header(("Host", "localhost:11434"))
+            .insert_header(("Connection", "keep-alive, X-Secret"))
+            .insert_header(("Keep-Alive", "timeout=5"))
+            .insert_header(("Transfer-Encoding", "chunked"))
+            .insert_header(("Upgrade", "websocket"))
+            .insert_header(("Content-Length", "12"))
+            .insert_header(("X-Secret", "hush"))
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header(("Authorization", "Bearer token"))
+            .to_http_request();
+
+        let headers = HeaderRules::default().request_headers(&req, "http");
+        let mut names: Vec<_> = headers.keys().map(|name| name.as_str()).collect();
+        names.sort();
+
+        assert_eq!(names, ["authorization", "content-type"]);
+    }
+
+    #[test]
+    fn request_headers_leave_out_origin() {
+        let req = TestRequest::default()
+            .insert_header(("Origin", "http://localhost:3000"))
+            .to_http_request();
+
+        assert!(!HeaderRules::default()
+            .request_headers(&req, "http")
+            .contains_key("origin"));
+
+        let rules = HeaderRules {
+            remove: Vec::new(),
+            add: vec!["Origin: http://localhost:11434".parse().unwrap()],
+        };
+
+        assert_eq!(
+            header(&rules.request_headers(&req, "http"), "origin"),
+            ["http://localhost:11434"]
+        );
+    }
+
+    #[test]
+    fn request_headers_append_forwarding_headers() {
+        let req = TestRequest::default()
+            .insert_header(("Host", "gateway:11434"))
+            .append_header(("X-Forwarded-For", "10.0.0.1"))
+            .append_header(("X-Forwarded-For", "10.0.0.2"))
+            .insert_header(("Forwarded", "for=10.0.0.1"))
+            .peer_addr(peer("192.168.1.20"))
+            .to_http_request();
+
+        let headers = HeaderRules::default().request_headers(&req, "https");
+
+        assert_eq!(
+            header(&headers, "x-forwarded-for"),
+            ["10.0.0.1, 10.0.0.2, 192.168.1.20"]
+        );
+        assert_eq!(
+            header(&headers, "forwarded"),
+            ["for=10.0.0.1, for=192.168.1.20;host=\"gateway:11434\";proto=https"]
+        );
+    }
+
+    #[test]
+    fn request_headers_quote_ipv6_nodes() {
+        let req = TestRequest::default()
+            .peer_addr(peer("fe80::1"))
+            .to_http_request();
+
+        let headers = HeaderRules::default().request_headers(&req, "http");
+
+        assert_eq!(header(&headers, "x-forwarded-for"), ["fe80::1"]);
+        assert_eq!(
+            header(&headers, "forwarded"),
+            ["for=\"[fe80::1]\";host=\"\";proto=http"]
+        );
+    }
+
+    #[test]
+    fn request_headers_apply_rules() {
+        let req = TestRequest::default()
+            .insert_header(("X-Team", "ops"))
+            .insert_header(("X-Debug", "1"))
+            .peer_addr(peer("192.168.1.20"))
+            .to_http_request();
+        let rules = HeaderRules {
+            remove: vec![
+                HeaderName::from_static("x-debug"),
+                HeaderName::from_static("forwarded"),
+            ],
+            add: vec!["X-Team: research".parse().unwrap()],
+        };
+
+        let headers = rules.request_headers(&req, "http");
+
+        assert_eq!(header(&headers, "x-team"), ["research"]);
+        assert_eq!(header(&headers, "x-forwarded-for"), ["192.168.1.20"]);
+        assert!(!headers.contains_key("x-debug"));
+        assert!(!headers.contains_key("forwarded"));
+    }
+
+    #[test]
+    fn copy_response_headers_leaves_out_hop_by_hop_headers() {
+        let mut from = HeaderMap::new();
+        from.insert("connection", HeaderValue::from_static("close, x-upstream"));
+        from.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+        from.insert("x-upstream", HeaderValue::from_static("1"));
+        from.insert("content-type", HeaderValue::from_static("application/json"));
+
+        let mut builder = HttpResponse::Ok();
+        copy_response_headers(&from, &mut builder);
+        let response = builder.finish();
+        let mut names: Vec<_> = response
+            .headers()
+            .keys()
+            .map(|name| name.as_str())
+            .collect();
+        names.sort();
+
+        assert_eq!(names, ["content-type"]);
+    }
+
+    #[test]
+    fn parses_added_headers() {
+        let header: AddedHeader = "X-Team:  research lab ".parse().unwrap();
+
+        assert_eq!(header.name, "x-team");
+        assert_eq!(header.value, "research lab");
+
+        let header: AddedHeader = "X-Empty:".parse().unwrap();
+
+        assert_eq!(header.value, "");
+    }
+
+    #[test]
+    fn rejects_invalid_added_headers() {
+        assert_eq!(
+            "X-Team".parse::<AddedHeader>().unwrap_err(),
+            "'X-Team' is not of the form NAME: VALUE"
+        );
+        assert_eq!(
+            "X Team: research".parse::<AddedHeader>().unwrap_err(),
+            "'X Team' is not a valid header name"
+        );
+        assert_eq!(
+            ": research".parse::<AddedHeader>().unwrap_err(),
+            "'' is not a valid header name"
+        );
+        assert_eq!(
+            "X-Team: research\nlab".parse::<AddedHeader>().unwrap_err(),
+            "'research\nlab' is not a valid header value"
+        );
+    }
+}
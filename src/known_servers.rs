@@ -0,0 +1,346 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::{error, info};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::WebPkiSupportedAlgorithms,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    certs::{self, DeviceIdentity},
+    get_local_dir, write_atomically,
+};
+
+const KNOWN_SERVERS_TOML: &str = "known_servers.toml";
+
+/// Certificate fingerprints of the servers the client has connected to, pinned on first use.
+///
+/// A fingerprint is the SHA-256 digest of the server certificate's public key, i.e. the server's
+/// Device ID. Pins are keyed by server address and stored in `known_servers.toml` next to
+/// `device_allowed.toml`. They are kept in memory, the file is only read again when a server
+/// doesn't match, in case its pin has been forgotten in the meantime.
+pub struct KnownServers {
+    dir: PathBuf,
+    // Also serializes updates of the file within the process
+    servers: Mutex<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct KnownServersConfig {
+    servers: BTreeMap<String, String>,
+}
+
+/// Accepts a server certificate only if it matches the pinned fingerprint of the server, pinning
+/// it if the server hasn't been seen before.
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    server: SocketAddr,
+    known_servers: Arc<KnownServers>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl KnownServers {
+    pub fn new() -> anyhow::Result<Self> {
+        Self::with_dir(get_local_dir()?)
+    }
+
+    pub(crate) fn with_dir(dir: PathBuf) -> anyhow::Result<Self> {
+        let servers = Self::load_config(&dir)?.servers;
+
+        Ok(Self {
+            dir,
+            servers: Mutex::new(servers),
+        })
+    }
+
+    /// Returns all pinned fingerprints by server address.
+    pub fn list(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        Ok(self.servers.lock().unwrap().clone())
+    }
+
+    /// Returns the pinned fingerprint of a server, if any.
+    pub fn fingerprint(&self, server: SocketAddr) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .servers
+            .lock()
+            .unwrap()
+            .get(&server.to_string())
+            .cloned())
+    }
+
+    /// Removes the pinned fingerprint of a server, so that it is pinned again on the next connection.
+    ///
+    /// Returns `true` if the server had a pinned fingerprint.
+    ///
+    /// # Arguments
+    /// * `address`: The server address as listed by [`KnownServers::list`].
+    ///
+    pub fn forget(&self, address: &str) -> anyhow::Result<bool> {
+        let mut servers = self.servers.lock().unwrap();
+        let mut config = Self::load_config(&self.dir)?;
+
+        if config.servers.remove(address).is_some() {
+            Self::save_config(&self.dir, &config)?;
+            *servers = config.servers;
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Removes all pinned fingerprints.
+    pub fn reset(&self) -> anyhow::Result<()> {
+        let mut servers = self.servers.lock().unwrap();
+
+        Self::save_config(&self.dir, &KnownServersConfig::default())?;
+        servers.clear();
+
+        Ok(())
+    }
+
+    /// Builds a TLS configuration for connecting to a server proxy.
+    ///
+    /// The server certificate is checked against the pinned fingerprint of the server, and the
+    /// device certificate is presented to the server.
+    ///
+    /// # Arguments
+    /// * `server` - The address of the server proxy.
+    /// * `identity` - The device identity to authenticate with.
+    ///
+    pub fn tls_config(
+        self: &Arc<Self>,
+        server: SocketAddr,
+        identity: DeviceIdentity,
+    ) -> anyhow::Result<rustls::ClientConfig> {
+        let verifier = PinnedServerVerifier {
+            server,
+            known_servers: self.clone(),
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        };
+
+        rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_client_auth_cert(vec![identity.cert], identity.key)
+            .map_err(anyhow::Error::from)
+    }
+
+    /// Checks a server fingerprint against the pinned one, pinning it on first use.
+    ///
+    /// Returns `false` if the server has a different fingerprint pinned.
+    fn check(&self, server: SocketAddr, fingerprint: &str) -> anyhow::Result<bool> {
+        let mut servers = self.servers.lock().unwrap();
+        let address = server.to_string();
+
+        if servers
+            .get(&address)
+            .is_some_and(|pinned| pinned == fingerprint)
+        {
+            return Ok(true);
+        }
+
+        // The pin may have been forgotten, or added, by another process
+        let mut config = Self::load_config(&self.dir)?;
+
+        let is_pinned = match config.servers.get(&address) {
+            Some(pinned) => Ok(pinned == fingerprint),
+            None => {
+                info!(
+                    "Pinning certificate of Ollana server {}: {}",
+                    address, fingerprint
+                );
+
+                config.servers.insert(address, fingerprint.to_string());
+                Self::save_config(&self.dir, &config)?;
+
+                Ok(true)
+            }
+        };
+
+        *servers = config.servers;
+
+        is_pinned
+    }
+
+    /// Loads the known servers from a TOML file, there are none if the file doesn't exist.
+    ///
+    /// # Arguments
+    /// * `dir`: A reference to the directory where the file is located.
+    ///
+    fn load_config(dir: &Path) -> anyhow::Result<KnownServersConfig> {
+        let path = dir.join(KNOWN_SERVERS_TOML);
+
+        if !path.exists() {
+            return Ok(KnownServersConfig::default());
+        }
+
+        let toml_str = std::fs::read_to_string(path)?;
+
+        toml::from_str(&toml_str).map_err(anyhow::Error::from)
+    }
+
+    /// Saves the known servers to a TOML file.
+    ///
+    /// # Arguments
+    /// * `dir`: A reference to the directory where the file should be saved.
+    /// * `config`: The known servers to serialize and save.
+    ///
+    fn save_config(dir: &Path, config: &KnownServersConfig) -> anyhow::Result<()> {
+        let toml_str = toml::to_string_pretty(&config)?;
+
+        write_atomically(&dir.join(KNOWN_SERVERS_TOML), toml_str.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to write a known servers file: {}", e))
+    }
+}
+
+impl std::fmt::Debug for KnownServers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KnownServers")
+            .field("dir", &self.dir)
+            .finish()
+    }
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = certs::device_id(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        match self.known_servers.check(self.server, &fingerprint) {
+            Ok(true) => Ok(ServerCertVerified::assertion()),
+            Ok(false) => {
+                error!(
+                    "Certificate of Ollana server {} has changed to {}! Someone could be \
+                     impersonating the server. If the change is expected, run \
+                     `ollana known-servers forget {}`",
+                    self.server, fingerprint, self.server
+                );
+
+                Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+            Err(error) => Err(rustls::Error::General(format!(
+                "Couldn't check the pinned certificate: {}",
+                error
+            ))),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::TempDir;
+
+    use super::*;
+
+    fn server(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 20], port))
+    }
+
+    #[test]
+    fn pins_on_first_use() {
+        let dir = TempDir::new();
+        let known_servers = KnownServers::with_dir(dir.to_path_buf()).unwrap();
+
+        assert_eq!(known_servers.fingerprint(server(1)).unwrap(), None);
+        assert!(known_servers.check(server(1), "aaaa").unwrap());
+        assert!(known_servers.check(server(1), "aaaa").unwrap());
+        assert_eq!(
+            known_servers.fingerprint(server(1)).unwrap().as_deref(),
+            Some("aaaa")
+        );
+
+        // Pins are kept across restarts
+        let known_servers = KnownServers::with_dir(dir.to_path_buf()).unwrap();
+        assert_eq!(
+            known_servers.list().unwrap(),
+            BTreeMap::from([(server(1).to_string(), "aaaa".to_string())])
+        );
+    }
+
+    #[test]
+    fn rejects_changed_fingerprint() {
+        let dir = TempDir::new();
+        let known_servers = KnownServers::with_dir(dir.to_path_buf()).unwrap();
+
+        assert!(known_servers.check(server(1), "aaaa").unwrap());
+        assert!(!known_servers.check(server(1), "bbbb").unwrap());
+        assert!(known_servers.check(server(2), "bbbb").unwrap());
+        assert_eq!(
+            known_servers.fingerprint(server(1)).unwrap().as_deref(),
+            Some("aaaa")
+        );
+    }
+
+    #[test]
+    fn forgotten_servers_are_pinned_again() {
+        let dir = TempDir::new();
+        let known_servers = KnownServers::with_dir(dir.to_path_buf()).unwrap();
+
+        known_servers.check(server(1), "aaaa").unwrap();
+        known_servers.check(server(2), "aaaa").unwrap();
+
+        assert!(known_servers.forget(&server(1).to_string()).unwrap());
+        assert!(!known_servers.forget(&server(1).to_string()).unwrap());
+        assert!(known_servers.check(server(1), "bbbb").unwrap());
+        assert!(!known_servers.check(server(2), "bbbb").unwrap());
+
+        known_servers.reset().unwrap();
+
+        assert!(known_servers.list().unwrap().is_empty());
+        assert!(known_servers.check(server(2), "bbbb").unwrap());
+    }
+
+    #[test]
+    fn sees_pins_forgotten_by_another_process() {
+        let dir = TempDir::new();
+        let daemon = KnownServers::with_dir(dir.to_path_buf()).unwrap();
+
+        daemon.check(server(1), "aaaa").unwrap();
+        KnownServers::with_dir(dir.to_path_buf())
+            .unwrap()
+            .forget(&server(1).to_string())
+            .unwrap();
+
+        assert!(daemon.check(server(1), "bbbb").unwrap());
+    }
+}
@@ -1,21 +1,55 @@
-use std::path::PathBuf;
+use std::{
+    fs::File,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use url::Url;
 
 pub mod args;
+pub mod balancer;
 pub mod certs;
+pub mod config;
 pub mod constants;
+pub mod control;
 pub mod device;
 pub mod discovery;
+pub mod headers;
+pub mod known_servers;
 pub mod manager;
+pub mod mdns;
+pub mod metrics;
 pub mod ollama;
 pub mod ollana;
+pub mod pairing;
+pub mod permission;
+pub mod preference;
 pub mod proxy;
+pub mod rate_limit;
+pub mod scheduler;
 pub mod serve_app;
+#[cfg(test)]
+mod test_support;
+pub mod usage;
+
+// Host name reported by clients to servers, to help recognizing pending devices
+pub const HTTP_HEADER_OLLANA_HOSTNAME: &str = "X-Ollana-Hostname";
 
-pub const HTTP_HEADER_OLLANA_DEVICE_ID: &str = "X-Ollana-Device-Id";
+// How long HTTP clients wait for a connection to another device before giving up on it
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
+// Placeholder host name used in URLs of link-local IPv6 addresses, since zone IDs can't be
+// represented in URLs. HTTP clients resolve it to the actual scoped address.
+const SCOPED_IPV6_HOST: &str = "ollana-scoped-ipv6";
+
+#[derive(Debug)]
 pub enum Mode {
     Client,
     Server,
+    /// Server mode with a client proxy for the other servers on a port of its own
+    Hybrid,
 }
 
 /// Returns the path to the local data directory used by Ollana.
@@ -39,3 +73,89 @@ fn get_local_dir() -> anyhow::Result<PathBuf> {
             "Couldn't determine data local directory",
         ))
 }
+
+/// Replaces the contents of a file, so that readers see either the old or the new contents.
+///
+/// The contents are written to a temporary file in the same directory first, which is then
+/// renamed over the file.
+///
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a file path"))?;
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+/// Builds a URL with the given scheme pointing to the given socket address.
+///
+/// IPv6 addresses are enclosed in square brackets, e.g. `https://[fd00::1]:11435`. Link-local IPv6
+/// addresses with a zone ID use a placeholder host name instead, which is resolved by HTTP clients
+/// built with [`http_client_builder`].
+///
+/// # Errors
+/// Returns an error if the resulting URL cannot be parsed.
+///
+pub fn socket_addr_url(scheme: &str, socket_addr: SocketAddr) -> anyhow::Result<Url> {
+    let host = match socket_addr {
+        SocketAddr::V4(addr) => addr.ip().to_string(),
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => SCOPED_IPV6_HOST.to_string(),
+        SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+    };
+
+    Url::parse(&format!("{}://{}:{}", scheme, host, socket_addr.port()))
+        .map_err(anyhow::Error::from)
+}
+
+/// Returns an HTTP client builder able to reach the given socket address through URLs built with
+/// [`socket_addr_url`].
+///
+/// Connecting times out, so that an unreachable device fails fast instead of hanging requests.
+///
+pub fn http_client_builder(socket_addr: SocketAddr) -> reqwest::ClientBuilder {
+    let builder = reqwest::ClientBuilder::new().connect_timeout(HTTP_CONNECT_TIMEOUT);
+
+    match socket_addr {
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => {
+            builder.resolve(SCOPED_IPV6_HOST, socket_addr)
+        }
+        _ => builder,
+    }
+}
+
+/// Appends the given default port to a `HOST[:PORT]` address if it doesn't have one.
+///
+pub fn with_default_port(address: &str, default_port: u16) -> String {
+    if address.parse::<SocketAddr>().is_ok() {
+        return address.to_string();
+    }
+
+    let bare_ip = address
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>();
+
+    match bare_ip {
+        Ok(ip) => SocketAddr::new(ip, default_port).to_string(),
+        Err(_) => match address.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => address.to_string(),
+            _ => format!("{}:{}", address, default_port),
+        },
+    }
+}
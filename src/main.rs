@@ -1,12 +1,25 @@
 use clap::Parser;
 use env_logger::{Builder, Env};
 use ollana::{
-    args::{Args, DeviceCommands},
+    args::{
+        Args, ConfigCommands, DeviceCommands, KnownServersCommands, PairArgs, StatsArgs,
+        StatsFormat,
+    },
     certs::Certs,
-    device::Device,
+    config::Config,
+    control::Control,
+    device::{Device, DeviceUpdate},
+    known_servers::KnownServers,
+    pairing::Pairing,
+    permission,
     serve_app::ServeApp,
+    usage::{self, UsageFilter, UsageLog, UsageSummary},
+};
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    sync::Arc,
 };
-use std::{fs::OpenOptions, sync::Arc};
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
@@ -15,6 +28,9 @@ fn main() -> anyhow::Result<()> {
 
     match args {
         Args::Serve(args) => {
+            let config = Config::load(args.config.path.clone())?;
+            config.validate_serve(args.hybrid, args.metrics)?;
+
             // Configure logging based on whether a log file was specified
             let mut builder = Builder::from_env(Env::default().default_filter_or("info"));
 
@@ -34,7 +50,7 @@ fn main() -> anyhow::Result<()> {
                 builder.init();
             }
 
-            let serve_app = ServeApp::new(args, certs, device)?;
+            let serve_app = ServeApp::new(args, config, certs, device)?;
 
             serve_app.run()
         }
@@ -44,18 +60,60 @@ fn main() -> anyhow::Result<()> {
             Ok(())
         }
         Args::Device(DeviceCommands::List) => {
-            println!("Allowed Device IDs:");
-            for id in &device.allowed {
-                println!("{}", id);
+            println!("Allowed devices:");
+            for allowed in device.allowed() {
+                println!(
+                    "{} name: {}, labels: {}, role: {}, models: {}, limits: {}, priority: {}, added: {}, last seen: {}",
+                    allowed.id,
+                    allowed.name.as_deref().unwrap_or("-"),
+                    if allowed.labels.is_empty() {
+                        "-".to_string()
+                    } else {
+                        allowed.labels.join(",")
+                    },
+                    allowed.role,
+                    if allowed.is_model_restricted() {
+                        allowed.models.join(",")
+                    } else {
+                        permission::ANY_MODEL.to_string()
+                    },
+                    allowed.limits,
+                    allowed.priority,
+                    allowed.added.map_or("-".to_string(), format_timestamp),
+                    allowed.last_seen.map_or("-".to_string(), format_timestamp)
+                );
             }
 
             Ok(())
         }
-        Args::Device(DeviceCommands::Allow { id }) => {
-            let is_allowed = device.allow(id.clone())?;
+        Args::Device(DeviceCommands::Allow {
+            id,
+            name,
+            labels,
+            role,
+            models,
+            requests_per_minute,
+            max_concurrent,
+            tokens_per_hour,
+            priority,
+        }) => {
+            let update = DeviceUpdate {
+                name,
+                labels,
+                role,
+                models,
+                requests_per_minute,
+                max_concurrent,
+                tokens_per_hour,
+                priority,
+            };
+            let is_updated = !update.is_empty();
+            let is_allowed = device.allow(id.clone(), update)?;
 
             if is_allowed {
-                println!("Added Device ID: {}", id);
+                println!("Added device: {}", device.display_name(&id));
+            } else if is_updated {
+                println!("Updated device: {}", device.display_name(&id));
             } else {
                 println!("The given Device ID has been allowed already")
             }
@@ -63,15 +121,309 @@ fn main() -> anyhow::Result<()> {
             Ok(())
         }
         Args::Device(DeviceCommands::Disable { id }) => {
-            let is_disabled = device.disable(id.clone())?;
+            let name = device.display_name(&id);
+            let is_disabled = device.disable(id)?;
 
             if is_disabled {
-                println!("Removed Device ID: {}", id);
+                println!("Removed device: {}", name);
             } else {
                 println!("The given Device ID has not beed allowed");
             }
 
             Ok(())
         }
+        Args::Device(DeviceCommands::Pending) => {
+            println!("Pending Device IDs:");
+            for pending in device.pending()? {
+                println!(
+                    "{} address: {}, hostname: {}, first seen: {}, last seen: {}",
+                    pending.id,
+                    pending.address,
+                    pending.hostname.as_deref().unwrap_or("-"),
+                    format_timestamp(pending.first_seen),
+                    format_timestamp(pending.last_seen)
+                );
+            }
+
+            Ok(())
+        }
+        Args::Device(DeviceCommands::Approve { id, name }) => {
+            let is_approved = device.approve(id.clone(), name)?;
+
+            if is_approved {
+                println!("Approved device: {}", device.display_name(&id));
+            } else {
+                println!("The given Device ID is not pending");
+            }
+
+            Ok(())
+        }
+        Args::Pair(PairArgs {
+            address: None,
+            code: _,
+            config,
+        }) => {
+            let config = Config::load(config.path)?;
+            let pairing = Pairing::listen(device, config.pairing.port)?;
+
+            println!("Pairing code: {}", pairing.code());
+            println!("Run `ollana pair <ADDRESS>` on the other device, where <ADDRESS> is the address of this device, and enter the code");
+
+            let id = pairing.accept()?;
+
+            println!("Paired with Device ID: {}", id);
+
+            Ok(())
+        }
+        Args::Pair(PairArgs {
+            address: Some(address),
+            code,
+            config,
+        }) => {
+            let config = Config::load(config.path)?;
+            let code = match code {
+                Some(code) => code,
+                None => {
+                    print!("Enter the pairing code shown on the other device: ");
+                    io::stdout().flush()?;
+
+                    let mut code = String::new();
+                    io::stdin().read_line(&mut code)?;
+
+                    code
+                }
+            };
+
+            let id = Pairing::join(device, &address, config.pairing.port, &code)?;
+
+            println!("Paired with Device ID: {}", id);
+
+            Ok(())
+        }
+        Args::KnownServers(KnownServersCommands::List) => {
+            println!("Known servers:");
+            for (address, fingerprint) in KnownServers::new()?.list()? {
+                println!("{} {}", address, fingerprint);
+            }
+
+            Ok(())
+        }
+        Args::KnownServers(KnownServersCommands::Forget { address }) => {
+            let is_forgotten = KnownServers::new()?.forget(&address)?;
+
+            if is_forgotten {
+                println!("Forgot server: {}", address);
+            } else {
+                println!("The given server is not known");
+            }
+
+            Ok(())
+        }
+        Args::KnownServers(KnownServersCommands::Reset) => {
+            KnownServers::new()?.reset()?;
+
+            println!("Forgot all servers");
+
+            Ok(())
+        }
+        Args::Stats(args) => print_stats(args, &device),
+        Args::Status(args) => {
+            let status = Control::status(args.socket)?;
+
+            println!("Mode: {}", status.mode);
+            println!("Uptime: {}", format_uptime(status.uptime_secs));
+            println!("Device ID: {}", status.device_id);
+            println!("Version: {}, PID: {}", status.version, status.pid);
+            if let Some(proxy) = &status.server_proxy {
+                println!("Server proxy: {}", proxy.address);
+            }
+            if let Some(proxy) = &status.client_proxy {
+                println!(
+                    "Client proxy: {} ({})",
+                    proxy.address,
+                    if proxy.is_running {
+                        "running"
+                    } else {
+                        "waiting for servers"
+                    }
+                );
+            }
+            if status.client_proxy.is_some() {
+                println!(
+                    "Servers: {}, active: {}",
+                    status.servers.len(),
+                    status.servers.iter().filter(|s| s.is_active).count()
+                );
+            }
+            if let Some(queue) = &status.queue {
+                println!(
+                    "Queue: running: {}/{}, queued: {}, dispatched: {}, max wait: {:.1}s",
+                    queue.running,
+                    queue.capacity,
+                    queue.queued,
+                    queue.dispatched,
+                    queue.wait_seconds_max
+                );
+            }
+            println!("Allowed devices: {}", status.allowed_devices.len());
+
+            Ok(())
+        }
+        Args::Servers(args) => {
+            let status = Control::status(args.socket)?;
+
+            println!("Servers:");
+            for server in status.servers {
+                println!(
+                    "{} device: {}, priority: {}, active: {}, in flight: {}, latency: {}, last response: {}",
+                    server.address,
+                    server.name.unwrap_or(server.device_id),
+                    server.priority,
+                    server.is_active,
+                    server.in_flight,
+                    server
+                        .latency_ms
+                        .map_or("-".to_string(), |ms| format!("{}ms", ms)),
+                    server
+                        .responded_secs_ago
+                        .map_or("-".to_string(), |secs| format!("{}s ago", secs))
+                );
+            }
+
+            Ok(())
+        }
+        Args::Config(ConfigCommands::Show(args)) => {
+            let config = Config::load(args.path)?;
+
+            match &config.file {
+                Some(file) => println!("# Read from {}", file.display()),
+                None => println!("# No configuration file, using the defaults"),
+            }
+            for name in &config.env_overrides {
+                println!("# Overridden by {}", name);
+            }
+            println!();
+            print!("{}", config.to_toml()?);
+
+            Ok(())
+        }
+    }
+}
+
+fn print_stats(args: StatsArgs, device: &Device) -> anyhow::Result<()> {
+    let filter = UsageFilter {
+        since: args.since,
+        until: args.until,
+        device: args.device,
+        model: args.model,
+    };
+    let summaries = usage::summarize(&UsageLog::new()?.records()?, &filter, &args.groupings);
+    let rows: Vec<_> = summaries
+        .iter()
+        .map(|summary| stats_row(summary, device))
+        .collect();
+
+    match args.format {
+        StatsFormat::Text => {
+            println!("Usage:");
+            for (summary, row) in summaries.iter().zip(&rows) {
+                let group: Vec<_> = row
+                    .iter()
+                    .filter(|(key, _)| ["name", "model", "period"].contains(key))
+                    .filter_map(|(_, value)| value.as_str())
+                    .collect();
+
+                println!(
+                    "{} requests: {}, prompt tokens: {}, completion tokens: {}, duration: {:.1}s",
+                    if group.is_empty() {
+                        "total".to_string()
+                    } else {
+                        group.join(" ")
+                    },
+                    summary.requests,
+                    summary.prompt_tokens,
+                    summary.completion_tokens,
+                    summary.duration_ms as f64 / 1000.0
+                );
+            }
+        }
+        StatsFormat::Csv => {
+            let header = rows
+                .first()
+                .map(|row| row.iter().map(|(key, _)| *key).collect::<Vec<_>>())
+                .unwrap_or_default();
+
+            println!("{}", header.join(","));
+            for row in &rows {
+                let fields: Vec<_> = row
+                    .iter()
+                    .map(|(_, value)| match value {
+                        serde_json::Value::String(s) if s.contains([',', '"', '\n']) => {
+                            format!("\"{}\"", s.replace('"', "\"\""))
+                        }
+                        serde_json::Value::String(s) => s.clone(),
+                        value => value.to_string(),
+                    })
+                    .collect();
+
+                println!("{}", fields.join(","));
+            }
+        }
+        StatsFormat::Json => {
+            let rows: Vec<serde_json::Map<_, _>> = rows
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|(key, value)| (key.to_string(), value))
+                        .collect()
+                })
+                .collect();
+
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        }
     }
+
+    Ok(())
+}
+
+/// Flattens a usage summary into named columns, showing devices by name and periods as times.
+fn stats_row(summary: &UsageSummary, device: &Device) -> Vec<(&'static str, serde_json::Value)> {
+    let mut row = Vec::new();
+
+    if let Some(id) = &summary.device {
+        row.push(("device", id.clone().into()));
+        row.push(("name", device.display_name(id).into()));
+    }
+    if let Some(model) = &summary.model {
+        row.push(("model", model.clone().into()));
+    }
+    if let Some(period) = summary.period {
+        row.push(("period", format_timestamp(period).into()));
+    }
+
+    row.push(("requests", summary.requests.into()));
+    row.push(("prompt_tokens", summary.prompt_tokens.into()));
+    row.push(("completion_tokens", summary.completion_tokens.into()));
+    row.push(("duration_ms", summary.duration_ms.into()));
+
+    row
+}
+
+fn format_uptime(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..86400 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+fn format_timestamp(secs: u64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(secs as i64)
+        .ok()
+        .and_then(|t| {
+            t.format(&time::format_description::well_known::Rfc3339)
+                .ok()
+        })
+        .unwrap_or_else(|| secs.to_string())
 }
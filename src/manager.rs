@@ -1,57 +1,234 @@
-use std::{collections::VecDeque, net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use futures_util::StreamExt;
+use futures_util::{future, FutureExt, StreamExt};
 use tokio::{
-    sync::mpsc::{self, Receiver, Sender},
+    net::lookup_host,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        watch,
+    },
     task::{AbortHandle, JoinHandle},
-    time,
+    time::{self, Instant},
 };
 use tokio_stream::wrappers::IntervalStream;
 
 use crate::{
-    device::Device, discovery::ClientDiscovery, ollama::Ollama, ollana::Ollana, proxy::ClientProxy,
+    balancer::{Balancer, BalancingStrategy, Upstream},
+    constants::OLLANA_SERVER_PROXY_DEFAULT_PORT,
+    device::Device,
+    discovery::DiscoveryBackend,
+    known_servers::KnownServers,
+    metrics::metrics,
+    ollama::{ModelsResponse, Ollama},
+    ollana::Ollana,
+    preference::ServerPreferences,
+    proxy::{ClientProxy, ClientProxyOptions},
+    with_default_port,
 };
 use log::{debug, error, info};
 
-const DEFAULT_LIVENESS_INTERVAL: Duration = Duration::from_secs(10);
-
-pub struct ActiveProxy {
-    proxy: ClientProxy,
+pub struct KnownServer {
     server: SocketAddr,
+    device_id: String,
     liveness_handle: AbortHandle,
 }
 
+/// A server being authorized and checked before it is registered.
+struct ConnectingServer {
+    server: SocketAddr,
+    // The Device ID the server has announced or is pinned with, if known
+    device_id: Option<String>,
+    handle: AbortHandle,
+}
+
+/// A server that has been authorized and checked, ready to be registered.
+struct ConnectedServer {
+    upstream: Arc<Upstream>,
+    ollama: Ollama,
+    // Installed and loaded models, if the server has listed them
+    models: Option<(Vec<String>, Vec<String>)>,
+}
+
+type Connection = (SocketAddr, Option<ConnectedServer>);
+
 pub struct Manager {
-    servers: VecDeque<SocketAddr>,
-    active_proxy: Option<ActiveProxy>,
+    servers: Vec<KnownServer>,
+    connecting: Vec<ConnectingServer>,
+    balancer: Arc<Balancer>,
+    active_proxy: Option<ClientProxy>,
     liveness_interval: std::time::Duration,
     device: Arc<Device>,
+    discovery_backends: Arc<Vec<DiscoveryBackend>>,
+    static_servers: Arc<Vec<String>>,
+    static_servers_interval: std::time::Duration,
+    proxy_options: ClientProxyOptions,
+    preferences: ServerPreferences,
+    known_servers: Arc<KnownServers>,
+}
+
+/// How the manager finds, picks and checks servers.
+#[derive(Clone, Debug)]
+pub struct ManagerOptions {
+    /// Servers given with `--server`, retried periodically.
+    pub static_servers: Vec<String>,
+    pub balancing_strategy: BalancingStrategy,
+    pub preferences: ServerPreferences,
+    pub liveness_interval: Duration,
+    pub static_servers_interval: Duration,
 }
 
 pub enum ManagerCommand {
-    Add(SocketAddr),
+    /// Adds a server, along with the Device ID it has announced, if any.
+    Add(SocketAddr, Option<String>),
     Remove(SocketAddr),
+    AllowlistChanged,
 }
 
 impl Manager {
-    pub fn new(device: Arc<Device>) -> Self {
+    pub fn new(
+        device: Arc<Device>,
+        discovery_backends: Vec<DiscoveryBackend>,
+        options: ManagerOptions,
+        proxy_options: ClientProxyOptions,
+        known_servers: Arc<KnownServers>,
+    ) -> Self {
         Self {
-            servers: VecDeque::new(),
+            servers: Vec::new(),
+            connecting: Vec::new(),
+            balancer: Arc::new(Balancer::new(
+                options.balancing_strategy,
+                options.preferences.switch_back_delay,
+            )),
             active_proxy: None,
-            liveness_interval: DEFAULT_LIVENESS_INTERVAL,
+            liveness_interval: options.liveness_interval,
             device,
+            discovery_backends: Arc::new(discovery_backends),
+            static_servers: Arc::new(options.static_servers),
+            static_servers_interval: options.static_servers_interval,
+            proxy_options,
+            preferences: options.preferences,
+            known_servers,
         }
     }
 
+    /// Returns the balancer spreading requests across the registered servers.
+    pub fn balancer(&self) -> Arc<Balancer> {
+        self.balancer.clone()
+    }
+
+    /// Returns the address the client proxy listens on while there are servers.
+    pub fn proxy_address(&self) -> String {
+        self.proxy_options.address.to_string()
+    }
+
     pub async fn run(&mut self) -> anyhow::Result<()> {
-        let client_discovery = ClientDiscovery::default();
+        let discovery_backends = self.discovery_backends.clone();
+        let static_servers = self.static_servers.clone();
+        let static_servers_interval = self.static_servers_interval;
 
         let (cmd_tx, cmd_rx) = mpsc::channel::<ManagerCommand>(32);
+        let allowlist_changes = self.device.subscribe();
+
+        // Stop as soon as any of the discovery backends fails
+        let discovery = future::select_all(
+            discovery_backends
+                .iter()
+                .map(|backend| backend.run(&cmd_tx).boxed_local()),
+        )
+        .map(|(val, _, _)| val);
 
         tokio::select! {
             val = self.handle_commands(cmd_rx, &cmd_tx) => val,
-            val = client_discovery.run(&cmd_tx) => val
+            val = discovery => val,
+            val = Self::add_static_servers_periodically(&static_servers, static_servers_interval, &cmd_tx) => val,
+            val = Self::watch_allowlist(allowlist_changes, &cmd_tx) => val,
+        }
+    }
+
+    /// Deregisters all servers and stops the proxy, e.g. when switching to server mode.
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        self.abort_connecting();
+
+        let servers: Vec<_> = self.servers.iter().map(|s| s.server).collect();
+
+        for server in servers {
+            self.handle_remove_server(server).await?;
+        }
+
+        if let Some(proxy) = self.active_proxy.take() {
+            proxy.stop(true).await;
+        }
+
+        Ok(())
+    }
+
+    /// Notifies the manager whenever the allowed devices change.
+    ///
+    /// # Arguments
+    /// * `changes` - A receiver of the allowed devices changes.
+    /// * `cmd_tx` - A sender for sending commands to the manager (`&Sender<ManagerCommand>`).
+    ///
+    async fn watch_allowlist(
+        mut changes: watch::Receiver<()>,
+        cmd_tx: &Sender<ManagerCommand>,
+    ) -> anyhow::Result<()> {
+        while changes.changed().await.is_ok() {
+            cmd_tx
+                .send(ManagerCommand::AllowlistChanged)
+                .await
+                .unwrap_or(());
         }
+
+        Ok(())
+    }
+
+    /// Periodically resolves the configured static servers and adds them to the manager.
+    ///
+    /// Static servers go through the same authorization and liveness checks as discovered ones.
+    /// Servers that are unreachable or fail to resolve are retried on the next tick.
+    ///
+    /// # Arguments
+    /// * `static_servers` - Server addresses in the form of `host[:port]`.
+    /// * `interval` - How often to retry the static servers.
+    /// * `cmd_tx` - A sender for sending commands to the manager (`&Sender<ManagerCommand>`).
+    ///
+    async fn add_static_servers_periodically(
+        static_servers: &[String],
+        interval: Duration,
+        cmd_tx: &Sender<ManagerCommand>,
+    ) -> anyhow::Result<()> {
+        if static_servers.is_empty() {
+            return std::future::pending().await;
+        }
+
+        let mut stream = IntervalStream::new(time::interval(interval));
+
+        while stream.next().await.is_some() {
+            for static_server in static_servers {
+                let address = with_default_port(static_server, OLLANA_SERVER_PROXY_DEFAULT_PORT);
+
+                match lookup_host(address).await {
+                    Ok(servers) => {
+                        for server in servers {
+                            debug!("Adding static server {} ({})", static_server, server);
+
+                            cmd_tx
+                                .send(ManagerCommand::Add(server, None))
+                                .await
+                                .unwrap_or(());
+                        }
+                    }
+                    Err(error) => {
+                        error!(
+                            "Couldn't resolve static server {}: {}",
+                            static_server, error
+                        )
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 
     async fn handle_commands(
@@ -59,48 +236,71 @@ impl Manager {
         mut cmd_rx: Receiver<ManagerCommand>,
         cmd_tx: &Sender<ManagerCommand>,
     ) -> anyhow::Result<()> {
+        // Servers are connected to in the background, so that slow servers don't hold up commands
+        let (connection_tx, mut connection_rx) = mpsc::channel::<Connection>(32);
+
+        // Results of connections started by an earlier run would never arrive
+        self.abort_connecting();
+
         loop {
-            if let Some(cmd) = cmd_rx.recv().await {
-                match cmd {
-                    ManagerCommand::Add(server) => self.handle_add_server(server, cmd_tx).await?,
-                    ManagerCommand::Remove(server) => {
-                        self.handle_remove_server(server, cmd_tx).await?
+            tokio::select! {
+                Some(cmd) = cmd_rx.recv() => match cmd {
+                    ManagerCommand::Add(server, device_id) => {
+                        self.handle_add_server(server, device_id, &connection_tx)?
                     }
+                    ManagerCommand::Remove(server) => self.handle_remove_server(server).await?,
+                    ManagerCommand::AllowlistChanged => self.handle_allowlist_changed().await?,
+                },
+                Some((server, connected)) = connection_rx.recv() => {
+                    self.handle_connected(server, connected, cmd_tx).await?
                 }
             }
         }
     }
 
-    async fn handle_remove_server(
-        &mut self,
-        server: SocketAddr,
-        cmd_tx: &Sender<ManagerCommand>,
-    ) -> anyhow::Result<()> {
-        // Remove the server from the queue
-        if self.servers.contains(&server) {
-            self.servers.retain(|s| *s != server);
+    fn abort_connecting(&mut self) {
+        for connecting in self.connecting.drain(..) {
+            connecting.handle.abort();
         }
+    }
 
-        // Stop an active proxy if it is running and its server is the server to be removed
-        if let Some(ActiveProxy {
-            proxy,
-            server: active_server,
-            liveness_handle,
-        }) = &self.active_proxy
-        {
-            if *active_server == server {
-                proxy.stop(true).await;
-                liveness_handle.abort();
+    /// Deregisters the servers whose devices are no longer allowed.
+    async fn handle_allowlist_changed(&mut self) -> anyhow::Result<()> {
+        let revoked: Vec<SocketAddr> = self
+            .servers
+            .iter()
+            .filter(|s| !self.device.is_allowed(s.device_id.clone()))
+            .map(|s| s.server)
+            .collect();
 
-                self.active_proxy = None;
-            }
+        for server in revoked {
+            info!("Ollana server {} is no longer allowed", server);
+
+            self.handle_remove_server(server).await?;
         }
 
-        // Run and register a new active proxy for the first server in the queue
-        if let Some(next) = self.servers.front() {
-            let ollama = Self::ollama_for_server(*next)?;
+        Ok(())
+    }
+
+    async fn handle_remove_server(&mut self, server: SocketAddr) -> anyhow::Result<()> {
+        // Stop routing requests to the server and stop checking it
+        self.balancer.remove(server);
+        metrics().update_servers(&self.balancer);
+
+        if let Some(position) = self.servers.iter().position(|s| s.server == server) {
+            let known_server = self.servers.remove(position);
+            known_server.liveness_handle.abort();
 
-            self.register_proxy(*next, ollama, cmd_tx).await?;
+            info!("Deregistered Ollana server {}", server);
+        }
+
+        // Stop the proxy if there are no servers left to forward requests to
+        if self.servers.is_empty() {
+            if let Some(proxy) = self.active_proxy.take() {
+                info!("Stopping the Ollana proxy, no servers left");
+
+                proxy.stop(true).await;
+            }
         }
 
         Ok(())
@@ -108,92 +308,289 @@ impl Manager {
 
     /// Handles adding a new server to the manager.
     ///
-    /// This method checks whether the provided `server` is already in the list of managed servers,
-    /// then proceeds to authenticate with the Ollama service at that address. If successful, it adds
-    /// the server to the end of the queue and registers a proxy if there isn't one currently active.
+    /// Unless the server is already registered or being connected to, this method starts
+    /// authorizing on the server and checking its Ollama in the background. The result is handled
+    /// by [`Self::handle_connected`].
+    ///
+    /// The same server may be found under several addresses (e.g. IPv4 and IPv6), only the first one
+    /// is used, based on the server's Device ID. The Device ID announced by discovery, or the one
+    /// the address is pinned with, lets the other addresses be skipped before connecting.
     ///
     /// # Arguments
     /// * `self` - A mutable reference to the manager instance.
     /// * `server` - The new server's socket address (`SocketAddr`).
-    /// * `cmd_tx` - A sender for sending commands to the manager (`&Sender<ManagerCommand>`).
-    ///
-    /// # Returns
-    /// This function returns an `anyhow::Result<()>`, indicating success or failure.
+    /// * `device_id` - The Device ID announced by the server, if any.
+    /// * `connection_tx` - A sender for the result of connecting to the server.
     ///
     /// # Errors
-    /// This method can return errors if any of the following occur:
-    /// - The provided server address is not authorized.
-    /// - There is an error in connecting to the Ollama service at the provided address.
-    /// - Other unexpected issues arise during execution.
+    /// Returns an error if the HTTP clients for the server can't be created.
     ///
-    async fn handle_add_server(
+    fn handle_add_server(
         &mut self,
         server: SocketAddr,
-        cmd_tx: &Sender<ManagerCommand>,
+        device_id: Option<String>,
+        connection_tx: &Sender<Connection>,
     ) -> anyhow::Result<()> {
+        let device_id = device_id.or_else(|| self.known_servers.fingerprint(server).ok().flatten());
+
+        // E.g. the server of this device given with `--server`
+        if device_id.as_ref() == Some(&self.device.id) {
+            debug!("Ollana server {} is this device", server);
+
+            return Ok(());
+        }
+
+        let is_known = |known_server: SocketAddr, known_device_id: Option<&String>| {
+            known_server == server || (device_id.is_some() && known_device_id == device_id.as_ref())
+        };
+
         // Don't do anything for the already added server
-        if !self.servers.contains(&server) {
-            let ollama = Self::ollama_for_server(server)?;
-            let ollana = Ollana::new(server)?;
-
-            if let Some(auth_response) = ollana.check_authorization(self.device.id.clone()).await? {
-                let server_device_id = auth_response.device_id;
-
-                // Check if the server's device_id is allowed on the client
-                if self.device.is_allowed(server_device_id.clone()) {
-                    // Check if the server is proxying requests and has a running Ollama instance
-                    match ollama.get_version().await {
-                        Ok(_) => {
-                            // Add new server to the end of queue
-                            self.servers.push_back(server);
-
-                            // Run and register a new active proxy if there is no running
-                            if self.active_proxy.is_none() {
-                                self.register_proxy(server, ollama, cmd_tx).await?;
-                            }
-                        }
-                        Err(error) => {
-                            error!("Ollana server {} returned an error: {}", server, error);
-                        }
-                    }
-                } else {
-                    debug!(
-                        "Ollana server is not allowed to be registered: {}",
-                        server_device_id
-                    );
-                }
-            }
+        if self
+            .servers
+            .iter()
+            .any(|s| is_known(s.server, Some(&s.device_id)))
+            || self
+                .connecting
+                .iter()
+                .any(|s| is_known(s.server, s.device_id.as_ref()))
+        {
+            return Ok(());
         }
 
+        let ollama = self.ollama_for_server(server)?;
+        let ollana = Ollana::new(server, self.tls_config(server)?)?;
+        let tls_config = self.tls_config(server)?;
+        let device = self.device.clone();
+        let known_servers = self.known_servers.clone();
+        let preferences = self.preferences.clone();
+        let connection_tx = connection_tx.clone();
+
+        let handle = tokio::spawn(async move {
+            let connected = Self::connect(
+                server,
+                ollana,
+                ollama,
+                tls_config,
+                &device,
+                &known_servers,
+                &preferences,
+            )
+            .await
+            .unwrap_or_else(|error| {
+                error!("Couldn't connect to Ollana server {}: {}", server, error);
+
+                None
+            });
+
+            connection_tx.send((server, connected)).await.unwrap_or(());
+        });
+
+        self.connecting.push(ConnectingServer {
+            server,
+            device_id,
+            handle: handle.abort_handle(),
+        });
+
         Ok(())
     }
 
-    async fn register_proxy(
-        &mut self,
+    /// Authorizes on a server and checks that it is allowed and its Ollama is running.
+    ///
+    /// When a server is pinned, other servers are skipped.
+    ///
+    /// # Returns
+    /// The server ready to be registered, `None` if it must not be used.
+    ///
+    /// # Errors
+    /// Returns an error if the HTTP client for the server can't be created.
+    ///
+    async fn connect(
         server: SocketAddr,
+        ollana: Ollana,
         ollama: Ollama,
+        tls_config: rustls::ClientConfig,
+        device: &Arc<Device>,
+        known_servers: &KnownServers,
+        preferences: &ServerPreferences,
+    ) -> anyhow::Result<Option<ConnectedServer>> {
+        let auth_response = match ollana.check_authorization().await {
+            Ok(Some(auth_response)) => auth_response,
+            Ok(None) => return Ok(None),
+            Err(error) => {
+                error!("Couldn't authorize on Ollana server {}: {}", server, error);
+
+                return Ok(None);
+            }
+        };
+        let server_device_id = auth_response.device_id;
+
+        // The server's certificate, pinned during the TLS handshake, proves its Device ID
+        let fingerprint = known_servers.fingerprint(server).unwrap_or_default();
+
+        if fingerprint.as_ref() != Some(&server_device_id) {
+            error!(
+                "Ollana server {} claims Device ID {} that doesn't match its certificate",
+                server, server_device_id
+            );
+
+            return Ok(None);
+        }
+
+        if server_device_id == device.id {
+            debug!("Ollana server {} is this device", server);
+
+            return Ok(None);
+        }
+
+        if !preferences.allows(server, &server_device_id) {
+            debug!(
+                "Ollana server {} is not the pinned server: {}",
+                server, server_device_id
+            );
+
+            return Ok(None);
+        }
+
+        // Check if the server's device_id is allowed on the client
+        if !device.is_allowed(server_device_id.clone()) {
+            debug!(
+                "Ollana server is not allowed to be registered: {}",
+                server_device_id
+            );
+
+            return Ok(None);
+        }
+
+        // Check if the server is proxying requests and has a running Ollama instance
+        if let Err(error) = ollama.get_version().await {
+            error!("Ollana server {} returned an error: {}", server, error);
+
+            return Ok(None);
+        }
+
+        if let Err(error) = device.touch(&server_device_id).await {
+            error!(
+                "Couldn't record when Ollana server {} was last seen: {}",
+                server, error
+            );
+        }
+
+        let priority = preferences.priority(server, &server_device_id);
+        let upstream = Arc::new(Upstream::new(
+            server,
+            server_device_id,
+            priority,
+            tls_config,
+        )?);
+        let models = Self::fetch_models(server, &ollama).await;
+
+        Ok(Some(ConnectedServer {
+            upstream,
+            ollama,
+            models,
+        }))
+    }
+
+    /// Registers a server that has been connected to, unless it has been registered under another
+    /// address in the meantime.
+    ///
+    /// The server is added to the load balancer, and a proxy is run if there isn't one currently
+    /// active.
+    ///
+    /// # Arguments
+    /// * `server` - The server's socket address.
+    /// * `connected` - The server ready to be registered, `None` if connecting has failed.
+    /// * `cmd_tx` - A sender for sending commands to the manager (`&Sender<ManagerCommand>`).
+    ///
+    async fn handle_connected(
+        &mut self,
+        server: SocketAddr,
+        connected: Option<ConnectedServer>,
         cmd_tx: &Sender<ManagerCommand>,
     ) -> anyhow::Result<()> {
-        let mut client_proxy = ClientProxy::new(server, self.device.clone())?;
+        self.connecting.retain(|s| s.server != server);
+
+        let Some(ConnectedServer {
+            upstream,
+            ollama,
+            models,
+        }) = connected
+        else {
+            return Ok(());
+        };
+        let server_device_id = upstream.device_id.clone();
+
+        if let Some(known_server) = self
+            .servers
+            .iter()
+            .find(|s| s.device_id == server_device_id)
+        {
+            debug!(
+                "Ollana server {} is already registered as {}",
+                server, known_server.server
+            );
+
+            return Ok(());
+        }
+
+        // The device may have been revoked while connecting
+        if !self.device.is_allowed(server_device_id.clone()) {
+            debug!(
+                "Ollana server is not allowed to be registered: {}",
+                server_device_id
+            );
+
+            return Ok(());
+        }
+
+        if let Some((installed, loaded)) = models {
+            self.balancer.update_models(server, installed, loaded);
+        }
+
+        let liveness_handle = self
+            .run_liveness_check(upstream.clone(), server_device_id.clone(), ollama, cmd_tx)
+            .await?
+            .abort_handle();
+
+        info!(
+            "Registered Ollana server {} {} (priority: {})",
+            self.device.display_name(&server_device_id),
+            server,
+            upstream.priority
+        );
+
+        self.servers.push(KnownServer {
+            server,
+            device_id: server_device_id,
+            liveness_handle,
+        });
+        self.balancer.add(upstream);
+        metrics().update_servers(&self.balancer);
+
+        // Run a proxy if there is no running
+        if self.active_proxy.is_none() {
+            self.register_proxy(cmd_tx).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn register_proxy(&mut self, cmd_tx: &Sender<ManagerCommand>) -> anyhow::Result<()> {
+        let mut client_proxy = ClientProxy::new(
+            self.balancer.clone(),
+            self.proxy_options.clone(),
+            cmd_tx.clone(),
+        );
         let (tx, rx) = tokio::sync::oneshot::channel();
 
-        info!("Spawning an Ollana proxy for address {}", server);
+        info!("Spawning an Ollana proxy");
 
         actix_web::rt::spawn(async move { client_proxy.run_server(tx).await });
 
         if let Ok(proxy) = rx.await {
-            let liveness_handle = self
-                .run_liveness_check(server, ollama, cmd_tx)
-                .await?
-                .abort_handle();
+            self.active_proxy = Some(proxy);
 
-            self.active_proxy = Some(ActiveProxy {
-                proxy,
-                server,
-                liveness_handle,
-            });
-
-            info!("Registered an Ollana proxy for address {}", server);
+            info!("Registered an Ollana proxy");
         }
 
         Ok(())
@@ -201,21 +598,45 @@ impl Manager {
 
     async fn run_liveness_check(
         &self,
-        server: SocketAddr,
+        upstream: Arc<Upstream>,
+        device_id: String,
         ollama: Ollama,
         cmd_tx: &Sender<ManagerCommand>,
     ) -> anyhow::Result<JoinHandle<()>> {
         let mut stream = IntervalStream::new(time::interval(self.liveness_interval));
         let cmd_tx = cmd_tx.clone();
+        let server = upstream.server;
+        let balancer = self.balancer.clone();
+        let device = self.device.clone();
 
         let handle = tokio::spawn(async move {
             while stream.next().await.is_some() {
                 debug!("Executing liveness check for address {}", server);
 
-                match ollama.get_version().await {
-                    Ok(_) => (),
+                let started_at = Instant::now();
+
+                let result = ollama.get_version().await;
+
+                metrics().observe_liveness_check(&device_id, result.is_ok());
+
+                match result {
+                    Ok(_) => {
+                        upstream.record_latency(started_at.elapsed());
+
+                        if let Err(error) = device.touch(&device_id).await {
+                            error!(
+                                "Couldn't record when Ollana server {} was last seen: {}",
+                                server, error
+                            );
+                        }
+
+                        Self::refresh_models(&balancer, server, &ollama).await;
+
+                        // Servers that have just come online become active after a while
+                        metrics().update_servers(&balancer);
+                    }
                     Err(_) => {
-                        info!("Deregistering an Ollana proxy for address {}", server);
+                        info!("Deregistering Ollana server {}", server);
 
                         cmd_tx
                             .send(ManagerCommand::Remove(server))
@@ -229,8 +650,47 @@ impl Manager {
         Ok(handle)
     }
 
-    fn ollama_for_server(server: SocketAddr) -> anyhow::Result<Ollama> {
-        Ollama::new(server, true).inspect_err(|error| {
+    /// Fetches the installed and loaded models of a server and updates the balancer's model index.
+    ///
+    /// The previously known models are kept if the server fails to list them.
+    async fn refresh_models(balancer: &Balancer, server: SocketAddr, ollama: &Ollama) {
+        if let Some((installed, loaded)) = Self::fetch_models(server, ollama).await {
+            balancer.update_models(server, installed, loaded);
+        }
+    }
+
+    /// Fetches the names of the installed and loaded models of a server.
+    async fn fetch_models(
+        server: SocketAddr,
+        ollama: &Ollama,
+    ) -> Option<(Vec<String>, Vec<String>)> {
+        match tokio::try_join!(ollama.get_tags(), ollama.get_ps()) {
+            Ok((installed, loaded)) => {
+                let names = |response: ModelsResponse| {
+                    response
+                        .models
+                        .into_iter()
+                        .map(|model| model.name)
+                        .collect::<Vec<_>>()
+                };
+
+                Some((names(installed), names(loaded)))
+            }
+            Err(error) => {
+                debug!("Couldn't list models of server {}: {}", server, error);
+
+                None
+            }
+        }
+    }
+
+    fn tls_config(&self, server: SocketAddr) -> anyhow::Result<rustls::ClientConfig> {
+        self.known_servers
+            .tls_config(server, self.device.identity())
+    }
+
+    fn ollama_for_server(&self, server: SocketAddr) -> anyhow::Result<Ollama> {
+        Ollama::new(server, true, Some(self.tls_config(server)?)).inspect_err(|error| {
             error!(
                 "Couldn't create an Ollama instance for address {}: {}",
                 server, error
@@ -238,3 +698,85 @@ impl Manager {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use crate::{certs::Certs, headers::HeaderRules, test_support::TempDir};
+
+    use super::*;
+
+    fn manager(dir: &TempDir) -> Manager {
+        let certs = Certs::with_dir(dir.to_path_buf());
+        let device = Arc::new(Device::with_dir(dir.to_path_buf(), &certs).unwrap());
+        let options = ManagerOptions {
+            static_servers: Vec::new(),
+            balancing_strategy: BalancingStrategy::RoundRobin,
+            preferences: ServerPreferences {
+                preferred: Vec::new(),
+                pinned: None,
+                switch_back_delay: Duration::ZERO,
+            },
+            liveness_interval: Duration::from_secs(10),
+            static_servers_interval: Duration::from_secs(10),
+        };
+        let proxy_options = ClientProxyOptions {
+            address: (Ipv4Addr::LOCALHOST, 0).into(),
+            workers: 1,
+            annotate_models: false,
+            header_rules: HeaderRules::default(),
+        };
+        let known_servers = Arc::new(KnownServers::with_dir(dir.to_path_buf()).unwrap());
+
+        Manager::new(device, Vec::new(), options, proxy_options, known_servers)
+    }
+
+    #[actix_web::test]
+    async fn connects_dual_stack_server_once() {
+        let dir = TempDir::new();
+        let mut manager = manager(&dir);
+        let (connection_tx, _connection_rx) = mpsc::channel(8);
+        let device_id = Some("1d33566afe18293f".to_string());
+
+        manager
+            .handle_add_server(
+                (Ipv4Addr::LOCALHOST, 11435).into(),
+                device_id.clone(),
+                &connection_tx,
+            )
+            .unwrap();
+        manager
+            .handle_add_server(
+                (Ipv6Addr::LOCALHOST, 11435).into(),
+                device_id,
+                &connection_tx,
+            )
+            .unwrap();
+
+        assert_eq!(manager.connecting.len(), 1);
+        assert_eq!(
+            manager.connecting[0].server,
+            (Ipv4Addr::LOCALHOST, 11435).into()
+        );
+    }
+
+    #[actix_web::test]
+    async fn connects_servers_without_device_id_by_address() {
+        let dir = TempDir::new();
+        let mut manager = manager(&dir);
+        let (connection_tx, _connection_rx) = mpsc::channel(8);
+
+        for server in [
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 11435)),
+            (Ipv6Addr::LOCALHOST, 11435).into(),
+            (Ipv4Addr::LOCALHOST, 11435).into(),
+        ] {
+            manager
+                .handle_add_server(server, None, &connection_tx)
+                .unwrap();
+        }
+
+        assert_eq!(manager.connecting.len(), 2);
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::StreamExt;
+use log::{debug, error, info};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use tokio::{sync::mpsc::Sender, time};
+use tokio_stream::wrappers::IntervalStream;
+
+use crate::{
+    config::DiscoveryConfig, constants, device::Device, manager::ManagerCommand, ollama::Ollama,
+};
+
+const TXT_PORT: &str = "port";
+const TXT_DEVICE_ID: &str = "id";
+const TXT_VERSION: &str = "version";
+
+// Length of the Device ID prefix used to build unique instance and host names
+const INSTANCE_ID_LEN: usize = 12;
+
+/// Advertises the server proxy as an `_ollana._tcp.local.` DNS-SD service.
+///
+/// TXT records carry the server proxy port, the server Device ID and the Ollana version. The
+/// service is withdrawn while the local Ollama is down.
+pub struct MdnsAdvertiser {
+    proxy_port: u16,
+    device_id: String,
+    local_ollama: Arc<Ollama>,
+    liveness_interval: Duration,
+}
+
+/// Browses `_ollana._tcp.local.` DNS-SD services and registers resolved servers in the manager.
+///
+/// Services are resolved only once, so the servers of the services that are still advertised
+/// are added again periodically, in case the manager has removed them in the meantime. The
+/// service of this device, advertised in hybrid mode, is skipped.
+pub struct MdnsBrowser {
+    device_id: String,
+    readd_interval: Duration,
+}
+
+impl MdnsAdvertiser {
+    /// Creates an advertiser for the server proxy.
+    ///
+    /// # Arguments
+    /// * `local_ollama` - The local Ollama, the service is only advertised while it is up.
+    /// * `device` - This device.
+    /// * `config` - The discovery settings.
+    /// * `proxy_port` - The port of the server proxy to advertise.
+    ///
+    pub fn new(
+        local_ollama: Arc<Ollama>,
+        device: Arc<Device>,
+        config: &DiscoveryConfig,
+        proxy_port: u16,
+    ) -> Self {
+        Self {
+            proxy_port,
+            device_id: device.id.clone(),
+            local_ollama,
+            liveness_interval: config.liveness_interval(),
+        }
+    }
+
+    /// Keeps the service advertised while the local Ollama is up, until the returned future is
+    /// dropped.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let mut guard = DaemonGuard {
+            daemon: ServiceDaemon::new()?,
+            fullname: None,
+        };
+        let service = self.service()?;
+        let fullname = service.get_fullname().to_string();
+        let mut stream = IntervalStream::new(time::interval(self.liveness_interval));
+
+        while stream.next().await.is_some() {
+            let is_alive = self.local_ollama.get_version().await.is_ok();
+
+            match (is_alive, &guard.fullname) {
+                (true, None) => {
+                    guard.daemon.register(service.clone())?;
+                    guard.fullname = Some(fullname.clone());
+
+                    info!(
+                        "Advertising mDNS service {} on port {}",
+                        fullname, self.proxy_port
+                    );
+                }
+                (false, Some(_)) => {
+                    info!("Detected local Ollama is not running, withdrawing mDNS service");
+
+                    guard.withdraw();
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn service(&self) -> anyhow::Result<ServiceInfo> {
+        let instance_id = &self.device_id[..INSTANCE_ID_LEN.min(self.device_id.len())];
+        let instance_name = format!("ollana-{}", instance_id);
+        let host_name = format!("{}.local.", instance_name);
+        let properties = HashMap::from([
+            (TXT_PORT.to_string(), self.proxy_port.to_string()),
+            (TXT_DEVICE_ID.to_string(), self.device_id.clone()),
+            (
+                TXT_VERSION.to_string(),
+                env!("CARGO_PKG_VERSION").to_string(),
+            ),
+        ]);
+
+        let service = ServiceInfo::new(
+            constants::OLLANA_MDNS_SERVICE_TYPE,
+            &instance_name,
+            &host_name,
+            (),
+            self.proxy_port,
+            properties,
+        )?
+        .enable_addr_auto();
+
+        Ok(service)
+    }
+}
+
+impl MdnsBrowser {
+    /// Creates a browser for the client.
+    ///
+    /// # Arguments
+    /// * `device` - This device.
+    /// * `config` - The discovery settings, resolved servers are added again as often as UDP
+    ///   discovery looks for servers.
+    ///
+    pub fn new(device: Arc<Device>, config: &DiscoveryConfig) -> Self {
+        Self {
+            device_id: device.id.clone(),
+            readd_interval: config.broadcast_interval(),
+        }
+    }
+
+    pub async fn run(&self, cmd_tx: &Sender<ManagerCommand>) -> anyhow::Result<()> {
+        let daemon = ServiceDaemon::new()?;
+        let receiver = daemon.browse(constants::OLLANA_MDNS_SERVICE_TYPE)?;
+        let _guard = DaemonGuard {
+            daemon,
+            fullname: None,
+        };
+        // Device IDs and servers of the services resolved so far, by service name
+        let mut resolved: HashMap<String, (Option<String>, Vec<SocketAddr>)> = HashMap::new();
+        let mut readd = time::interval(self.readd_interval);
+
+        info!(
+            "Running mDNS discovery for {}...",
+            constants::OLLANA_MDNS_SERVICE_TYPE
+        );
+
+        loop {
+            let servers = tokio::select! {
+                event = receiver.recv_async() => match event {
+                    Ok(ServiceEvent::ServiceResolved(service)) => {
+                        debug!(
+                            "mDNS discovery resolved {}: device_id = {:?}, version = {:?}",
+                            service.get_fullname(),
+                            service.get_property_val_str(TXT_DEVICE_ID),
+                            service.get_property_val_str(TXT_VERSION)
+                        );
+
+                        let device_id = service
+                            .get_property_val_str(TXT_DEVICE_ID)
+                            .map(String::from);
+
+                        if device_id.as_ref() == Some(&self.device_id) {
+                            continue;
+                        }
+
+                        let servers = (device_id, service_servers(&service));
+                        resolved.insert(service.get_fullname().to_string(), servers.clone());
+
+                        vec![servers]
+                    }
+                    Ok(ServiceEvent::ServiceRemoved(_, fullname)) => {
+                        debug!("mDNS discovery lost {}", fullname);
+
+                        resolved.remove(&fullname);
+                        continue;
+                    }
+                    Ok(_) => continue,
+                    Err(_) => break,
+                },
+                _ = readd.tick() => resolved.values().cloned().collect(),
+            };
+
+            for (device_id, servers) in servers {
+                for server in servers {
+                    cmd_tx
+                        .send(ManagerCommand::Add(server, device_id.clone()))
+                        .await
+                        .unwrap_or(());
+                }
+            }
+        }
+
+        error!("mDNS discovery has stopped unexpectedly");
+
+        Ok(())
+    }
+}
+
+/// Returns the addresses of the server proxy advertised by a resolved service.
+fn service_servers(service: &ServiceInfo) -> Vec<SocketAddr> {
+    let port = service
+        .get_property_val_str(TXT_PORT)
+        .and_then(|port| port.parse::<u16>().ok())
+        .unwrap_or_else(|| service.get_port());
+
+    service
+        .get_addresses()
+        .iter()
+        // Resolved addresses don't carry a zone ID, link-local IPv6 addresses are unusable
+        .filter(|ip| !matches!(ip, IpAddr::V6(ipv6) if ipv6.is_unicast_link_local()))
+        .map(|ip| SocketAddr::new(*ip, port))
+        .collect()
+}
+
+/// Withdraws the advertised service, if any, and stops the mDNS daemon when dropped.
+struct DaemonGuard {
+    daemon: ServiceDaemon,
+    fullname: Option<String>,
+}
+
+impl DaemonGuard {
+    /// Withdraws the advertised service, if any.
+    fn withdraw(&mut self) {
+        if let Some(fullname) = self.fullname.take() {
+            debug!("Withdrawing mDNS service {}", fullname);
+
+            if let Err(error) = self.daemon.unregister(&fullname) {
+                error!("Couldn't withdraw mDNS service {}: {}", fullname, error);
+            }
+        }
+    }
+}
+
+impl Drop for DaemonGuard {
+    fn drop(&mut self) {
+        self.withdraw();
+
+        if let Err(error) = self.daemon.shutdown() {
+            error!("Couldn't shut down mDNS daemon: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(addresses: &[&str], txt_port: Option<&str>) -> ServiceInfo {
+        let addresses: Vec<IpAddr> = addresses.iter().map(|a| a.parse().unwrap()).collect();
+        let properties: HashMap<String, String> = txt_port
+            .map(|port| (TXT_PORT.to_string(), port.to_string()))
+            .into_iter()
+            .collect();
+
+        ServiceInfo::new(
+            constants::OLLANA_MDNS_SERVICE_TYPE,
+            "ollana-test",
+            "ollana-test.local.",
+            &addresses[..],
+            11435,
+            properties,
+        )
+        .unwrap()
+    }
+
+    fn sorted(mut servers: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        servers.sort();
+        servers
+    }
+
+    #[test]
+    fn service_servers_use_the_txt_port() {
+        let servers = service_servers(&service(&["192.168.1.20", "fd00::1"], Some("12345")));
+
+        assert_eq!(
+            sorted(servers),
+            [
+                "192.168.1.20:12345".parse().unwrap(),
+                "[fd00::1]:12345".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn service_servers_fall_back_to_the_service_port() {
+        for txt_port in [None, Some("not a port")] {
+            let servers = service_servers(&service(&["192.168.1.20"], txt_port));
+
+            assert_eq!(servers, ["192.168.1.20:11435".parse().unwrap()]);
+        }
+    }
+
+    #[test]
+    fn service_servers_skip_link_local_ipv6_addresses() {
+        let servers = service_servers(&service(&["fe80::1", "192.168.1.20"], None));
+
+        assert_eq!(servers, ["192.168.1.20:11435".parse().unwrap()]);
+    }
+}
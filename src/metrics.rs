@@ -0,0 +1,405 @@
+use std::{net::SocketAddr, sync::LazyLock, time::Duration};
+
+use actix_web::{http::Method, web, App, HttpResponse, HttpServer};
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::{Registry, Unit},
+};
+
+use crate::{balancer::Balancer, proxy::PROXY_DEFAULT_WORKERS_NUMBER};
+
+// Routes reported as they are, others are reported as `other` to keep the number of series small
+const KNOWN_ROUTES: [&str; 20] = [
+    "/",
+    "/api/generate",
+    "/api/chat",
+    "/api/embed",
+    "/api/embeddings",
+    "/api/tags",
+    "/api/ps",
+    "/api/show",
+    "/api/pull",
+    "/api/push",
+    "/api/delete",
+    "/api/create",
+    "/api/copy",
+    "/api/version",
+    "/v1/chat/completions",
+    "/v1/completions",
+    "/v1/embeddings",
+    "/v1/models",
+    "/ollana/api/authorize",
+    "/ollana/api/queue",
+];
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Which side of Ollana handled a request.
+#[derive(Clone, Copy, Debug)]
+pub enum ProxyKind {
+    Client,
+    Server,
+}
+
+/// Which side of Ollana sent or received a discovery packet.
+#[derive(Clone, Copy, Debug)]
+pub enum DiscoveryRole {
+    Client,
+    Server,
+}
+
+/// Prometheus metrics of this process, served by [`serve`].
+pub struct Metrics {
+    registry: Registry,
+    requests: Family<RequestLabels, Counter>,
+    request_duration: Family<RequestLabels, Histogram, fn() -> Histogram>,
+    response_bytes: Family<DeviceLabels, Counter>,
+    in_flight_requests: Family<ProxyLabels, Gauge>,
+    discovery_packets: Family<DiscoveryLabels, Counter>,
+    liveness_checks: Family<LivenessLabels, Counter>,
+    known_servers: Gauge,
+    active_servers: Family<ServerLabels, Gauge>,
+    scheduler_running: Gauge,
+    scheduler_queued: Gauge,
+    scheduler_wait: Histogram,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    proxy: &'static str,
+    route: &'static str,
+    method: &'static str,
+    status: u16,
+    device: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DeviceLabels {
+    proxy: &'static str,
+    device: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ProxyLabels {
+    proxy: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DiscoveryLabels {
+    role: &'static str,
+    direction: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LivenessLabels {
+    device: String,
+    result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ServerLabels {
+    server: String,
+    device: String,
+}
+
+/// Keeps a request counted as in flight until dropped.
+pub struct InFlightRequest {
+    gauge: Gauge,
+}
+
+/// Returns the metrics of this process.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Serves the metrics in the Prometheus / OpenMetrics text format at `/metrics`.
+///
+/// # Arguments
+/// * `address` - The address to listen on, e.g. a loopback address to keep the metrics private.
+///
+pub async fn serve(address: SocketAddr) -> anyhow::Result<()> {
+    log::info!("Serving metrics on http://{}/metrics", address);
+
+    HttpServer::new(|| App::new().route("/metrics", web::get().to(render)))
+        .bind(address)?
+        .workers(PROXY_DEFAULT_WORKERS_NUMBER)
+        .run()
+        .await
+        .map_err(anyhow::Error::new)
+}
+
+async fn render() -> HttpResponse {
+    let mut body = String::new();
+
+    match encode(&mut body, &metrics().registry) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(OPENMETRICS_CONTENT_TYPE)
+            .body(body),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut registry = Registry::with_prefix("ollana");
+        let requests = Family::<RequestLabels, Counter>::default();
+        let request_duration =
+            Family::<RequestLabels, Histogram, fn() -> Histogram>::new_with_constructor(|| {
+                // 5ms to about 3 minutes
+                Histogram::new(exponential_buckets(0.005, 2.0, 16))
+            });
+        let response_bytes = Family::<DeviceLabels, Counter>::default();
+        let in_flight_requests = Family::<ProxyLabels, Gauge>::default();
+        let discovery_packets = Family::<DiscoveryLabels, Counter>::default();
+        let liveness_checks = Family::<LivenessLabels, Counter>::default();
+        let known_servers = Gauge::default();
+        let active_servers = Family::<ServerLabels, Gauge>::default();
+        let scheduler_running = Gauge::default();
+        let scheduler_queued = Gauge::default();
+        // 5ms to about 3 minutes, generations that didn't have to wait fall into the first bucket
+        let scheduler_wait = Histogram::new(exponential_buckets(0.005, 2.0, 16));
+
+        registry.register(
+            "requests",
+            "Requests handled by the proxies",
+            requests.clone(),
+        );
+        registry.register_with_unit(
+            "request_duration",
+            "Time until the response headers of a request were sent",
+            Unit::Seconds,
+            request_duration.clone(),
+        );
+        registry.register_with_unit(
+            "response",
+            "Response body bytes streamed to clients",
+            Unit::Bytes,
+            response_bytes.clone(),
+        );
+        registry.register(
+            "in_flight_requests",
+            "Requests whose responses are still being streamed",
+            in_flight_requests.clone(),
+        );
+        registry.register(
+            "discovery_packets",
+            "UDP discovery packets sent and received",
+            discovery_packets.clone(),
+        );
+        registry.register(
+            "liveness_checks",
+            "Liveness checks of servers by their Device IDs and results",
+            liveness_checks.clone(),
+        );
+        registry.register(
+            "known_servers",
+            "Servers registered with the client",
+            known_servers.clone(),
+        );
+        registry.register(
+            "server_active",
+            "Whether the client currently sends requests to a server",
+            active_servers.clone(),
+        );
+        registry.register(
+            "scheduler_running",
+            "Generations the server currently runs",
+            scheduler_running.clone(),
+        );
+        registry.register(
+            "scheduler_queued",
+            "Generations waiting on the server for a free slot",
+            scheduler_queued.clone(),
+        );
+        registry.register_with_unit(
+            "scheduler_wait",
+            "Time generations waited on the server until they were run",
+            Unit::Seconds,
+            scheduler_wait.clone(),
+        );
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            response_bytes,
+            in_flight_requests,
+            discovery_packets,
+            liveness_checks,
+            known_servers,
+            active_servers,
+            scheduler_running,
+            scheduler_queued,
+            scheduler_wait,
+        }
+    }
+
+    /// Counts a request once its response headers have been sent.
+    ///
+    /// # Arguments
+    /// * `proxy` - The proxy that handled the request.
+    /// * `method` - The HTTP method of the request, non-standard ones are counted as `other`.
+    /// * `path` - The URI path of the request.
+    /// * `status` - The HTTP status of the response.
+    /// * `device` - The Device ID of the client on a server, of the server on a client, if known
+    ///   and allowed.
+    /// * `duration` - The time it took until the response headers were sent.
+    ///
+    pub fn observe_request(
+        &self,
+        proxy: ProxyKind,
+        method: &Method,
+        path: &str,
+        status: u16,
+        device: Option<&str>,
+        duration: Duration,
+    ) {
+        let labels = RequestLabels {
+            proxy: proxy.as_str(),
+            route: route(path),
+            method: method_label(method),
+            status,
+            device: device.unwrap_or_default().to_string(),
+        };
+
+        self.requests.get_or_create(&labels).inc();
+        self.request_duration
+            .get_or_create(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Returns the counter of response body bytes streamed for a device.
+    pub fn response_bytes(&self, proxy: ProxyKind, device: Option<&str>) -> Counter {
+        self.response_bytes
+            .get_or_create(&DeviceLabels {
+                proxy: proxy.as_str(),
+                device: device.unwrap_or_default().to_string(),
+            })
+            .clone()
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub fn track_in_flight(&self, proxy: ProxyKind) -> InFlightRequest {
+        let gauge = self
+            .in_flight_requests
+            .get_or_create(&ProxyLabels {
+                proxy: proxy.as_str(),
+            })
+            .clone();
+
+        gauge.inc();
+
+        InFlightRequest { gauge }
+    }
+
+    /// Counts a discovery packet.
+    ///
+    /// # Arguments
+    /// * `role` - Whether the client or the server discovery handled the packet.
+    /// * `is_sent` - Whether the packet was sent rather than received.
+    ///
+    pub fn observe_discovery_packet(&self, role: DiscoveryRole, is_sent: bool) {
+        self.discovery_packets
+            .get_or_create(&DiscoveryLabels {
+                role: match role {
+                    DiscoveryRole::Client => "client",
+                    DiscoveryRole::Server => "server",
+                },
+                direction: if is_sent { "sent" } else { "received" },
+            })
+            .inc();
+    }
+
+    /// Counts the result of a liveness check of a server, by the server's Device ID, which stays
+    /// the same when the server comes back under another address.
+    pub fn observe_liveness_check(&self, device_id: &str, is_alive: bool) {
+        self.liveness_checks
+            .get_or_create(&LivenessLabels {
+                device: device_id.to_string(),
+                result: if is_alive { "success" } else { "failure" },
+            })
+            .inc();
+    }
+
+    /// Updates the generations the server runs and the ones waiting for a free slot.
+    pub fn update_scheduler(&self, running: usize, queued: usize) {
+        self.scheduler_running.set(running as i64);
+        self.scheduler_queued.set(queued as i64);
+    }
+
+    /// Counts how long a generation waited until it was run.
+    pub fn observe_scheduler_wait(&self, wait: Duration) {
+        self.scheduler_wait.observe(wait.as_secs_f64());
+    }
+
+    /// Updates the known servers and which of them are active from the balancer.
+    pub fn update_servers(&self, balancer: &Balancer) {
+        let upstreams = balancer.upstreams();
+        let active = balancer.active();
+
+        self.known_servers.set(upstreams.len() as i64);
+        self.active_servers.clear();
+
+        for upstream in upstreams {
+            let is_active = active.iter().any(|a| a.server == upstream.server);
+
+            self.active_servers
+                .get_or_create(&ServerLabels {
+                    server: upstream.server.to_string(),
+                    device: upstream.device_id.clone(),
+                })
+                .set(i64::from(is_active));
+        }
+    }
+}
+
+impl ProxyKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ProxyKind::Client => "client",
+            ProxyKind::Server => "server",
+        }
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// Maps an HTTP method to a label with a bounded number of values.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::CONNECT => "CONNECT",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::PATCH => "PATCH",
+        _ => "other",
+    }
+}
+
+/// Maps a URI path to a route with a bounded number of values.
+fn route(path: &str) -> &'static str {
+    if let Some(route) = KNOWN_ROUTES.iter().find(|route| **route == path) {
+        route
+    } else if path.starts_with("/api/blobs/") {
+        "/api/blobs/{digest}"
+    } else if path.starts_with("/v1/models/") {
+        "/v1/models/{model}"
+    } else {
+        "other"
+    }
+}
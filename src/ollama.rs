@@ -1,9 +1,9 @@
 use std::net::SocketAddr;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::constants;
+use crate::{constants, http_client_builder, socket_addr_url};
 
 #[derive(Clone)]
 pub struct Ollama {
@@ -18,10 +18,8 @@ impl Default for Ollama {
             constants::OLLAMA_DEFAULT_ADDRESS,
             constants::OLLAMA_DEFAULT_PORT
         );
-        let url = Url::parse(&url).unwrap();
-        let client = reqwest::Client::default();
 
-        Self { client, url }
+        Self::with_url(Url::parse(&url).unwrap())
     }
 }
 
@@ -31,15 +29,61 @@ pub struct VersionResponse {
     version: String,
 }
 
+/// Response of the `/api/tags` (installed models) and `/api/ps` (loaded models) endpoints.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ModelsResponse {
+    pub models: Vec<Model>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Model {
+    pub name: String,
+    /// All the other fields returned by Ollama, kept as they are
+    #[serde(flatten)]
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Normalizes a model name the way Ollama does, i.e. `llama3` is the same as `llama3:latest`.
+pub fn normalize_model_name(name: &str) -> String {
+    let tag_start = name.rfind('/').map_or(0, |i| i + 1);
+
+    if name[tag_start..].contains(':') {
+        name.to_string()
+    } else {
+        format!("{}:latest", name)
+    }
+}
+
 impl Ollama {
-    pub fn new(socket_addr: SocketAddr, secure: bool) -> anyhow::Result<Self> {
+    /// Creates a client for the Ollama API at the given URL, e.g. of the local Ollama.
+    pub fn with_url(url: Url) -> Self {
+        Self {
+            client: reqwest::Client::default(),
+            url,
+        }
+    }
+
+    /// Creates a client for the Ollama API at the given address.
+    ///
+    /// # Arguments
+    /// * `socket_addr` - The address of Ollama or of an Ollana server proxy.
+    /// * `secure` - Whether to use HTTPS.
+    /// * `tls_config` - The TLS configuration for an Ollana server proxy, if any.
+    ///
+    pub fn new(
+        socket_addr: SocketAddr,
+        secure: bool,
+        tls_config: Option<rustls::ClientConfig>,
+    ) -> anyhow::Result<Self> {
         let url_schema = if secure { "https" } else { "http" };
-        let url = format!("{}://{}", url_schema, socket_addr);
-        let url = Url::parse(&url)?;
-        let client = reqwest::ClientBuilder::new()
-            .use_rustls_tls()
-            .danger_accept_invalid_certs(true)
-            .build()?;
+        let url = socket_addr_url(url_schema, socket_addr)?;
+        let mut builder = http_client_builder(socket_addr);
+
+        if let Some(tls_config) = tls_config {
+            builder = builder.use_preconfigured_tls(tls_config);
+        }
+
+        let client = builder.build()?;
 
         Ok(Ollama { client, url })
     }
@@ -56,4 +100,28 @@ impl Ollama {
             .await
             .map_err(anyhow::Error::new)
     }
+
+    /// Lists the models installed on the server.
+    pub async fn get_tags(&self) -> anyhow::Result<ModelsResponse> {
+        self.get_models("api/tags").await
+    }
+
+    /// Lists the models currently loaded into memory on the server.
+    pub async fn get_ps(&self) -> anyhow::Result<ModelsResponse> {
+        self.get_models("api/ps").await
+    }
+
+    async fn get_models(&self, path: &str) -> anyhow::Result<ModelsResponse> {
+        let mut uri = self.url.clone();
+        uri.set_path(path);
+
+        self.client
+            .get(uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<ModelsResponse>()
+            .await
+            .map_err(anyhow::Error::new)
+    }
 }
@@ -5,7 +5,7 @@ use log::debug;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::HTTP_HEADER_OLLANA_DEVICE_ID;
+use crate::{http_client_builder, socket_addr_url, HTTP_HEADER_OLLANA_HOSTNAME};
 
 pub struct Ollana {
     client: reqwest::Client,
@@ -24,12 +24,16 @@ impl AuthorizationResponse {
 }
 
 impl Ollana {
-    pub fn new(socket_addr: SocketAddr) -> anyhow::Result<Self> {
-        let url = format!("https://{}", socket_addr);
-        let url = Url::parse(&url).unwrap();
-        let client = reqwest::ClientBuilder::new()
-            .use_rustls_tls()
-            .danger_accept_invalid_certs(true)
+    /// Creates a client for the Ollana API of the server proxy at the given address.
+    ///
+    /// # Arguments
+    /// * `socket_addr` - The address of the server proxy.
+    /// * `tls_config` - The TLS configuration to connect to the server proxy with.
+    ///
+    pub fn new(socket_addr: SocketAddr, tls_config: rustls::ClientConfig) -> anyhow::Result<Self> {
+        let url = socket_addr_url("https", socket_addr)?;
+        let client = http_client_builder(socket_addr)
+            .use_preconfigured_tls(tls_config)
             .build()?;
 
         Ok(Self { client, url })
@@ -38,41 +42,35 @@ impl Ollana {
     /// Checks if a device is authorized to access Ollana API.
     ///
     /// This function sends an HTTP POST request to the `/ollana/api/authorize`
-    /// endpoint with the specified device ID. If the response status code is
-    /// `UNAUTHORIZED`, it logs the failure and returns `None`. Otherwise, it parses
-    /// the JSON response as an `AuthorizationResponse` and returns it wrapped in
-    /// `Some`.
-    ///
-    /// # Arguments
-    ///
-    /// * `device_id`: A `String` representing the unique identifier for a device.
+    /// endpoint, the server identifies the device by the certificate presented during
+    /// the TLS handshake. If the response status code is `UNAUTHORIZED`, it logs the
+    /// failure and returns `None`. Otherwise, it parses the JSON response as an
+    /// `AuthorizationResponse` and returns it wrapped in `Some`.
     ///
     /// # Returns
     ///
     /// * An `anyhow::Result<Option<AuthorizationResponse>>` indicating success or failure,
     ///   with an optional authorization response if the request is successful and authorized.
     ///
-    pub async fn check_authorization(
-        &self,
-        device_id: String,
-    ) -> anyhow::Result<Option<AuthorizationResponse>> {
+    pub async fn check_authorization(&self) -> anyhow::Result<Option<AuthorizationResponse>> {
         let mut uri = self.url.clone();
         uri.set_path("ollana/api/authorize");
 
-        match self
-            .client
-            .post(uri)
-            .header(HTTP_HEADER_OLLANA_DEVICE_ID, &device_id)
-            .send()
-            .await?
+        let mut request = self.client.post(uri.clone());
+
+        // Helps the server's admin to recognize this device if it is not allowed yet
+        if let Some(hostname) = nix::unistd::gethostname()
+            .ok()
+            .and_then(|hostname| hostname.into_string().ok())
         {
+            request = request.header(HTTP_HEADER_OLLANA_HOSTNAME, hostname);
+        }
+
+        match request.send().await? {
             response if response.status() == StatusCode::UNAUTHORIZED => {
                 let message = response.text().await?;
 
-                debug!(
-                    "Ollana authorization failed for device_id {}: {}",
-                    device_id, message
-                );
+                debug!("Ollana authorization failed on {}: {}", uri, message);
 
                 Ok(None)
             }
@@ -0,0 +1,417 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::{pki_types::ServerName, ClientConnection, ServerConnection, StreamOwned};
+
+use crate::{
+    certs::{self, DeviceCertVerifier},
+    device::{Device, DeviceUpdate},
+    with_default_port,
+};
+
+// Crockford's base32, without the letters that are easily confused with digits
+const CODE_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+// 50 bits, so that the code can't be guessed from an intercepted exchange while it is valid
+const CODE_LENGTH: usize = 10;
+const CODE_VALIDITY: Duration = Duration::from_secs(5 * 60);
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const PROTOCOL_LABEL: &[u8] = b"ollana-pair-v1";
+
+/// Waits for another device to pair with this one using a one-time code.
+///
+/// Both devices connect over mutual TLS with their device certificates, so each learns the other's
+/// Device ID from the handshake. They then prove to each other that they know the code with an
+/// HMAC over both Device IDs, the joining device first. The code is valid for a single attempt.
+pub struct Pairing {
+    device: Arc<Device>,
+    listener: TcpListener,
+    code: String,
+}
+
+/// Who is proving the knowledge of the code.
+#[derive(Clone, Copy)]
+enum Role {
+    Waiting,
+    Joining,
+}
+
+impl Pairing {
+    /// Starts listening for a pairing device on the given port and generates a new code.
+    pub fn listen(device: Arc<Device>, port: u16) -> anyhow::Result<Self> {
+        // A dual-stack socket accepts IPv4 connections too, unless the host disabled them
+        let listener = TcpListener::bind((Ipv6Addr::UNSPECIFIED, port))
+            .or_else(|_| TcpListener::bind(("0.0.0.0", port)))?;
+        let code = Self::gen_code()?;
+
+        Ok(Self {
+            device,
+            listener,
+            code,
+        })
+    }
+
+    /// Returns the code to enter on the other device, e.g. `K7QM2-XRD9F`.
+    pub fn code(&self) -> String {
+        format!(
+            "{}-{}",
+            &self.code[..CODE_LENGTH / 2],
+            &self.code[CODE_LENGTH / 2..]
+        )
+    }
+
+    /// Waits for the other device and allows it once it has proven the knowledge of the code.
+    ///
+    /// # Returns
+    /// The Device ID of the paired device.
+    ///
+    /// # Errors
+    /// Returns an error if the code expires or the other device sends a wrong code.
+    ///
+    pub fn accept(self) -> anyhow::Result<String> {
+        self.accept_until(Instant::now() + CODE_VALIDITY)
+    }
+
+    fn accept_until(self, deadline: Instant) -> anyhow::Result<String> {
+        self.listener.set_nonblocking(true)?;
+
+        loop {
+            let (stream, peer) = match self.listener.accept() {
+                Ok(connection) => connection,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        anyhow::bail!("The pairing code has expired");
+                    }
+
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                    continue;
+                }
+                Err(error) => return Err(error.into()),
+            };
+
+            debug!("Pairing connection from {}", peer);
+
+            stream.set_nonblocking(false)?;
+
+            let tls_config = rustls::ServerConfig::builder()
+                .with_client_cert_verifier(Arc::new(DeviceCertVerifier::default()))
+                .with_single_cert(
+                    vec![self.device.identity().cert],
+                    self.device.identity().key,
+                )?;
+            let mut tls = StreamOwned::new(ServerConnection::new(Arc::new(tls_config))?, stream);
+
+            // Connections that don't get as far as sending a code don't use up the attempt
+            let peer_id = match Self::handshake(&mut tls.conn, &mut tls.sock) {
+                Ok(()) => match tls.conn.peer_certificates().and_then(|certs| certs.first()) {
+                    Some(cert) => certs::device_id(cert)?,
+                    None => {
+                        warn!("Pairing device {} didn't present a certificate", peer);
+                        continue;
+                    }
+                },
+                Err(error) => {
+                    warn!("Pairing with {} has failed: {}", peer, error);
+                    continue;
+                }
+            };
+
+            let mut tag = [0u8; 32];
+
+            if let Err(error) = tls.read_exact(&mut tag) {
+                warn!("Pairing device {} didn't send a code: {}", peer, error);
+                continue;
+            }
+
+            let key = Self::key(&self.code);
+            let message = Self::message(Role::Joining, &self.device.id, &peer_id);
+
+            hmac::verify(&key, &message, &tag)
+                .map_err(|_| anyhow::anyhow!("Device {} has sent a wrong pairing code", peer))?;
+
+            let message = Self::message(Role::Waiting, &self.device.id, &peer_id);
+
+            tls.write_all(hmac::sign(&key, &message).as_ref())?;
+            tls.conn.send_close_notify();
+            tls.flush()?;
+
+            self.device
+                .allow(peer_id.clone(), DeviceUpdate::default())?;
+
+            return Ok(peer_id);
+        }
+    }
+
+    /// Pairs with a device waiting at the given address and allows it.
+    ///
+    /// # Arguments
+    /// * `device` - This device.
+    /// * `address` - The `HOST[:PORT]` address of the waiting device.
+    /// * `default_port` - The port to use if the address has none.
+    /// * `code` - The code shown by the waiting device.
+    ///
+    /// # Returns
+    /// The Device ID of the paired device.
+    ///
+    /// # Errors
+    /// Returns an error if the waiting device can't be reached or rejects the code.
+    ///
+    pub fn join(
+        device: Arc<Device>,
+        address: &str,
+        default_port: u16,
+        code: &str,
+    ) -> anyhow::Result<String> {
+        let code = Self::normalize_code(code);
+
+        if code.len() != CODE_LENGTH {
+            anyhow::bail!("The pairing code must have {} characters", CODE_LENGTH);
+        }
+
+        let server: SocketAddr = with_default_port(address, default_port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Couldn't resolve {}", address))?;
+        let stream = TcpStream::connect_timeout(&server, IO_TIMEOUT)?;
+
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let tls_config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(DeviceCertVerifier::default()))
+            .with_client_auth_cert(vec![device.identity().cert], device.identity().key)?;
+        let server_name = ServerName::IpAddress(server.ip().into());
+        let mut tls = StreamOwned::new(
+            ClientConnection::new(Arc::new(tls_config), server_name)?,
+            stream,
+        );
+
+        Self::handshake(&mut tls.conn, &mut tls.sock)?;
+
+        let peer_id = tls
+            .conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(certs::device_id)
+            .ok_or_else(|| anyhow::anyhow!("The other device didn't present a certificate"))??;
+
+        let key = Self::key(&code);
+        let message = Self::message(Role::Joining, &peer_id, &device.id);
+
+        tls.write_all(hmac::sign(&key, &message).as_ref())?;
+        tls.flush()?;
+
+        let mut tag = [0u8; 32];
+
+        tls.read_exact(&mut tag).map_err(|_| {
+            anyhow::anyhow!("The other device has rejected the pairing code, or it has expired")
+        })?;
+
+        let message = Self::message(Role::Waiting, &peer_id, &device.id);
+
+        hmac::verify(&key, &message, &tag)
+            .map_err(|_| anyhow::anyhow!("The other device doesn't know the pairing code"))?;
+
+        device.allow(peer_id.clone(), DeviceUpdate::default())?;
+
+        Ok(peer_id)
+    }
+
+    fn handshake<C, S>(conn: &mut C, sock: &mut TcpStream) -> anyhow::Result<()>
+    where
+        C: std::ops::DerefMut<Target = rustls::ConnectionCommon<S>>,
+        S: rustls::SideData,
+    {
+        sock.set_read_timeout(Some(IO_TIMEOUT))?;
+        sock.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        while conn.is_handshaking() {
+            conn.complete_io(sock)?;
+        }
+
+        Ok(())
+    }
+
+    fn gen_code() -> anyhow::Result<String> {
+        let mut bytes = [0u8; CODE_LENGTH];
+
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| anyhow::anyhow!("Couldn't generate a pairing code"))?;
+
+        // 256 is a multiple of 32, so every character is equally likely
+        Ok(bytes
+            .iter()
+            .map(|byte| CODE_ALPHABET[*byte as usize % CODE_ALPHABET.len()] as char)
+            .collect())
+    }
+
+    /// Accepts codes typed in lower case, with separators or with the letters Crockford's base32
+    /// leaves out.
+    fn normalize_code(code: &str) -> String {
+        code.chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| match c.to_ascii_uppercase() {
+                'O' => '0',
+                'I' | 'L' => '1',
+                c => c,
+            })
+            .collect()
+    }
+
+    fn key(code: &str) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, code.as_bytes())
+    }
+
+    fn message(role: Role, waiting_id: &str, joining_id: &str) -> Vec<u8> {
+        let role: &[u8] = match role {
+            Role::Waiting => b"waiting",
+            Role::Joining => b"joining",
+        };
+
+        [
+            PROTOCOL_LABEL,
+            b"|",
+            role,
+            b"|",
+            waiting_id.as_bytes(),
+            b"|",
+            joining_id.as_bytes(),
+        ]
+        .concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{certs::Certs, test_support::TempDir};
+
+    use super::*;
+
+    fn device(dir: &TempDir) -> Arc<Device> {
+        let certs = Certs::with_dir(dir.to_path_buf());
+
+        Arc::new(Device::with_dir(dir.to_path_buf(), &certs).unwrap())
+    }
+
+    fn listen(device: &Arc<Device>) -> (Pairing, String) {
+        let pairing = Pairing::listen(device.clone(), 0).unwrap();
+        let address = format!(
+            "127.0.0.1:{}",
+            pairing.listener.local_addr().unwrap().port()
+        );
+
+        (pairing, address)
+    }
+
+    #[test]
+    fn codes_are_grouped_crockford_base32() {
+        let dir = TempDir::new();
+        let (pairing, _) = listen(&device(&dir));
+        let code = pairing.code();
+
+        assert_eq!(code.len(), CODE_LENGTH + 1);
+        assert_eq!(code.find('-'), Some(CODE_LENGTH / 2));
+        assert!(pairing.code.bytes().all(|c| CODE_ALPHABET.contains(&c)));
+        assert_eq!(Pairing::normalize_code(&code), pairing.code);
+        assert_ne!(Pairing::gen_code().unwrap(), pairing.code);
+    }
+
+    #[test]
+    fn codes_are_normalized() {
+        assert_eq!(Pairing::normalize_code("k7qm2-xrd9f"), "K7QM2XRD9F");
+        assert_eq!(Pairing::normalize_code(" K7QM2 XRD9F "), "K7QM2XRD9F");
+        assert_eq!(Pairing::normalize_code("K7QM2_XRD9F\n"), "K7QM2XRD9F");
+        // Letters that look like digits
+        assert_eq!(Pairing::normalize_code("OoIiLl-01"), "00111101");
+    }
+
+    #[test]
+    fn join_rejects_codes_of_the_wrong_length() {
+        let dir = TempDir::new();
+
+        // Nobody listens on the port, the code is checked first
+        let error = Pairing::join(device(&dir), "127.0.0.1", 1, "K7QM2-XRD9").unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            format!("The pairing code must have {} characters", CODE_LENGTH)
+        );
+    }
+
+    #[test]
+    fn expired_codes_are_rejected() {
+        let dir = TempDir::new();
+        let (pairing, _) = listen(&device(&dir));
+
+        let error = pairing.accept_until(Instant::now()).unwrap_err();
+
+        assert_eq!(error.to_string(), "The pairing code has expired");
+    }
+
+    #[test]
+    fn hmac_depends_on_the_code_and_the_role() {
+        let message = Pairing::message(Role::Joining, "waiting", "joining");
+        let tag = hmac::sign(&Pairing::key("K7QM2XRD9F"), &message);
+
+        assert!(hmac::verify(&Pairing::key("K7QM2XRD9F"), &message, tag.as_ref()).is_ok());
+        assert!(hmac::verify(&Pairing::key("K7QM2XRD9G"), &message, tag.as_ref()).is_err());
+        assert!(hmac::verify(
+            &Pairing::key("K7QM2XRD9F"),
+            &Pairing::message(Role::Waiting, "waiting", "joining"),
+            tag.as_ref()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn devices_pair_over_loopback() {
+        let (dir_a, dir_b) = (TempDir::new(), TempDir::new());
+        let (waiting, joining) = (device(&dir_a), device(&dir_b));
+        let (pairing, address) = listen(&waiting);
+        let code = pairing.code().to_lowercase();
+
+        let accepted = thread::spawn(move || pairing.accept());
+        let joined = Pairing::join(joining.clone(), &address, 0, &code).unwrap();
+
+        assert_eq!(joined, waiting.id);
+        assert_eq!(accepted.join().unwrap().unwrap(), joining.id);
+        assert!(waiting.is_allowed(joining.id.clone()));
+        assert!(joining.is_allowed(waiting.id.clone()));
+    }
+
+    #[test]
+    fn wrong_codes_are_rejected() {
+        let (dir_a, dir_b) = (TempDir::new(), TempDir::new());
+        let (waiting, joining) = (device(&dir_a), device(&dir_b));
+        let (pairing, address) = listen(&waiting);
+        let code = match pairing.code.as_bytes()[0] {
+            b'0' => "1".to_string() + &pairing.code[1..],
+            _ => "0".to_string() + &pairing.code[1..],
+        };
+
+        let accepted = thread::spawn(move || pairing.accept());
+        let joined = Pairing::join(joining.clone(), &address, 0, &code);
+
+        assert!(joined.is_err());
+        assert!(accepted
+            .join()
+            .unwrap()
+            .unwrap_err()
+            .to_string()
+            .contains("has sent a wrong pairing code"));
+        assert!(!waiting.is_allowed(joining.id.clone()));
+        assert!(!joining.is_allowed(waiting.id.clone()));
+    }
+}
@@ -0,0 +1,243 @@
+use actix_web::http::Method;
+use serde::{Deserialize, Serialize};
+
+// Endpoints of the inference role, the admin role can use all of them
+const INFERENCE_READ_PATHS: [&str; 5] = ["/", "/api/version", "/api/tags", "/api/ps", "/v1/models"];
+const INFERENCE_WRITE_PATHS: [&str; 8] = [
+    "/api/generate",
+    "/api/chat",
+    "/api/embed",
+    "/api/embeddings",
+    "/api/show",
+    "/v1/chat/completions",
+    "/v1/completions",
+    "/v1/embeddings",
+];
+// Endpoints whose JSON request body names models, checked against the models a device may use
+const MODEL_PATHS: [&str; 13] = [
+    "/api/generate",
+    "/api/chat",
+    "/api/embed",
+    "/api/embeddings",
+    "/api/show",
+    "/api/pull",
+    "/api/push",
+    "/api/delete",
+    "/api/create",
+    "/api/copy",
+    "/v1/chat/completions",
+    "/v1/completions",
+    "/v1/embeddings",
+];
+// Request body fields naming models, older API versions call the model `name`
+const MODEL_FIELDS: [&str; 5] = ["model", "name", "from", "source", "destination"];
+// Matches any model in the models a device may use
+pub const ANY_MODEL: &str = "*";
+
+/// Which Ollama endpoints a device may use.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceRole {
+    /// Run and list models only
+    Inference,
+    /// Use all endpoints, including pulling, creating and deleting models
+    #[default]
+    Admin,
+}
+
+impl DeviceRole {
+    /// Checks whether the role allows a request.
+    ///
+    /// # Arguments
+    /// * `method` - The HTTP method of the request.
+    /// * `path` - The URI path of the request.
+    ///
+    pub fn allows(&self, method: &Method, path: &str) -> bool {
+        match self {
+            DeviceRole::Admin => true,
+            DeviceRole::Inference => {
+                let is_read = *method == Method::GET || *method == Method::HEAD;
+
+                (is_read
+                    && (INFERENCE_READ_PATHS.contains(&path) || path.starts_with("/v1/models/")))
+                    || (*method == Method::POST && INFERENCE_WRITE_PATHS.contains(&path))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceRole::Inference => write!(f, "inference"),
+            DeviceRole::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Checks whether requests to an endpoint name models in their bodies.
+pub fn is_model_request(path: &str) -> bool {
+    MODEL_PATHS.contains(&path)
+}
+
+/// Extracts the names of all models a JSON request body refers to.
+pub fn requested_models(body: &[u8]) -> Vec<String> {
+    let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) else {
+        return Vec::new();
+    };
+
+    MODEL_FIELDS
+        .iter()
+        .filter_map(|key| json.get(key).and_then(|v| v.as_str()))
+        .map(String::from)
+        .collect()
+}
+
+/// Checks whether a model is one of the given models.
+///
+/// A model without a tag stands for its `latest` tag, and a listed model without a tag matches
+/// all tags of the model, e.g. `llama3` matches `llama3:8b`.
+///
+/// # Arguments
+/// * `models` - The models a device may use, all models if empty or containing `*`.
+/// * `model` - The requested model.
+///
+pub fn allows_model(models: &[String], model: &str) -> bool {
+    let (name, tag) = split_model(model);
+
+    models.is_empty()
+        || models.iter().any(|allowed| match allowed.as_str() {
+            ANY_MODEL => true,
+            allowed if allowed.contains(':') => split_model(allowed) == (name, tag),
+            allowed => allowed == name,
+        })
+}
+
+fn split_model(model: &str) -> (&str, &str) {
+    model.split_once(':').unwrap_or((model, "latest"))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::Uri;
+
+    use super::*;
+
+    fn models(models: &[&str]) -> Vec<String> {
+        models.iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn inference_role_allows_only_inference_endpoints() {
+        let role = DeviceRole::Inference;
+
+        for (method, path, is_allowed) in [
+            (Method::GET, "/", true),
+            (Method::HEAD, "/", true),
+            (Method::GET, "/api/version", true),
+            (Method::GET, "/api/tags", true),
+            (Method::GET, "/api/ps", true),
+            (Method::GET, "/v1/models", true),
+            (Method::GET, "/v1/models/llama3", true),
+            (Method::POST, "/api/generate", true),
+            (Method::POST, "/api/chat", true),
+            (Method::POST, "/api/embed", true),
+            (Method::POST, "/api/show", true),
+            (Method::POST, "/v1/chat/completions", true),
+            (Method::GET, "/api/chat", false),
+            (Method::POST, "/api/tags", false),
+            (Method::DELETE, "/api/generate", false),
+            (Method::POST, "/api/pull", false),
+            (Method::POST, "/api/push", false),
+            (Method::POST, "/api/create", false),
+            (Method::POST, "/api/copy", false),
+            (Method::DELETE, "/api/delete", false),
+            (Method::POST, "/api/blobs/sha256:abc", false),
+            // Only exact paths match, a trailing slash doesn't sneak in another endpoint
+            (Method::POST, "/api/chat/", false),
+            (Method::GET, "/api/tags/", false),
+            (Method::POST, "/api/pull/", false),
+        ] {
+            assert_eq!(
+                role.allows(&method, path),
+                is_allowed,
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn inference_role_checks_the_path_without_the_query_string() {
+        let role = DeviceRole::Inference;
+
+        for (method, uri, is_allowed) in [
+            (Method::POST, "/api/chat?stream=false", true),
+            (Method::GET, "/api/tags?", true),
+            (Method::POST, "/api/pull?path=/api/chat", false),
+        ] {
+            let uri: Uri = uri.parse().unwrap();
+
+            assert_eq!(role.allows(&method, uri.path()), is_allowed, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn admin_role_allows_everything() {
+        for (method, path) in [
+            (Method::POST, "/api/pull"),
+            (Method::DELETE, "/api/delete"),
+            (Method::PUT, "/anything"),
+        ] {
+            assert!(DeviceRole::Admin.allows(&method, path));
+        }
+    }
+
+    #[test]
+    fn requested_models_reads_every_model_field() {
+        for key in MODEL_FIELDS {
+            let body = format!(r#"{{"{}": "llama3:8b", "prompt": "hi"}}"#, key);
+
+            assert_eq!(
+                requested_models(body.as_bytes()),
+                models(&["llama3:8b"]),
+                "{}",
+                key
+            );
+        }
+
+        assert_eq!(
+            requested_models(br#"{"source": "llama3", "destination": "mine"}"#),
+            models(&["llama3", "mine"])
+        );
+        assert!(requested_models(br#"{"prompt": "hi"}"#).is_empty());
+        assert!(requested_models(br#"{"model": 42}"#).is_empty());
+        assert!(requested_models(b"not json").is_empty());
+    }
+
+    #[test]
+    fn allows_model_matches_tags() {
+        for (allowed, model, is_allowed) in [
+            (&[][..], "llama3", true),
+            (&["*"][..], "llama3:70b", true),
+            (&["llama3"][..], "llama3", true),
+            (&["llama3"][..], "llama3:8b", true),
+            (&["llama3:8b"][..], "llama3:8b", true),
+            (&["llama3:8b"][..], "llama3:70b", false),
+            (&["llama3:latest"][..], "llama3", true),
+            (&["llama3:8b"][..], "llama3", false),
+            (&["llama3"][..], "llama3.1", false),
+            (&["mistral", "llama3:8b"][..], "mistral:7b", true),
+            (&["mistral", "llama3:8b"][..], "qwen", false),
+        ] {
+            assert_eq!(
+                allows_model(&models(allowed), model),
+                is_allowed,
+                "{:?} {}",
+                allowed,
+                model
+            );
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Matches a server by its Device ID or address.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerRule {
+    DeviceId(String),
+    Ip(IpAddr),
+    SocketAddr(SocketAddr),
+}
+
+/// Which servers the client should use and in what order.
+#[derive(Clone, Debug)]
+pub struct ServerPreferences {
+    /// Servers to prefer, most preferred first. Other servers are used only if none of these is online.
+    pub preferred: Vec<ServerRule>,
+    /// The only server to use, if set.
+    pub pinned: Option<ServerRule>,
+    /// How long a preferred server has to be online before requests are switched back to it.
+    pub switch_back_delay: Duration,
+}
+
+impl ServerRule {
+    /// Checks whether the rule matches a server.
+    ///
+    /// # Arguments
+    /// * `server` - The address the server has been registered under.
+    /// * `device_id` - The server's Device ID.
+    ///
+    pub fn matches(&self, server: SocketAddr, device_id: &str) -> bool {
+        match self {
+            ServerRule::DeviceId(id) => id.eq_ignore_ascii_case(device_id),
+            ServerRule::Ip(ip) => *ip == server.ip(),
+            ServerRule::SocketAddr(socket_addr) => *socket_addr == server,
+        }
+    }
+}
+
+impl FromStr for ServerRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(socket_addr) = s.parse::<SocketAddr>() {
+            Ok(ServerRule::SocketAddr(socket_addr))
+        } else if let Ok(ip) = s.parse::<IpAddr>() {
+            Ok(ServerRule::Ip(ip))
+        } else if !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit()) {
+            Ok(ServerRule::DeviceId(s.to_string()))
+        } else {
+            Err(format!("'{}' is neither a Device ID nor an IP address", s))
+        }
+    }
+}
+
+impl ServerPreferences {
+    /// Checks whether a server may be used at all, i.e. whether it is pinned when pinning is used.
+    pub fn allows(&self, server: SocketAddr, device_id: &str) -> bool {
+        self.pinned
+            .as_ref()
+            .is_none_or(|rule| rule.matches(server, device_id))
+    }
+
+    /// Returns the priority of a server, lower values are preferred.
+    ///
+    /// Servers not matching any rule share the lowest priority.
+    pub fn priority(&self, server: SocketAddr, device_id: &str) -> usize {
+        self.preferred
+            .iter()
+            .position(|rule| rule.matches(server, device_id))
+            .unwrap_or(self.preferred.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE_ID: &str = "3f9a0c41d2b7e865";
+
+    fn server(ip: [u8; 4], port: u16) -> SocketAddr {
+        SocketAddr::from((ip, port))
+    }
+
+    fn preferences(preferred: &[&str], pinned: Option<&str>) -> ServerPreferences {
+        ServerPreferences {
+            preferred: preferred.iter().map(|rule| rule.parse().unwrap()).collect(),
+            pinned: pinned.map(|rule| rule.parse().unwrap()),
+            switch_back_delay: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(
+            "192.168.1.20:11435".parse(),
+            Ok(ServerRule::SocketAddr(server([192, 168, 1, 20], 11435)))
+        );
+        assert_eq!(
+            "[::1]:11435".parse(),
+            Ok(ServerRule::SocketAddr("[::1]:11435".parse().unwrap()))
+        );
+        assert_eq!(
+            "192.168.1.20".parse(),
+            Ok(ServerRule::Ip(IpAddr::from([192, 168, 1, 20])))
+        );
+        assert_eq!(
+            "fe80::1".parse(),
+            Ok(ServerRule::Ip("fe80::1".parse().unwrap()))
+        );
+        assert_eq!(
+            DEVICE_ID.parse(),
+            Ok(ServerRule::DeviceId(DEVICE_ID.to_string()))
+        );
+
+        for rule in ["", "laptop", "192.168.1.20:port", "3f9a-0c41"] {
+            assert!(rule.parse::<ServerRule>().is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn rules_match_servers() {
+        let addr = server([192, 168, 1, 20], 11435);
+        let matches = |rule: &str, server| {
+            rule.parse::<ServerRule>()
+                .unwrap()
+                .matches(server, DEVICE_ID)
+        };
+
+        assert!(matches(DEVICE_ID, server([10, 0, 0, 1], 1)));
+        assert!(matches(&DEVICE_ID.to_uppercase(), addr));
+        assert!(!matches("3f9a0c41d2b7e866", addr));
+        assert!(matches("192.168.1.20", addr));
+        assert!(matches("192.168.1.20", server([192, 168, 1, 20], 1)));
+        assert!(!matches("192.168.1.21", addr));
+        assert!(matches("192.168.1.20:11435", addr));
+        assert!(!matches("192.168.1.20:11436", addr));
+    }
+
+    #[test]
+    fn pinning_allows_only_the_pinned_server() {
+        let addr = server([192, 168, 1, 20], 11435);
+        let other = server([192, 168, 1, 21], 11435);
+
+        let unpinned = preferences(&[], None);
+        assert!(unpinned.allows(addr, DEVICE_ID));
+        assert!(unpinned.allows(other, "aaaa"));
+
+        let pinned = preferences(&[], Some(DEVICE_ID));
+        assert!(pinned.allows(addr, DEVICE_ID));
+        assert!(pinned.allows(other, DEVICE_ID));
+        assert!(!pinned.allows(addr, "aaaa"));
+
+        let pinned = preferences(&[], Some("192.168.1.20"));
+        assert!(pinned.allows(addr, "aaaa"));
+        assert!(!pinned.allows(other, DEVICE_ID));
+    }
+
+    #[test]
+    fn preferred_servers_come_first_in_order() {
+        let preferences = preferences(&["192.168.1.21", DEVICE_ID], None);
+        let first = server([192, 168, 1, 21], 11435);
+        let second = server([192, 168, 1, 20], 11435);
+
+        assert_eq!(preferences.priority(first, "aaaa"), 0);
+        assert_eq!(preferences.priority(second, DEVICE_ID), 1);
+        assert_eq!(preferences.priority(second, "aaaa"), 2);
+
+        // The first matching rule decides
+        assert_eq!(preferences.priority(first, DEVICE_ID), 0);
+
+        assert_eq!(self::preferences(&[], None).priority(first, DEVICE_ID), 0);
+    }
+}
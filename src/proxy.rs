@@ -1,74 +1,179 @@
 use actix_cors::Cors;
+use actix_tls::accept::rustls_0_23::TlsStream;
 use actix_web::{
-    dev::ServerHandle, error, http::header::ContentType, web, App, Error, HttpRequest,
-    HttpResponse, HttpServer,
+    dev::{Extensions, ServerHandle, Service as _},
+    error,
+    http::header::{self, ContentType},
+    rt::net::TcpStream,
+    web::{self, Bytes},
+    App, Error, HttpRequest, HttpResponse, HttpServer,
+};
+use futures_util::{future, Stream, StreamExt as _, TryFutureExt as _};
+use log::{debug, error, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    any::Any,
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
 };
-use futures_util::StreamExt as _;
-use log::{debug, error};
-use std::{fs::File, io::BufReader, net::SocketAddr, sync::Arc};
 use tokio::sync::{mpsc, oneshot::Sender};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use url::Url;
 
 use crate::{
-    certs::Certs, constants, device::Device, ollana::AuthorizationResponse,
-    HTTP_HEADER_OLLANA_DEVICE_ID,
+    balancer::Balancer,
+    certs::{self, Certs, DeviceCertVerifier},
+    config::ServerProxyConfig,
+    device::{AllowedDevice, Device},
+    headers::{self, HeaderRules},
+    manager::ManagerCommand,
+    metrics::{metrics, ProxyKind},
+    ollama::ModelsResponse,
+    ollana::AuthorizationResponse,
+    permission,
+    rate_limit::{LimitExceeded, RateLimiter},
+    scheduler::Scheduler,
+    usage::{UsageLog, UsageMeter},
+    HTTP_HEADER_OLLANA_HOSTNAME,
 };
 
 pub const PROXY_DEFAULT_WORKERS_NUMBER: usize = 2;
 
+// Endpoints whose JSON request body names the model to use
+const MODEL_REQUEST_PATHS: [&str; 5] = [
+    "/api/generate",
+    "/api/chat",
+    "/api/embed",
+    "/api/embeddings",
+    "/api/show",
+];
+// Endpoints listing models, filtered for devices that may only use some models
+const MODEL_LISTING_PATHS: [&str; 3] = ["/api/tags", "/api/ps", "/v1/models"];
+// Field added to merged model listings that lists the servers having a model
+const MODEL_SERVERS_ANNOTATION: &str = "ollana_servers";
+// Bodies of the model requests are buffered to find out the model, images can make them large
+const MODEL_REQUEST_BODY_LIMIT: usize = 64 * 1024 * 1024;
+// Other request bodies up to this size are buffered, so that failed requests can be retried
+const RETRY_BODY_LIMIT: u64 = 1024 * 1024;
+
 #[derive(Clone)]
 pub struct ClientProxy {
-    client: reqwest::Client,
-    host: String,
-    port: u16,
-    server_url: Url,
+    address: SocketAddr,
+    workers: usize,
+    balancer: Arc<Balancer>,
+    annotate_models: AnnotateModels,
+    header_rules: Arc<HeaderRules>,
     handle: Option<ServerHandle>,
-    device: Arc<Device>,
+    cmd_tx: mpsc::Sender<ManagerCommand>,
 }
 
+/// How the client proxy presents the servers to local applications.
+#[derive(Clone, Debug)]
+pub struct ClientProxyOptions {
+    /// The address to listen on, the one of Ollama unless in hybrid mode.
+    pub address: SocketAddr,
+    pub workers: usize,
+    /// Whether merged model listings should show which servers have each model.
+    pub annotate_models: bool,
+    /// Which headers of forwarded requests to remove or add.
+    pub header_rules: HeaderRules,
+}
+
+/// Device ID of the client of a server proxy connection, proven by its certificate.
+#[derive(Clone)]
+struct PeerDeviceId(String);
+
+/// Device ID of the server a client proxy request has been forwarded to, for the metrics.
+#[derive(Clone)]
+struct UpstreamDeviceId(String);
+
+/// Whether merged model listings should show which servers have each model.
+#[derive(Clone, Copy)]
+struct AnnotateModels(bool);
+
 pub struct ServerProxy {
     client: reqwest::Client,
-    host: String,
-    port: u16,
+    address: SocketAddr,
+    workers: usize,
     ollama_url: Url,
     device: Arc<Device>,
+    generations: Generations,
+    header_rules: Arc<HeaderRules>,
+    handle: Option<ServerHandle>,
+}
+
+/// What generation requests go through on the server proxy.
+#[derive(Clone)]
+struct Generations {
+    rate_limiter: Arc<RateLimiter>,
+    scheduler: Option<Arc<Scheduler>>,
+    usage_log: Arc<UsageLog>,
 }
 
 impl ClientProxy {
-    pub fn new(server_socket_addr: SocketAddr, device: Arc<Device>) -> anyhow::Result<Self> {
-        let server_url = format!("https://{server_socket_addr}");
-        let server_url = Url::parse(&server_url)?;
-        let client = reqwest::ClientBuilder::new()
-            .use_rustls_tls()
-            .danger_accept_invalid_certs(true)
-            .build()?;
-
-        Ok(ClientProxy {
-            client,
-            host: constants::OLLANA_CLIENT_PROXY_DEFAULT_ADDRESS.to_string(),
-            port: constants::OLLANA_CLIENT_PROXY_DEFAULT_PORT,
-            server_url,
+    pub fn new(
+        balancer: Arc<Balancer>,
+        options: ClientProxyOptions,
+        cmd_tx: mpsc::Sender<ManagerCommand>,
+    ) -> Self {
+        ClientProxy {
+            address: options.address,
+            workers: options.workers,
+            balancer,
+            annotate_models: AnnotateModels(options.annotate_models),
+            header_rules: Arc::new(options.header_rules),
             handle: None,
-            device,
-        })
+            cmd_tx,
+        }
     }
 
     pub async fn run_server(&mut self, tx: Sender<Self>) -> anyhow::Result<()> {
-        let client = self.client.clone();
-        let server_url = self.server_url.clone();
-        let device = self.device.clone();
+        let balancer = self.balancer.clone();
+        let annotate_models = self.annotate_models;
+        let header_rules = self.header_rules.clone();
+        let cmd_tx = self.cmd_tx.clone();
 
         let server = HttpServer::new(move || {
             App::new()
-                .app_data(web::Data::new(client.clone()))
-                .app_data(web::Data::new(server_url.clone()))
-                .app_data(web::Data::new(device.clone()))
+                .app_data(web::Data::new(balancer.clone()))
+                .app_data(web::Data::new(annotate_models))
+                .app_data(web::Data::new(header_rules.clone()))
+                .app_data(web::Data::new(cmd_tx.clone()))
                 .wrap(Cors::permissive())
+                .wrap_fn(|req, srv| {
+                    let started_at = Instant::now();
+                    let response = srv.call(req);
+
+                    async move {
+                        let response = response.await?;
+                        let device_id = response
+                            .response()
+                            .extensions()
+                            .get::<UpstreamDeviceId>()
+                            .map(|UpstreamDeviceId(id)| id.clone());
+
+                        metrics().observe_request(
+                            ProxyKind::Client,
+                            response.request().method(),
+                            response.request().path(),
+                            response.status().as_u16(),
+                            device_id.as_deref(),
+                            started_at.elapsed(),
+                        );
+
+                        Ok(response)
+                    }
+                })
+                .route("/api/tags", web::get().to(Self::list_models))
+                .route("/api/ps", web::get().to(Self::list_models))
                 .default_service(web::to(Self::forward))
         })
-        .bind((self.host.clone(), self.port))?
-        .workers(PROXY_DEFAULT_WORKERS_NUMBER)
+        .bind(self.address)?
+        .workers(self.workers)
         .run();
 
         let handle = server.handle();
@@ -81,44 +186,240 @@ impl ClientProxy {
         server.await.map_err(anyhow::Error::new)
     }
 
+    /// Forwards a request to one of the servers picked by the balancer.
+    ///
+    /// If a server can't be reached, it is reported to the manager for removal and a request with
+    /// a buffered body is retried on the next server. Streamed bodies can't be replayed, so such
+    /// requests fail right away.
     async fn forward(
         req: HttpRequest,
-        client: web::Data<reqwest::Client>,
-        server_url: web::Data<Url>,
-        device: web::Data<Arc<Device>>,
-        mut payload: web::Payload,
+        balancer: web::Data<Arc<Balancer>>,
+        header_rules: web::Data<Arc<HeaderRules>>,
+        cmd_tx: web::Data<mpsc::Sender<ManagerCommand>>,
+        payload: web::Payload,
         method: actix_web::http::Method,
     ) -> Result<HttpResponse, actix_web::Error> {
-        let (tx, rx) = mpsc::unbounded_channel();
+        let in_flight_request = metrics().track_in_flight(ProxyKind::Client);
+        let is_model_request = MODEL_REQUEST_PATHS.contains(&req.uri().path());
+        let (buffered_body, mut streaming_body, model) =
+            if is_model_request || Self::is_replayable(&req, &method) {
+                let bytes = payload
+                    .to_bytes_limited(MODEL_REQUEST_BODY_LIMIT)
+                    .await
+                    .map_err(error::ErrorPayloadTooLarge)??;
+                let model = if is_model_request {
+                    Self::requested_model(&bytes)
+                } else {
+                    None
+                };
+
+                (Some(bytes), None, model)
+            } else {
+                (None, Some(streaming_body(payload)), None)
+            };
+
+        let request_headers = header_rules.request_headers(&req, "http");
+        let mut failed_servers = Vec::new();
+
+        let (server_response, upstream, in_flight) = loop {
+            let upstream = balancer
+                .select(model.as_deref(), &failed_servers)
+                .ok_or_else(|| {
+                    if failed_servers.is_empty() {
+                        error::ErrorServiceUnavailable("No Ollana servers available")
+                    } else {
+                        error::ErrorBadGateway("No Ollana servers could be reached")
+                    }
+                })?;
+            let in_flight = upstream.track();
+
+            debug!(
+                "Forwarding {} {} (model: {:?}) to {} (in flight: {})",
+                method,
+                req.uri().path(),
+                model,
+                upstream.server,
+                upstream.in_flight()
+            );
+
+            let mut server_uri = upstream.url.clone();
+            server_uri.set_path(req.uri().path());
+            server_uri.set_query(req.uri().query());
+
+            let body = match (&buffered_body, streaming_body.take()) {
+                (Some(bytes), _) => reqwest::Body::from(bytes.clone()),
+                (None, Some(body)) => body,
+                (None, None) => unreachable!("a streamed body is sent at most once"),
+            };
+
+            let server_request = upstream
+                .client
+                .request(forwarded_method(&method)?, server_uri)
+                .headers(request_headers.clone())
+                .body(body);
+
+            let started_at = Instant::now();
 
-        actix_web::rt::spawn(async move {
-            while let Some(chunk) = payload.next().await {
-                tx.send(chunk).unwrap();
+            match server_request.send().await {
+                Ok(server_response) => {
+                    upstream.record_latency(started_at.elapsed());
+
+                    break (server_response, upstream, in_flight);
+                }
+                // E.g. the local application has aborted the upload, the server isn't to blame.
+                // Connect timeouts are connect errors as well.
+                Err(err) if !err.is_connect() => {
+                    warn!(
+                        "Forwarding to Ollana server {} has failed: {}",
+                        upstream.server, err
+                    );
+
+                    return Err(error::ErrorBadGateway(err));
+                }
+                Err(err) => {
+                    warn!("Ollana server {} has failed: {}", upstream.server, err);
+
+                    // Don't wait for the liveness check to notice the failure. Never block here: the
+                    // manager may be waiting for this request while gracefully stopping the proxy.
+                    cmd_tx
+                        .try_send(ManagerCommand::Remove(upstream.server))
+                        .unwrap_or(());
+
+                    if buffered_body.is_none() {
+                        return Err(error::ErrorBadGateway(err));
+                    }
+
+                    failed_servers.push(upstream.server);
+                }
+            }
+        };
+
+        let mut response = HttpResponse::build(forwarded_status(server_response.status())?);
+
+        headers::copy_response_headers(server_response.headers(), &mut response);
+        response
+            .extensions_mut()
+            .insert(UpstreamDeviceId(upstream.device_id.clone()));
+
+        // The request stays in flight until the whole response has been streamed
+        let response_bytes = metrics().response_bytes(ProxyKind::Client, Some(&upstream.device_id));
+        let body = server_response.bytes_stream().map(move |chunk| {
+            let _ = (&in_flight, &in_flight_request);
+
+            if let Ok(bytes) = &chunk {
+                response_bytes.inc_by(bytes.len() as u64);
             }
+
+            chunk
         });
 
-        let mut server_uri = (**server_url).clone();
-        server_uri.set_path(req.uri().path());
-        server_uri.set_query(req.uri().query());
+        Ok(response.streaming(body))
+    }
 
-        let server_request = client
-            .request(
-                reqwest::Method::from_bytes(method.as_str().as_bytes()).unwrap(),
-                server_uri,
-            )
-            .header(HTTP_HEADER_OLLANA_DEVICE_ID, &device.id)
-            .body(reqwest::Body::wrap_stream(UnboundedReceiverStream::new(rx)));
+    /// Checks whether the request body should be buffered, so that the request can be retried.
+    ///
+    /// Bodies of idempotent requests and bodies known to be small are buffered.
+    fn is_replayable(req: &HttpRequest, method: &actix_web::http::Method) -> bool {
+        use actix_web::http::Method;
 
-        let server_response = server_request
-            .send()
-            .await
-            .map_err(actix_web::error::ErrorInternalServerError)?;
+        let is_idempotent = [
+            Method::GET,
+            Method::HEAD,
+            Method::OPTIONS,
+            Method::PUT,
+            Method::DELETE,
+        ]
+        .contains(method);
+        let is_small = req
+            .headers()
+            .get(actix_web::http::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .is_some_and(|length| length <= RETRY_BODY_LIMIT);
 
-        let mut response = HttpResponse::build(
-            actix_web::http::StatusCode::from_u16(server_response.status().as_u16()).unwrap(),
-        );
+        is_idempotent || is_small
+    }
+
+    /// Answers `/api/tags` and `/api/ps` with the models of all servers merged together.
+    ///
+    /// Models present on several servers are listed once. Servers that fail to respond are skipped,
+    /// unless all of them fail.
+    async fn list_models(
+        req: HttpRequest,
+        balancer: web::Data<Arc<Balancer>>,
+        annotate_models: web::Data<AnnotateModels>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let upstreams = balancer.upstreams();
+
+        if upstreams.is_empty() {
+            return Err(error::ErrorServiceUnavailable(
+                "No Ollana servers available",
+            ));
+        }
+
+        let responses = future::join_all(upstreams.iter().map(|upstream| {
+            let mut uri = upstream.url.clone();
+            uri.set_path(req.uri().path());
 
-        Ok(response.streaming(server_response.bytes_stream()))
+            upstream.client.get(uri).send().and_then(|response| async {
+                response.error_for_status()?.json::<ModelsResponse>().await
+            })
+        }))
+        .await;
+
+        let mut merged = ModelsResponse::default();
+        let mut servers_by_model = HashMap::<String, Vec<String>>::new();
+        let mut failures = 0;
+
+        for (upstream, response) in upstreams.iter().zip(responses) {
+            match response {
+                Ok(response) => {
+                    for model in response.models {
+                        let servers = servers_by_model.entry(model.name.clone()).or_default();
+
+                        if servers.is_empty() {
+                            merged.models.push(model);
+                        }
+
+                        servers.push(upstream.server.to_string());
+                    }
+                }
+                Err(error) => {
+                    failures += 1;
+
+                    error!(
+                        "Couldn't list models of server {}: {}",
+                        upstream.server, error
+                    );
+                }
+            }
+        }
+
+        if failures == upstreams.len() {
+            return Err(error::ErrorBadGateway("Couldn't list models of any server"));
+        }
+
+        if annotate_models.0 {
+            for model in merged.models.iter_mut() {
+                let servers = servers_by_model.remove(&model.name).unwrap_or_default();
+
+                model
+                    .details
+                    .insert(MODEL_SERVERS_ANNOTATION.to_string(), servers.into());
+            }
+        }
+
+        Ok(HttpResponse::Ok().json(merged))
+    }
+
+    /// Extracts the model name from a JSON request body, older API versions call it `name`.
+    fn requested_model(body: &[u8]) -> Option<String> {
+        let json = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+
+        ["model", "name"]
+            .iter()
+            .find_map(|key| json.get(key).and_then(|v| v.as_str()))
+            .map(String::from)
     }
 
     pub async fn stop(&self, graceful: bool) {
@@ -129,46 +430,156 @@ impl ClientProxy {
 }
 
 impl ServerProxy {
-    pub fn new(device: Arc<Device>) -> Self {
-        let ollama_url = format!(
-            "http://{}:{}",
-            constants::OLLAMA_DEFAULT_ADDRESS,
-            constants::OLLAMA_DEFAULT_PORT
-        );
-        let ollama_url = Url::parse(&ollama_url).unwrap();
-
+    /// Creates a server proxy for the local Ollama.
+    ///
+    /// # Arguments
+    /// * `device` - This device.
+    /// * `config` - Where to listen and with how many workers.
+    /// * `ollama_url` - The URL of the local Ollama.
+    /// * `max_parallel_requests` - Generations to run on Ollama at once, others are queued. Zero
+    ///   disables queueing.
+    /// * `header_rules` - Which headers of forwarded requests to remove or add.
+    /// * `usage_log` - Where to record the usage of generations.
+    ///
+    pub fn new(
+        device: Arc<Device>,
+        config: &ServerProxyConfig,
+        ollama_url: Url,
+        max_parallel_requests: usize,
+        header_rules: HeaderRules,
+        usage_log: Arc<UsageLog>,
+    ) -> Self {
         Self {
             client: reqwest::Client::default(),
-            host: constants::OLLANA_SERVER_PROXY_DEFAULT_ADDRESS.to_string(),
-            port: constants::OLLANA_SERVER_PROXY_DEFAULT_PORT,
+            address: config.socket_addr(),
+            workers: config.workers,
             ollama_url,
             device,
+            generations: Generations {
+                rate_limiter: Arc::new(RateLimiter::default()),
+                scheduler: (max_parallel_requests > 0)
+                    .then(|| Arc::new(Scheduler::new(max_parallel_requests))),
+                usage_log,
+            },
+            header_rules: Arc::new(header_rules),
+            handle: None,
         }
     }
 
-    pub async fn run_server(&self, certs: &Certs) -> anyhow::Result<()> {
+    /// Returns the address the server proxy listens on.
+    pub fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// Returns the port the server proxy listens on.
+    pub fn port(&self) -> u16 {
+        self.address.port()
+    }
+
+    /// Returns the scheduler queueing generations, if queueing is enabled.
+    pub fn scheduler(&self) -> Option<Arc<Scheduler>> {
+        self.generations.scheduler.clone()
+    }
+
+    pub async fn run_server(&mut self, certs: &Certs) -> anyhow::Result<()> {
         let client = self.client.clone();
         let ollama_url = self.ollama_url.clone();
         let device = self.device.clone();
+        let generations = self.generations.clone();
+        let header_rules = self.header_rules.clone();
 
-        let (cert_file, key_file) = certs.get_http_server_files()?;
+        let (cert_file, key_file) = certs.get_device_files()?;
         let rustls_config = Self::rustls_config(cert_file, key_file)?;
 
-        HttpServer::new(move || {
+        let server = HttpServer::new(move || {
             App::new()
                 .app_data(web::Data::new(client.clone()))
                 .app_data(web::Data::new(ollama_url.clone()))
                 .app_data(web::Data::new(device.clone()))
+                .app_data(web::Data::new(generations.clone()))
+                .app_data(web::Data::new(header_rules.clone()))
+                .wrap_fn(|req, srv| {
+                    let started_at = Instant::now();
+                    // Anyone can make up Device IDs, only allowed ones get series of their own
+                    let device_id = req
+                        .conn_data::<PeerDeviceId>()
+                        .map(|PeerDeviceId(id)| id.clone())
+                        .filter(|id| {
+                            req.app_data::<web::Data<Arc<Device>>>()
+                                .is_some_and(|device| device.is_allowed(id.clone()))
+                        });
+                    let response = srv.call(req);
+
+                    async move {
+                        let response = response.await?;
+
+                        metrics().observe_request(
+                            ProxyKind::Server,
+                            response.request().method(),
+                            response.request().path(),
+                            response.status().as_u16(),
+                            device_id.as_deref(),
+                            started_at.elapsed(),
+                        );
+
+                        Ok(response)
+                    }
+                })
                 .service(
-                    web::scope("/ollana/api").route("/authorize", web::post().to(Self::authorize)),
+                    web::scope("/ollana/api")
+                        .route("/authorize", web::post().to(Self::authorize))
+                        .route("/queue", web::get().to(Self::queue)),
                 )
                 .default_service(web::to(Self::forward))
         })
-        .bind_rustls_0_23((self.host.clone(), self.port), rustls_config)?
-        .workers(PROXY_DEFAULT_WORKERS_NUMBER)
-        .run()
-        .await
-        .map_err(anyhow::Error::new)
+        .on_connect(Self::identify_peer)
+        .bind_rustls_0_23(self.address, rustls_config.clone())?;
+
+        // Serve IPv6 clients as well if the host supports it, unless bound to a particular address
+        let server = if self.address.ip() == Ipv4Addr::UNSPECIFIED {
+            match Self::ipv6_listener(self.address.port()) {
+                Ok(listener) => server.listen_rustls_0_23(listener, rustls_config)?,
+                Err(error) => {
+                    warn!("Server proxy couldn't listen on IPv6: {}", error);
+                    server
+                }
+            }
+        } else {
+            server
+        };
+
+        let server = server.workers(self.workers).run();
+
+        self.handle = Some(server.handle());
+
+        // Polled on a task of its own, so that it can still be stopped after this future is dropped
+        actix_web::rt::spawn(server)
+            .await
+            .map_err(anyhow::Error::new)?
+            .map_err(anyhow::Error::new)
+    }
+
+    /// Stops the server proxy, e.g. when switching to client mode.
+    ///
+    /// Dropping the future of [`ServerProxy::run_server`] doesn't stop the server, it keeps
+    /// listening until stopped.
+    ///
+    pub async fn stop(&self, graceful: bool) {
+        if let Some(handle) = &self.handle {
+            handle.stop(graceful).await
+        }
+    }
+
+    /// Creates an IPv6-only TCP listener, so it doesn't conflict with the IPv4 one on the same port.
+    fn ipv6_listener(port: u16) -> std::io::Result<std::net::TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+
+        socket.set_only_v6(true)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.listen(1024)?;
+
+        Ok(socket.into())
     }
 
     fn rustls_config(cert_file: File, key_file: File) -> anyhow::Result<rustls::ServerConfig> {
@@ -180,7 +591,7 @@ impl ServerProxy {
             rustls_pemfile::pkcs8_private_keys(key_reader).collect::<Result<Vec<_>, _>>()?;
 
         rustls::ServerConfig::builder()
-            .with_no_client_auth()
+            .with_client_cert_verifier(Arc::new(DeviceCertVerifier::default()))
             .with_single_cert(
                 tls_certs,
                 rustls::pki_types::PrivateKeyDer::Pkcs8(tls_keys.remove(0)),
@@ -188,19 +599,47 @@ impl ServerProxy {
             .map_err(anyhow::Error::from)
     }
 
-    fn is_authorized(req: HttpRequest, device: Arc<Device>) -> bool {
+    /// Derives the Device ID of the client from the certificate it presented in the TLS handshake.
+    fn identify_peer(connection: &dyn Any, data: &mut Extensions) {
+        let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+            return;
+        };
+        let (_, session) = stream.get_ref();
+
+        if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+            match certs::device_id(cert) {
+                Ok(device_id) => {
+                    data.insert(PeerDeviceId(device_id));
+                }
+                Err(error) => warn!("Couldn't identify the peer device: {}", error),
+            }
+        }
+    }
+
+    async fn is_authorized(req: HttpRequest, device: Arc<Device>) -> bool {
         let device_id = req
-            .headers()
-            .get(HTTP_HEADER_OLLANA_DEVICE_ID)
-            .and_then(|v| v.to_str().ok().map(String::from));
+            .conn_data::<PeerDeviceId>()
+            .map(|PeerDeviceId(id)| id.clone());
 
         debug!(
-            "Authorization decision: uri_path = {}, device_id = {:?}",
+            "Authorization decision: uri_path = {}, device = {:?}",
             req.uri().path(),
-            device_id
+            device_id.as_deref().map(|id| device.display_name(id))
         );
 
-        device_id.is_some_and(|id| device.is_allowed(id))
+        match device_id {
+            Some(id) if device.is_allowed(id.clone()) => {
+                if let Err(error) = device.touch(&id).await {
+                    error!(
+                        "Couldn't record when device {} was last seen: {}",
+                        id, error
+                    );
+                }
+
+                true
+            }
+            _ => false,
+        }
     }
 
     async fn authorize(
@@ -209,7 +648,7 @@ impl ServerProxy {
     ) -> Result<HttpResponse, actix_web::Error> {
         let device = (**device).clone();
 
-        if Self::is_authorized(req.clone(), device.clone()) {
+        if Self::is_authorized(req.clone(), device.clone()).await {
             let payload = AuthorizationResponse::new(device.id.clone());
             let body = serde_json::to_string(&payload)?;
 
@@ -217,9 +656,26 @@ impl ServerProxy {
                 .content_type(ContentType::json())
                 .body(body))
         } else {
-            Ok(HttpResponse::Unauthorized()
-                .content_type("text/plan")
-                .body("Device is not authorized"))
+            // Let the admin approve the device later, see `ollana device pending`. A client
+            // finding its own server, e.g. in hybrid mode, is no device to approve.
+            if let Some(PeerDeviceId(id)) = req
+                .conn_data::<PeerDeviceId>()
+                .filter(|PeerDeviceId(id)| *id != device.id)
+            {
+                let hostname = req
+                    .headers()
+                    .get(HTTP_HEADER_OLLANA_HOSTNAME)
+                    .and_then(|v| v.to_str().ok().map(String::from));
+
+                if let Err(error) = device
+                    .record_pending(id.clone(), req.peer_addr(), hostname)
+                    .await
+                {
+                    error!("Couldn't record pending device {}: {}", id, error);
+                }
+            }
+
+            Ok(Self::unauthorized())
         }
     }
 
@@ -228,45 +684,532 @@ impl ServerProxy {
         client: web::Data<reqwest::Client>,
         ollama_url: web::Data<Url>,
         device: web::Data<Arc<Device>>,
-        mut payload: web::Payload,
-        method: actix_web::http::Method,
+        generations: web::Data<Generations>,
+        header_rules: web::Data<Arc<HeaderRules>>,
+        payload: web::Payload,
     ) -> Result<HttpResponse, Error> {
-        let is_ignored_uri_path = req.uri().path() == "/api/version";
+        let in_flight_request = metrics().track_in_flight(ProxyKind::Server);
+        let method = req.method();
+        let path = req.uri().path();
+        let is_ignored_uri_path = path == "/api/version";
 
-        if is_ignored_uri_path || Self::is_authorized(req.clone(), (**device).clone()) {
-            let (tx, rx) = mpsc::unbounded_channel();
+        if !is_ignored_uri_path && !Self::is_authorized(req.clone(), (**device).clone()).await {
+            return Ok(Self::unauthorized());
+        }
+
+        let peer = req
+            .conn_data::<PeerDeviceId>()
+            .and_then(|PeerDeviceId(id)| device.allowed_device(id));
 
-            actix_web::rt::spawn(async move {
-                while let Some(chunk) = payload.next().await {
-                    tx.send(chunk).unwrap();
+        if let Some(peer) = &peer {
+            if !peer.role.allows(method, path) {
+                warn!(
+                    "Device {} with the {} role is not allowed to use {} {}",
+                    peer.display_name(),
+                    peer.role,
+                    method,
+                    path
+                );
+
+                return Ok(Self::forbidden(format!(
+                    "Device is not allowed to use {} {}",
+                    method, path
+                )));
+            }
+        }
+
+        // Restricted devices may only name the models they are allowed to use
+        let restricted_peer = peer.as_ref().filter(|peer| peer.is_model_restricted());
+        let body = match restricted_peer {
+            Some(peer) if permission::is_model_request(path) => {
+                let bytes = payload
+                    .to_bytes_limited(MODEL_REQUEST_BODY_LIMIT)
+                    .await
+                    .map_err(error::ErrorPayloadTooLarge)??;
+                let models = permission::requested_models(&bytes);
+
+                if models.is_empty() {
+                    return Ok(Self::forbidden(
+                        "Device may only use some models, but the request doesn't name any"
+                            .to_string(),
+                    ));
                 }
-            });
 
-            let mut ollama_uri = (**ollama_url).clone();
-            ollama_uri.set_path(req.uri().path());
-            ollama_uri.set_query(req.uri().query());
+                if let Some(model) = models.iter().find(|model| !peer.allows_model(model)) {
+                    warn!(
+                        "Device {} is not allowed to use model {}",
+                        peer.display_name(),
+                        model
+                    );
 
-            let ollama_request = client
-                .request(
-                    reqwest::Method::from_bytes(method.as_str().as_bytes()).unwrap(),
-                    ollama_uri,
-                )
-                .body(reqwest::Body::wrap_stream(UnboundedReceiverStream::new(rx)));
+                    return Ok(Self::forbidden(format!(
+                        "Device is not allowed to use model {}",
+                        model
+                    )));
+                }
 
-            let ollama_response = ollama_request
-                .send()
-                .await
-                .map_err(error::ErrorInternalServerError)?;
+                reqwest::Body::from(bytes)
+            }
+            _ => streaming_body(payload),
+        };
 
-            let mut response = HttpResponse::build(
-                actix_web::http::StatusCode::from_u16(ollama_response.status().as_u16()).unwrap(),
-            );
+        let generation = match &peer {
+            Some(peer) if RateLimiter::is_limited(path) => {
+                match generations.rate_limiter.acquire(&peer.id, &peer.limits) {
+                    Ok(generation) => Some(generation),
+                    Err(exceeded) => {
+                        warn!(
+                            "Device {} has exceeded its limit of {}",
+                            peer.display_name(),
+                            exceeded.reason
+                        );
 
-            Ok(response.streaming(ollama_response.bytes_stream()))
-        } else {
-            Ok(HttpResponse::Unauthorized()
-                .content_type("text/plan")
-                .body("Device is not authorized"))
+                        return Ok(Self::too_many_requests(exceeded));
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        // Generations wait for their turn while Ollama is busy
+        let slot = match (generations.scheduler.as_ref(), &peer) {
+            (Some(scheduler), Some(peer)) if RateLimiter::is_limited(path) => {
+                let queued_at = Instant::now();
+                let slot = scheduler
+                    .acquire(&peer.id, peer.priority)
+                    .await
+                    .map_err(|_| error::ErrorServiceUnavailable("Too many requests are queued"))?;
+
+                debug!(
+                    "Device {} waited {:?} for its turn",
+                    peer.display_name(),
+                    queued_at.elapsed()
+                );
+
+                // The device may have been revoked while waiting
+                if !device.is_allowed(peer.id.clone()) {
+                    return Ok(Self::unauthorized());
+                }
+
+                Some(slot)
+            }
+            _ => None,
+        };
+
+        let mut ollama_uri = (**ollama_url).clone();
+        ollama_uri.set_path(path);
+        ollama_uri.set_query(req.uri().query());
+
+        let request_headers = header_rules.request_headers(&req, "https");
+
+        let ollama_request = client
+            .request(forwarded_method(method)?, ollama_uri)
+            .headers(request_headers)
+            .body(body);
+
+        let ollama_response = ollama_request
+            .send()
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+
+        let status = forwarded_status(ollama_response.status())?;
+        let mut response = HttpResponse::build(status);
+
+        headers::copy_response_headers(ollama_response.headers(), &mut response);
+
+        // Model listings only show the models the device may use
+        if let Some(peer) = restricted_peer {
+            if status.is_success() && MODEL_LISTING_PATHS.contains(&path) {
+                let bytes = ollama_response
+                    .bytes()
+                    .await
+                    .map_err(error::ErrorBadGateway)?;
+
+                return Ok(response
+                    .content_type(ContentType::json())
+                    .body(Self::filter_models(&bytes, peer)?));
+            }
+        }
+
+        // The generation stays in flight and keeps its turn until the whole response has been streamed
+        let mut usage_meter = match &peer {
+            Some(peer) if RateLimiter::is_limited(path) => Some(UsageMeter::new(
+                peer.id.clone(),
+                generations.usage_log.clone(),
+                generation,
+            )),
+            _ => None,
+        };
+        let response_bytes = metrics().response_bytes(
+            ProxyKind::Server,
+            peer.as_ref().map(|peer| peer.id.as_str()),
+        );
+        let body = ollama_response.bytes_stream().map(move |chunk| {
+            let _ = (&slot, &in_flight_request);
+
+            if let Ok(bytes) = &chunk {
+                response_bytes.inc_by(bytes.len() as u64);
+            }
+
+            if let (Some(usage_meter), Ok(bytes)) = (&mut usage_meter, &chunk) {
+                usage_meter.feed(bytes);
+            }
+
+            chunk
+        });
+
+        // Responses that are already streaming are cut off once the device is revoked
+        match peer {
+            Some(peer) => Ok(response.streaming(revocable(body, (**device).clone(), peer.id))),
+            None => Ok(response.streaming(body)),
+        }
+    }
+
+    /// Shows the queue depth and wait times of generations to authorized devices.
+    async fn queue(
+        req: HttpRequest,
+        device: web::Data<Arc<Device>>,
+        generations: web::Data<Generations>,
+    ) -> Result<HttpResponse, Error> {
+        if !Self::is_authorized(req, (**device).clone()).await {
+            return Ok(Self::unauthorized());
+        }
+
+        match generations.scheduler.as_ref() {
+            Some(scheduler) => Ok(HttpResponse::Ok().json(scheduler.stats())),
+            None => Ok(HttpResponse::NotFound()
+                .content_type("text/plain")
+                .body("Queueing is disabled")),
+        }
+    }
+
+    /// Removes the models a device may not use from a model listing.
+    ///
+    /// # Arguments
+    /// * `body` - A JSON response of `/api/tags`, `/api/ps` or `/v1/models`.
+    /// * `peer` - The device the listing is for.
+    ///
+    fn filter_models(body: &[u8], peer: &AllowedDevice) -> Result<String, Error> {
+        let mut json =
+            serde_json::from_slice::<serde_json::Value>(body).map_err(error::ErrorBadGateway)?;
+
+        // Ollama lists models under `models`, the OpenAI compatible API under `data`
+        for (key, name_key) in [("models", "name"), ("data", "id")] {
+            if let Some(models) = json.get_mut(key).and_then(|v| v.as_array_mut()) {
+                models.retain(|model| {
+                    model
+                        .get(name_key)
+                        .and_then(|v| v.as_str())
+                        .is_some_and(|name| peer.allows_model(name))
+                });
+            }
+        }
+
+        Ok(json.to_string())
+    }
+
+    fn too_many_requests(exceeded: LimitExceeded) -> HttpResponse {
+        // Round up, so that retrying right after the given time succeeds
+        let retry_after =
+            exceeded.retry_after.as_secs() + u64::from(exceeded.retry_after.subsec_nanos() > 0);
+
+        HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after.max(1)))
+            .content_type("text/plain")
+            .body(format!(
+                "Device has exceeded its limit of {}",
+                exceeded.reason
+            ))
+    }
+
+    fn unauthorized() -> HttpResponse {
+        HttpResponse::Unauthorized()
+            .content_type("text/plain")
+            .body("Device is not authorized")
+    }
+
+    fn forbidden(message: String) -> HttpResponse {
+        HttpResponse::Forbidden()
+            .content_type("text/plain")
+            .body(message)
+    }
+}
+
+/// Converts the method of an incoming request for the HTTP client.
+fn forwarded_method(method: &actix_web::http::Method) -> Result<reqwest::Method, Error> {
+    reqwest::Method::from_bytes(method.as_str().as_bytes()).map_err(error::ErrorBadRequest)
+}
+
+/// Converts the status of a response from another server for the response to the client.
+fn forwarded_status(status: reqwest::StatusCode) -> Result<actix_web::http::StatusCode, Error> {
+    actix_web::http::StatusCode::from_u16(status.as_u16()).map_err(error::ErrorBadGateway)
+}
+
+/// Passes a request body through to another server as it arrives.
+fn streaming_body(mut payload: web::Payload) -> reqwest::Body {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    actix_web::rt::spawn(async move {
+        while let Some(chunk) = payload.next().await {
+            // The request has failed or been dropped before the whole body was sent
+            if tx.send(chunk).is_err() {
+                break;
+            }
+        }
+    });
+
+    reqwest::Body::wrap_stream(UnboundedReceiverStream::new(rx))
+}
+
+/// Ends a response body with an error as soon as the given device is no longer allowed.
+fn revocable<S, E>(
+    body: S,
+    device: Arc<Device>,
+    device_id: String,
+) -> impl Stream<Item = Result<Bytes, io::Error>>
+where
+    S: Stream<Item = Result<Bytes, E>> + 'static,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut changes = device.subscribe();
+    // The name is gone from the allowed devices by the time the device is revoked
+    let name = device.display_name(&device_id);
+    let revoked = Box::pin(async move {
+        while changes.changed().await.is_ok() {
+            if !device.is_allowed(device_id.clone()) {
+                return name;
+            }
+        }
+
+        std::future::pending().await
+    });
+
+    futures_util::stream::unfold(
+        (Box::pin(body), Some(revoked)),
+        |(mut body, revoked)| async move {
+            let mut revoked = revoked?;
+
+            tokio::select! {
+                chunk = body.next() => {
+                    chunk.map(|chunk| (chunk.map_err(io::Error::other), (body, Some(revoked))))
+                }
+                name = &mut revoked => {
+                    warn!("Device {} has been revoked, aborting its response", name);
+
+                    let error = io::Error::new(io::ErrorKind::PermissionDenied, "Device has been revoked");
+
+                    Some((Err(error), (body, None)))
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read as _, Write as _},
+        net::TcpListener,
+        thread,
+    };
+
+    use actix_web::test::{self, TestRequest};
+
+    use crate::balancer::{BalancingStrategy, Upstream};
+
+    use super::*;
+
+    /// Returns the address of a server that refuses connections.
+    fn refusing_server() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// Starts a server answering one request with the given raw response, or dropping the
+    /// connection if there is none.
+    fn one_shot_server(response: Option<&'static str>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+
+            if let Some(response) = response {
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        server
+    }
+
+    async fn forward(servers: &[SocketAddr], req: TestRequest) -> (HttpResponse, Vec<SocketAddr>) {
+        let balancer = Arc::new(Balancer::new(
+            BalancingStrategy::RoundRobin,
+            std::time::Duration::ZERO,
+        ));
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(32);
+
+        for server in servers {
+            balancer.add(Arc::new(Upstream::plain(*server, 0, Instant::now())));
         }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(balancer))
+                .app_data(web::Data::new(Arc::new(HeaderRules::default())))
+                .app_data(web::Data::new(cmd_tx))
+                .default_service(web::to(ClientProxy::forward)),
+        )
+        .await;
+        let response = test::call_service(&app, req.to_request()).await;
+        let mut removed = Vec::new();
+
+        while let Ok(command) = cmd_rx.try_recv() {
+            if let ManagerCommand::Remove(server) = command {
+                removed.push(server);
+            }
+        }
+
+        (response.into_parts().1, removed)
+    }
+
+    const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok";
+
+    #[actix_web::test]
+    async fn forward_fails_over_to_the_next_server() {
+        let refusing = refusing_server();
+        let working = one_shot_server(Some(OK_RESPONSE));
+
+        let (response, removed) =
+            forward(&[refusing, working], TestRequest::get().uri("/api/version")).await;
+
+        assert_eq!(response.status(), actix_web::http::StatusCode::OK);
+        assert_eq!(
+            actix_web::body::to_bytes(response.into_body())
+                .await
+                .unwrap(),
+            "ok"
+        );
+        // The manager learns about the failure right away
+        assert_eq!(removed, [refusing]);
+    }
+
+    #[actix_web::test]
+    async fn forward_fails_when_no_server_can_be_reached() {
+        let (first, second) = (refusing_server(), refusing_server());
+
+        let (response, mut removed) =
+            forward(&[first, second], TestRequest::get().uri("/api/version")).await;
+        removed.sort();
+        let mut expected = [first, second];
+        expected.sort();
+
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_GATEWAY);
+        assert_eq!(removed, expected);
+    }
+
+    #[actix_web::test]
+    async fn forward_fails_without_servers() {
+        let (response, removed) = forward(&[], TestRequest::get().uri("/api/version")).await;
+
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(removed.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn forward_does_not_retry_after_connecting() {
+        let dropping = one_shot_server(None);
+        let working = one_shot_server(Some(OK_RESPONSE));
+
+        let (response, removed) =
+            forward(&[dropping, working], TestRequest::get().uri("/api/version")).await;
+
+        // The server may have acted on the request already, so it isn't sent again
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_GATEWAY);
+        assert!(removed.is_empty());
+    }
+
+    fn filter_models(body: serde_json::Value, models: &[&str]) -> serde_json::Value {
+        let filtered = ServerProxy::filter_models(
+            body.to_string().as_bytes(),
+            &AllowedDevice::with_models(models),
+        )
+        .unwrap();
+
+        serde_json::from_str(&filtered).unwrap()
+    }
+
+    #[test]
+    fn filter_models_keeps_allowed_ollama_models() {
+        let body = serde_json::json!({
+            "models": [
+                {"name": "llama3:8b", "size": 1},
+                {"name": "llama3:70b", "size": 2},
+                {"name": "mistral:latest", "size": 3},
+                {"size": 4},
+            ]
+        });
+
+        assert_eq!(
+            filter_models(body, &["llama3:8b", "mistral"]),
+            serde_json::json!({
+                "models": [
+                    {"name": "llama3:8b", "size": 1},
+                    {"name": "mistral:latest", "size": 3},
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn filter_models_keeps_allowed_openai_models() {
+        let body = serde_json::json!({
+            "object": "list",
+            "data": [
+                {"id": "llama3:8b", "object": "model"},
+                {"id": "qwen:7b", "object": "model"},
+            ]
+        });
+
+        assert_eq!(
+            filter_models(body, &["llama3"]),
+            serde_json::json!({
+                "object": "list",
+                "data": [{"id": "llama3:8b", "object": "model"}]
+            })
+        );
+    }
+
+    #[test]
+    fn unauthorized_responses_are_plain_text() {
+        let response = ServerProxy::unauthorized();
+
+        assert_eq!(response.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain"
+        );
+    }
+
+    #[test]
+    fn filter_models_rejects_invalid_json() {
+        assert!(
+            ServerProxy::filter_models(b"not json", &AllowedDevice::with_models(&["llama3"]))
+                .is_err()
+        );
     }
 }
@@ -0,0 +1,381 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+// Endpoints that run models on the GPU, the limits only apply to them
+const GENERATION_PATHS: [&str; 7] = [
+    "/api/generate",
+    "/api/chat",
+    "/api/embed",
+    "/api/embeddings",
+    "/v1/chat/completions",
+    "/v1/completions",
+    "/v1/embeddings",
+];
+const REQUESTS_WINDOW: Duration = Duration::from_secs(60);
+const TOKENS_WINDOW: Duration = Duration::from_secs(60 * 60);
+// There is no telling when an in-flight generation ends, so retrying is suggested after this
+const CONCURRENCY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Limits of how much a device may use a server, unlimited if not set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DeviceLimits {
+    /// Generation requests per minute
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u32>,
+    /// Generation requests in flight at the same time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<u32>,
+    /// Generated tokens per hour, as reported by Ollama in `eval_count`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_per_hour: Option<u64>,
+}
+
+/// Enforces [`DeviceLimits`] of the devices using the server proxy.
+///
+/// Requests and tokens are counted over sliding windows of a minute and an hour respectively.
+/// Usage is kept in memory only, so it starts over when the server restarts.
+#[derive(Default)]
+pub struct RateLimiter {
+    usage: Arc<Mutex<HashMap<String, DeviceUsage>>>,
+}
+
+#[derive(Default)]
+struct DeviceUsage {
+    requests: VecDeque<Instant>,
+    tokens: VecDeque<(Instant, u64)>,
+    in_flight: u32,
+}
+
+/// Keeps a generation counted as in flight for a device until dropped.
+pub struct GenerationGuard {
+    device_id: String,
+    usage: Arc<Mutex<HashMap<String, DeviceUsage>>>,
+}
+
+/// Why a request has been rejected and when it may be retried.
+pub struct LimitExceeded {
+    pub reason: &'static str,
+    pub retry_after: Duration,
+}
+
+impl DeviceLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl std::fmt::Display for DeviceLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let limits = [
+            self.requests_per_minute.map(|n| format!("{} req/min", n)),
+            self.max_concurrent.map(|n| format!("{} concurrent", n)),
+            self.tokens_per_hour.map(|n| format!("{} tokens/h", n)),
+        ];
+        let limits: Vec<_> = limits.into_iter().flatten().collect();
+
+        if limits.is_empty() {
+            write!(f, "-")
+        } else {
+            write!(f, "{}", limits.join(", "))
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Checks whether requests to an endpoint count against the limits.
+    pub fn is_limited(path: &str) -> bool {
+        GENERATION_PATHS.contains(&path)
+    }
+
+    /// Counts a generation request of a device, unless it would exceed the device's limits.
+    ///
+    /// # Arguments
+    /// * `device_id` - The Device ID of the requesting device.
+    /// * `limits` - The limits of the device.
+    ///
+    /// # Returns
+    /// A guard keeping the request in flight until it is dropped.
+    ///
+    /// # Errors
+    /// Returns which limit would be exceeded and when the request may be retried.
+    ///
+    pub fn acquire(
+        &self,
+        device_id: &str,
+        limits: &DeviceLimits,
+    ) -> Result<GenerationGuard, LimitExceeded> {
+        self.acquire_at(device_id, limits, Instant::now())
+    }
+
+    fn acquire_at(
+        &self,
+        device_id: &str,
+        limits: &DeviceLimits,
+        now: Instant,
+    ) -> Result<GenerationGuard, LimitExceeded> {
+        let mut usage = self.usage.lock().unwrap();
+        let device_usage = usage.entry(device_id.to_string()).or_default();
+
+        device_usage.expire(now);
+
+        if let Some(max) = limits.requests_per_minute {
+            if device_usage.requests.len() >= max as usize {
+                // Wait until enough requests leave the window to get below the limit
+                let retry_after = device_usage
+                    .requests
+                    .get(device_usage.requests.len() - max as usize)
+                    .map_or(REQUESTS_WINDOW, |at| {
+                        (*at + REQUESTS_WINDOW).saturating_duration_since(now)
+                    });
+
+                return Err(LimitExceeded {
+                    reason: "requests per minute",
+                    retry_after,
+                });
+            }
+        }
+
+        if let Some(max) = limits.max_concurrent {
+            if device_usage.in_flight >= max {
+                return Err(LimitExceeded {
+                    reason: "concurrent requests",
+                    retry_after: CONCURRENCY_RETRY_AFTER,
+                });
+            }
+        }
+
+        if let Some(max) = limits.tokens_per_hour {
+            let mut used: u64 = device_usage.tokens.iter().map(|(_, n)| n).sum();
+
+            if used >= max {
+                // Wait until enough tokens leave the window to get below the limit
+                let mut retry_at = now + TOKENS_WINDOW;
+
+                for (at, n) in &device_usage.tokens {
+                    used -= n;
+                    retry_at = *at + TOKENS_WINDOW;
+
+                    if used < max {
+                        break;
+                    }
+                }
+
+                return Err(LimitExceeded {
+                    reason: "tokens per hour",
+                    retry_after: retry_at.saturating_duration_since(now),
+                });
+            }
+        }
+
+        device_usage.requests.push_back(now);
+        device_usage.in_flight += 1;
+
+        Ok(GenerationGuard {
+            device_id: device_id.to_string(),
+            usage: self.usage.clone(),
+        })
+    }
+}
+
+impl GenerationGuard {
+    /// Counts tokens generated for the device.
+    pub fn record_tokens(&self, tokens: u64) {
+        self.record_tokens_at(tokens, Instant::now());
+    }
+
+    fn record_tokens_at(&self, tokens: u64, now: Instant) {
+        let mut usage = self.usage.lock().unwrap();
+
+        if let Some(device_usage) = usage.get_mut(&self.device_id) {
+            device_usage.tokens.push_back((now, tokens));
+        }
+    }
+}
+
+impl Drop for GenerationGuard {
+    fn drop(&mut self) {
+        let mut usage = self.usage.lock().unwrap();
+
+        if let Some(device_usage) = usage.get_mut(&self.device_id) {
+            device_usage.in_flight = device_usage.in_flight.saturating_sub(1);
+            device_usage.expire(Instant::now());
+
+            // Forget devices that haven't used the server for a while
+            if device_usage.is_idle() {
+                usage.remove(&self.device_id);
+            }
+        }
+    }
+}
+
+impl DeviceUsage {
+    fn expire(&mut self, now: Instant) {
+        while self
+            .requests
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= REQUESTS_WINDOW)
+        {
+            self.requests.pop_front();
+        }
+
+        while self
+            .tokens
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= TOKENS_WINDOW)
+        {
+            self.tokens.pop_front();
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.in_flight == 0 && self.requests.is_empty() && self.tokens.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE_ID: &str = "device";
+
+    fn limits(
+        requests_per_minute: Option<u32>,
+        max_concurrent: Option<u32>,
+        tokens_per_hour: Option<u64>,
+    ) -> DeviceLimits {
+        DeviceLimits {
+            requests_per_minute,
+            max_concurrent,
+            tokens_per_hour,
+        }
+    }
+
+    #[test]
+    fn requests_per_minute_expire_with_the_window() {
+        let limiter = RateLimiter::default();
+        let limits = limits(Some(2), None, None);
+        let start = Instant::now();
+
+        drop(limiter.acquire_at(DEVICE_ID, &limits, start).ok());
+        drop(limiter.acquire_at(DEVICE_ID, &limits, start).ok());
+
+        let exceeded = limiter.acquire_at(DEVICE_ID, &limits, start).err().unwrap();
+        assert_eq!(exceeded.reason, "requests per minute");
+        assert_eq!(exceeded.retry_after, REQUESTS_WINDOW);
+
+        assert!(limiter
+            .acquire_at(DEVICE_ID, &limits, start + REQUESTS_WINDOW)
+            .is_ok());
+    }
+
+    #[test]
+    fn requests_per_minute_retry_after_the_oldest_request_over_the_limit() {
+        let limiter = RateLimiter::default();
+        let unlimited = DeviceLimits::default();
+        let start = Instant::now();
+
+        // Requests made while unlimited, at 0s, 10s and 20s
+        for secs in [0, 10, 20] {
+            let _guard = limiter
+                .acquire_at(DEVICE_ID, &unlimited, start + Duration::from_secs(secs))
+                .ok();
+        }
+
+        // With 2 requests per minute, the requests at 0s and 10s have to leave the window
+        let exceeded = limiter
+            .acquire_at(
+                DEVICE_ID,
+                &limits(Some(2), None, None),
+                start + Duration::from_secs(30),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(exceeded.retry_after, Duration::from_secs(40));
+
+        let exceeded = limiter
+            .acquire_at(
+                DEVICE_ID,
+                &limits(Some(3), None, None),
+                start + Duration::from_secs(30),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(exceeded.retry_after, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn max_concurrent_is_released_when_the_guard_is_dropped() {
+        let limiter = RateLimiter::default();
+        let limits = limits(None, Some(1), None);
+
+        let guard = limiter.acquire(DEVICE_ID, &limits).ok().unwrap();
+
+        let exceeded = limiter.acquire(DEVICE_ID, &limits).err().unwrap();
+        assert_eq!(exceeded.reason, "concurrent requests");
+        assert_eq!(exceeded.retry_after, CONCURRENCY_RETRY_AFTER);
+        assert!(limiter.acquire("other", &limits).is_ok());
+
+        drop(guard);
+
+        assert!(limiter.acquire(DEVICE_ID, &limits).is_ok());
+    }
+
+    #[test]
+    fn tokens_per_hour_retry_after_enough_tokens_leave_the_window() {
+        let limiter = RateLimiter::default();
+        let limits = limits(None, None, Some(100));
+        let start = Instant::now();
+        let minutes = |n: u64| start + Duration::from_secs(n * 60);
+
+        for (at, tokens) in [(minutes(0), 60), (minutes(10), 30), (minutes(20), 30)] {
+            limiter
+                .acquire_at(DEVICE_ID, &limits, at)
+                .ok()
+                .unwrap()
+                .record_tokens_at(tokens, at);
+        }
+
+        // The first 60 tokens have to leave the window, an hour after they were recorded
+        let exceeded = limiter
+            .acquire_at(DEVICE_ID, &limits, minutes(30))
+            .err()
+            .unwrap();
+        assert_eq!(exceeded.reason, "tokens per hour");
+        assert_eq!(exceeded.retry_after, Duration::from_secs(30 * 60));
+
+        let exceeded = limiter
+            .acquire_at(DEVICE_ID, &limits, minutes(60) - Duration::from_secs(1))
+            .err()
+            .unwrap();
+        assert_eq!(exceeded.retry_after, Duration::from_secs(1));
+
+        assert!(limiter.acquire_at(DEVICE_ID, &limits, minutes(60)).is_ok());
+    }
+
+    #[test]
+    fn unlimited_devices() {
+        assert!(DeviceLimits::default().is_unlimited());
+        assert!(!limits(Some(1), None, None).is_unlimited());
+        assert!(!limits(None, Some(1), None).is_unlimited());
+        assert!(!limits(None, None, Some(1)).is_unlimited());
+    }
+
+    #[test]
+    fn display_limits() {
+        assert_eq!(DeviceLimits::default().to_string(), "-");
+        assert_eq!(limits(Some(10), None, None).to_string(), "10 req/min");
+        assert_eq!(
+            limits(Some(10), Some(2), Some(5000)).to_string(),
+            "10 req/min, 2 concurrent, 5000 tokens/h"
+        );
+        assert_eq!(
+            limits(None, Some(2), Some(5000)).to_string(),
+            "2 concurrent, 5000 tokens/h"
+        );
+    }
+}
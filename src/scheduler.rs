@@ -0,0 +1,502 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::metrics::metrics;
+
+// Requests beyond this are rejected rather than queued
+const MAX_QUEUED_REQUESTS: usize = 1024;
+
+/// In which order queued requests of a device are run, relative to other devices.
+#[derive(
+    clap::ValueEnum,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum DevicePriority {
+    /// Run before the requests of all other devices
+    High,
+    #[default]
+    Normal,
+    /// Run only when no other device is waiting
+    Low,
+}
+
+/// Limits how many generations run on Ollama at once and queues the rest.
+///
+/// Queued requests are run by priority class first. Within a class, devices take turns, so that a
+/// device with many queued requests doesn't hold back the others.
+pub struct Scheduler {
+    capacity: usize,
+    state: Mutex<SchedulerState>,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    running: usize,
+    queued: usize,
+    // Indexed by priority, highest first
+    classes: [PriorityClass; 3],
+    dispatched: u64,
+    wait_total: Duration,
+    wait_max: Duration,
+    next_waiter_id: u64,
+}
+
+#[derive(Default)]
+struct PriorityClass {
+    // Devices with queued requests, in the order they take turns
+    turns: VecDeque<String>,
+    waiting: HashMap<String, VecDeque<Waiter>>,
+}
+
+struct Waiter {
+    id: u64,
+    tx: oneshot::Sender<Slot>,
+    queued_at: Instant,
+}
+
+/// Removes a queued request from the queue when dropped before it has been run.
+struct QueuedRequest {
+    scheduler: Arc<Scheduler>,
+    priority: DevicePriority,
+    device_id: String,
+    waiter_id: u64,
+    is_run: bool,
+}
+
+/// Keeps a generation running until dropped, then hands it over to the next queued request.
+pub struct Slot {
+    scheduler: Arc<Scheduler>,
+    // Set when the slot couldn't be handed over, so that dropping it doesn't free it again
+    is_abandoned: bool,
+}
+
+/// The queue is full.
+#[derive(Debug)]
+pub struct QueueFull;
+
+/// A snapshot of the queue depth and wait times.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SchedulerStats {
+    pub capacity: usize,
+    pub running: usize,
+    pub queued: usize,
+    pub queued_by_priority: BTreeMap<DevicePriority, usize>,
+    /// Requests run since the start, including the ones that didn't have to wait
+    pub dispatched: u64,
+    pub wait_seconds_total: f64,
+    pub wait_seconds_max: f64,
+}
+
+impl DevicePriority {
+    fn index(&self) -> usize {
+        match self {
+            DevicePriority::High => 0,
+            DevicePriority::Normal => 1,
+            DevicePriority::Low => 2,
+        }
+    }
+}
+
+impl std::fmt::Display for DevicePriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DevicePriority::High => write!(f, "high"),
+            DevicePriority::Normal => write!(f, "normal"),
+            DevicePriority::Low => write!(f, "low"),
+        }
+    }
+}
+
+impl Scheduler {
+    /// Creates a scheduler running up to `capacity` generations at once.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(SchedulerState::default()),
+        }
+    }
+
+    /// Waits for a generation of a device to be run.
+    ///
+    /// The request leaves the queue when the returned future is dropped, e.g. when the client
+    /// disconnects.
+    ///
+    /// # Arguments
+    /// * `device_id` - The Device ID of the requesting device.
+    /// * `priority` - The priority class of the device.
+    ///
+    /// # Errors
+    /// Returns an error if too many requests are queued already.
+    ///
+    pub async fn acquire(
+        self: &Arc<Self>,
+        device_id: &str,
+        priority: DevicePriority,
+    ) -> Result<Slot, QueueFull> {
+        let (rx, waiter_id) = {
+            let mut state = self.state.lock().unwrap();
+
+            if state.running < self.capacity {
+                state.running += 1;
+                state.record_wait(Duration::ZERO);
+                state.publish();
+
+                return Ok(Slot::new(self.clone()));
+            }
+
+            if state.queued >= MAX_QUEUED_REQUESTS {
+                return Err(QueueFull);
+            }
+
+            let (tx, rx) = oneshot::channel();
+            let waiter_id = state.next_waiter_id;
+            state.next_waiter_id += 1;
+
+            let class = &mut state.classes[priority.index()];
+            let waiting = class.waiting.entry(device_id.to_string()).or_default();
+
+            if waiting.is_empty() {
+                class.turns.push_back(device_id.to_string());
+            }
+
+            waiting.push_back(Waiter {
+                id: waiter_id,
+                tx,
+                queued_at: Instant::now(),
+            });
+            state.queued += 1;
+            state.publish();
+
+            (rx, waiter_id)
+        };
+
+        // Declared after the receiver, so that it is dropped first. A slot sent in the meantime
+        // is handed over to the next request when the receiver is dropped.
+        let mut queued = QueuedRequest {
+            scheduler: self.clone(),
+            priority,
+            device_id: device_id.to_string(),
+            waiter_id,
+            is_run: false,
+        };
+
+        // The sender is only dropped after a slot has been sent, or with the queued request
+        let slot = rx.await.expect("queued requests are always run");
+        queued.is_run = true;
+
+        Ok(slot)
+    }
+
+    /// Returns the current queue depth and the wait times so far.
+    pub fn stats(&self) -> SchedulerStats {
+        let state = self.state.lock().unwrap();
+        let queued_by_priority = [
+            DevicePriority::High,
+            DevicePriority::Normal,
+            DevicePriority::Low,
+        ]
+        .into_iter()
+        .map(|priority| {
+            let class = &state.classes[priority.index()];
+
+            (priority, class.waiting.values().map(VecDeque::len).sum())
+        })
+        .collect();
+
+        SchedulerStats {
+            capacity: self.capacity,
+            running: state.running,
+            queued: state.queued,
+            queued_by_priority,
+            dispatched: state.dispatched,
+            wait_seconds_total: state.wait_total.as_secs_f64(),
+            wait_seconds_max: state.wait_max.as_secs_f64(),
+        }
+    }
+
+    /// Hands a finished generation's slot over to the next queued request, or frees it.
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+
+        while let Some(waiter) = state.next_waiter() {
+            let wait = waiter.queued_at.elapsed();
+
+            match waiter.tx.send(Slot::new(self.clone())) {
+                Ok(()) => {
+                    state.record_wait(wait);
+                    state.publish();
+
+                    return;
+                }
+                // The request has left the queue, try the next one
+                Err(mut slot) => slot.is_abandoned = true,
+            }
+        }
+
+        state.running -= 1;
+        state.publish();
+    }
+}
+
+impl SchedulerState {
+    fn next_waiter(&mut self) -> Option<Waiter> {
+        let class = self.classes.iter_mut().find(|c| !c.turns.is_empty())?;
+        let device_id = class.turns.pop_front()?;
+        let waiting = class.waiting.get_mut(&device_id)?;
+        let waiter = waiting.pop_front();
+
+        if waiting.is_empty() {
+            class.waiting.remove(&device_id);
+        } else {
+            class.turns.push_back(device_id);
+        }
+
+        self.queued -= 1;
+
+        waiter
+    }
+
+    /// Removes a waiter from the queue, if it is still queued.
+    fn remove_waiter(&mut self, priority: DevicePriority, device_id: &str, waiter_id: u64) {
+        let class = &mut self.classes[priority.index()];
+        let Some(waiting) = class.waiting.get_mut(device_id) else {
+            return;
+        };
+        let Some(position) = waiting.iter().position(|w| w.id == waiter_id) else {
+            return;
+        };
+
+        waiting.remove(position);
+
+        if waiting.is_empty() {
+            class.waiting.remove(device_id);
+            class.turns.retain(|id| id != device_id);
+        }
+
+        self.queued -= 1;
+        self.publish();
+    }
+
+    fn record_wait(&mut self, wait: Duration) {
+        self.dispatched += 1;
+        self.wait_total += wait;
+        self.wait_max = self.wait_max.max(wait);
+
+        metrics().observe_scheduler_wait(wait);
+    }
+
+    /// Updates the running and queued generations in the metrics.
+    fn publish(&self) {
+        metrics().update_scheduler(self.running, self.queued);
+    }
+}
+
+impl Slot {
+    fn new(scheduler: Arc<Scheduler>) -> Self {
+        Self {
+            scheduler,
+            is_abandoned: false,
+        }
+    }
+}
+
+impl Drop for QueuedRequest {
+    fn drop(&mut self) {
+        if !self.is_run {
+            self.scheduler.state.lock().unwrap().remove_waiter(
+                self.priority,
+                &self.device_id,
+                self.waiter_id,
+            );
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.is_abandoned {
+            self.scheduler.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, pin::Pin};
+
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    type Acquire<'a> = Pin<Box<dyn Future<Output = Result<Slot, QueueFull>> + 'a>>;
+
+    /// Queues requests of the given devices, in the given order.
+    fn enqueue<'a>(
+        scheduler: &'a Arc<Scheduler>,
+        requests: &[(&'a str, DevicePriority)],
+    ) -> Vec<(&'a str, Acquire<'a>)> {
+        requests
+            .iter()
+            .map(|(device_id, priority)| {
+                let mut acquire: Acquire = Box::pin(scheduler.acquire(device_id, *priority));
+
+                assert!(acquire.as_mut().now_or_never().is_none());
+
+                (*device_id, acquire)
+            })
+            .collect()
+    }
+
+    /// Returns the device of the only queued request that has been run.
+    fn next_run<'a>(queue: &mut Vec<(&'a str, Acquire<'a>)>) -> (&'a str, Slot) {
+        let mut run = Vec::new();
+
+        for (i, (_, acquire)) in queue.iter_mut().enumerate() {
+            if let Some(slot) = acquire.as_mut().now_or_never() {
+                run.push((i, slot.unwrap()));
+            }
+        }
+
+        assert_eq!(run.len(), 1, "exactly one queued request should run");
+
+        let (i, slot) = run.pop().unwrap();
+
+        (queue.remove(i).0, slot)
+    }
+
+    /// Runs the queued requests one by one and returns the devices in the order they ran.
+    fn run_order<'a>(running: Slot, mut queue: Vec<(&'a str, Acquire<'a>)>) -> Vec<&'a str> {
+        let mut order = Vec::new();
+        let mut running = running;
+
+        while !queue.is_empty() {
+            drop(running);
+
+            let (device_id, slot) = next_run(&mut queue);
+
+            order.push(device_id);
+            running = slot;
+        }
+
+        order
+    }
+
+    fn run_now(scheduler: &Arc<Scheduler>) -> Slot {
+        scheduler
+            .acquire("running", DevicePriority::Normal)
+            .now_or_never()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn runs_up_to_capacity_without_queueing() {
+        let scheduler = Arc::new(Scheduler::new(2));
+        let _first = run_now(&scheduler);
+        let _second = run_now(&scheduler);
+        let queue = enqueue(&scheduler, &[("a", DevicePriority::Normal)]);
+
+        let stats = scheduler.stats();
+        assert_eq!(stats.running, 2);
+        assert_eq!(stats.queued, 1);
+        assert_eq!(stats.dispatched, 2);
+        drop(queue);
+    }
+
+    #[test]
+    fn devices_take_turns_within_a_priority_class() {
+        let scheduler = Arc::new(Scheduler::new(1));
+        let running = run_now(&scheduler);
+        let queue = enqueue(
+            &scheduler,
+            &[
+                ("a", DevicePriority::Normal),
+                ("a", DevicePriority::Normal),
+                ("a", DevicePriority::Normal),
+                ("b", DevicePriority::Normal),
+                ("c", DevicePriority::Normal),
+                ("b", DevicePriority::Normal),
+            ],
+        );
+
+        assert_eq!(run_order(running, queue), ["a", "b", "c", "a", "b", "a"]);
+        assert_eq!(scheduler.stats().queued, 0);
+    }
+
+    #[test]
+    fn higher_priority_classes_run_first() {
+        let scheduler = Arc::new(Scheduler::new(1));
+        let running = run_now(&scheduler);
+        let queue = enqueue(
+            &scheduler,
+            &[
+                ("low", DevicePriority::Low),
+                ("normal", DevicePriority::Normal),
+                ("high", DevicePriority::High),
+                ("normal", DevicePriority::Normal),
+            ],
+        );
+
+        assert_eq!(
+            run_order(running, queue),
+            ["high", "normal", "normal", "low"]
+        );
+    }
+
+    #[test]
+    fn dropped_requests_leave_the_queue() {
+        let scheduler = Arc::new(Scheduler::new(1));
+        let running = run_now(&scheduler);
+        let mut queue = enqueue(
+            &scheduler,
+            &[
+                ("a", DevicePriority::Normal),
+                ("b", DevicePriority::Normal),
+                ("a", DevicePriority::Normal),
+                ("c", DevicePriority::High),
+            ],
+        );
+
+        // The first request of a and the one of c
+        drop(queue.remove(3));
+        drop(queue.remove(0));
+
+        let stats = scheduler.stats();
+        assert_eq!(stats.queued, 2);
+        assert_eq!(stats.queued_by_priority[&DevicePriority::High], 0);
+        assert_eq!(stats.queued_by_priority[&DevicePriority::Normal], 2);
+
+        // The device keeps its turn while it has other requests queued
+        assert_eq!(run_order(running, queue), ["a", "b"]);
+    }
+
+    #[test]
+    fn slots_are_freed_when_all_requests_have_left() {
+        let scheduler = Arc::new(Scheduler::new(1));
+        let running = run_now(&scheduler);
+        let queue = enqueue(&scheduler, &[("a", DevicePriority::Normal)]);
+
+        drop(queue);
+        drop(running);
+
+        let stats = scheduler.stats();
+        assert_eq!(stats.running, 0);
+        assert_eq!(stats.queued, 0);
+    }
+}
@@ -1,13 +1,36 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    args::ServeArgs, certs::Certs, device::Device, discovery::ServerDiscovery, manager::Manager,
-    ollama::Ollama, proxy::ServerProxy, Mode,
+    args::{DiscoveryMethod, ServeArgs},
+    balancer::{Balancer, BalancingStrategy},
+    certs::Certs,
+    config::Config,
+    control::{ClientState, Control, ModeState, ServerState},
+    device::Device,
+    discovery::{ClientDiscovery, DiscoveryBackend, ServerDiscovery},
+    headers::HeaderRules,
+    known_servers::KnownServers,
+    manager::{Manager, ManagerOptions},
+    mdns::{MdnsAdvertiser, MdnsBrowser},
+    metrics,
+    ollama::Ollama,
+    preference::ServerPreferences,
+    proxy::{ClientProxyOptions, ServerProxy},
+    usage::UsageLog,
+    Mode,
 };
 use daemonizr::{Daemonizr, Group, Stderr, Stdout, User};
 use futures_util::TryFutureExt;
-use log::{error, info, warn};
-use tokio::signal::unix::{signal, SignalKind};
+use log::{debug, error, info, warn};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time,
+};
 
 const DEFAULT_LOG_FILE_PATH: &str = "/var/log/ollana/serve.log";
 const DEFAULT_PID_FILE_PATH: &str = "/run/ollana.pid";
@@ -18,19 +41,58 @@ pub struct ServeApp {
     pid_file: Option<PathBuf>,
     log_file: Option<PathBuf>,
     force_server_mode: bool,
+    // Port of the client proxy in hybrid mode
+    hybrid_port: Option<u16>,
+    ipv4_multicast_group: Option<Ipv4Addr>,
+    discovery: DiscoveryMethod,
+    static_servers: Vec<String>,
+    balancing: BalancingStrategy,
+    annotate_models: bool,
+    server_preferences: ServerPreferences,
+    max_parallel_requests: usize,
+    header_rules: HeaderRules,
+    metrics_address: Option<SocketAddr>,
+    control: Control,
+    usage_log: Arc<UsageLog>,
+    config: Config,
     local_ollama: Arc<Ollama>,
     certs: Arc<Certs>,
     device: Arc<Device>,
 }
 
 impl ServeApp {
-    pub fn new(args: ServeArgs, certs: Arc<Certs>, device: Arc<Device>) -> anyhow::Result<Self> {
+    pub fn new(
+        args: ServeArgs,
+        config: Config,
+        certs: Arc<Certs>,
+        device: Arc<Device>,
+    ) -> anyhow::Result<Self> {
         Ok(ServeApp {
             sysv_daemon: args.daemon,
             pid_file: args.pid_file,
             log_file: args.log_file,
             force_server_mode: args.force_server_mode,
-            local_ollama: Arc::new(Ollama::default()),
+            hybrid_port: args.hybrid,
+            ipv4_multicast_group: args.ipv4_multicast_group,
+            discovery: args.discovery,
+            static_servers: args.servers,
+            balancing: args.balancing,
+            annotate_models: args.annotate_models,
+            server_preferences: ServerPreferences {
+                preferred: args.prefer,
+                pinned: args.pin,
+                switch_back_delay: Duration::from_secs(args.switch_back_delay),
+            },
+            max_parallel_requests: args.max_parallel_requests,
+            header_rules: HeaderRules {
+                remove: args.remove_headers,
+                add: args.add_headers,
+            },
+            metrics_address: args.metrics,
+            control: Control::new(args.control_socket, device.clone())?,
+            usage_log: Arc::new(UsageLog::new()?),
+            local_ollama: Arc::new(Ollama::with_url(config.ollama.url()?)),
+            config,
             certs,
             device,
         })
@@ -47,73 +109,296 @@ impl ServeApp {
     }
 
     async fn detect_mode_and_run(&self) -> anyhow::Result<()> {
-        match self.detect_mode().await {
-            Mode::Server => self.run_server_mode().await,
-            Mode::Client => self.run_client_mode().await,
+        // Prepare signal futures
+        let mut sigterm = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            // Cross-platform ctrl_c support
+            _ = tokio::signal::ctrl_c().map_err(anyhow::Error::new) => {
+                info!("Received Ctrl-c (SIGINT), shutting down...");
+                Ok(())
+            },
+            // Unix: SIGTERM
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM, shutting down...");
+                Ok(())
+            }
+            val = self.run_modes() => val,
+            val = self.device.watch_config() => val,
+            val = self.usage_log.run() => val,
+            val = self.serve_metrics() => val,
+            val = self.control.run() => val,
         }
     }
 
+    /// Runs the detected mode, switching between modes as the local Ollama comes up or goes down.
+    async fn run_modes(&self) -> anyhow::Result<()> {
+        let mut mode = Some(self.detect_mode().await);
+
+        while let Some(current) = mode {
+            mode = match current {
+                Mode::Server => self.run_server_mode(None).await?,
+                Mode::Hybrid => self.run_server_mode(self.hybrid_port).await?,
+                Mode::Client => self.run_client_mode().await?,
+            };
+        }
+
+        Ok(())
+    }
+
     async fn detect_mode(&self) -> Mode {
         if self.force_server_mode {
             warn!("Force server mode is enabled. Ollama may not be available yet during boot.");
             warn!("Requests may fail until Ollama is fully started. ServerDiscovery will handle this automatically.");
 
-            return Mode::Server;
+            return self.server_mode();
         }
 
         match self.local_ollama.get_version().await {
-            Ok(_) => Mode::Server,
+            Ok(_) => self.server_mode(),
             Err(_) => Mode::Client,
         }
     }
 
-    async fn run_server_mode(&self) -> anyhow::Result<()> {
-        let server_proxy = ServerProxy::new(self.device.clone());
-        let server_discovery = ServerDiscovery::new(self.local_ollama.clone());
+    /// Returns the mode to run while the local Ollama is up.
+    fn server_mode(&self) -> Mode {
+        match self.hybrid_port {
+            Some(_) => Mode::Hybrid,
+            None => Mode::Server,
+        }
+    }
 
-        info!("Running in Server Mode");
+    /// Runs the server mode until the local Ollama goes down.
+    ///
+    /// # Arguments
+    /// * `hybrid_port` - Runs the hybrid mode with a client proxy for the other servers on this
+    ///   port, if given.
+    ///
+    /// # Returns
+    /// The mode to switch to, `None` if the mode has stopped otherwise.
+    ///
+    async fn run_server_mode(&self, hybrid_port: Option<u16>) -> anyhow::Result<Option<Mode>> {
+        let mut server_proxy = ServerProxy::new(
+            self.device.clone(),
+            &self.config.server_proxy,
+            self.config.ollama.url()?,
+            self.max_parallel_requests,
+            self.header_rules.clone(),
+            self.usage_log.clone(),
+        );
+        let server_discovery = ServerDiscovery::new(
+            self.local_ollama.clone(),
+            self.device.clone(),
+            self.ipv4_multicast_group,
+            &self.config.discovery,
+            server_proxy.port(),
+        );
+        let mdns_advertiser = MdnsAdvertiser::new(
+            self.local_ollama.clone(),
+            self.device.clone(),
+            &self.config.discovery,
+            server_proxy.port(),
+        );
+        let mut manager = hybrid_port
+            .map(|port| self.manager(SocketAddr::new(self.config.client_proxy.address, port)))
+            .transpose()?;
+        let server_state = ServerState {
+            address: server_proxy.address(),
+            scheduler: server_proxy.scheduler(),
+        };
 
-        self.certs.gen_http_server()?;
+        match &manager {
+            Some(manager) => {
+                self.control
+                    .set_mode(ModeState::Hybrid(server_state, Self::client_state(manager)));
 
-        // Prepare signal futures
-        let mut sigterm = signal(SignalKind::terminate())?;
+                info!("Running in Hybrid Mode");
+            }
+            None => {
+                self.control.set_mode(ModeState::Server(server_state));
 
-        tokio::select! {
-            // Cross-platform ctrl_c support
-            _ = tokio::signal::ctrl_c().map_err(anyhow::Error::new) => {
-                info!("Received Ctrl-c (SIGINT), shutting down Server Mode...");
-                Ok(())
-            },
-            // Unix: SIGTERM
-            _ = sigterm.recv() => {
-                info!("Received SIGTERM, shutting down Server Mode...");
-                Ok(())
+                info!("Running in Server Mode");
+            }
+        }
+
+        let result = tokio::select! {
+            val = server_proxy.run_server(&self.certs) => val.map(|_| None),
+            val = async {
+                if self.discovery.uses_udp() {
+                    server_discovery.run().await
+                } else {
+                    std::future::pending().await
+                }
+            } => val.map(|_| None),
+            val = async {
+                if self.discovery.uses_mdns() {
+                    mdns_advertiser.run().await
+                } else {
+                    std::future::pending().await
+                }
+            } => val.map(|_| None),
+            val = async {
+                match &mut manager {
+                    Some(manager) => manager.run().await,
+                    None => std::future::pending().await,
+                }
+            } => val.map(|_| None),
+            _ = self.wait_for_local_ollama_down() => {
+                info!("Local Ollama is down, switching to Client Mode");
+                Ok(Some(Mode::Client))
             }
-            val = server_proxy.run_server(&self.certs) => val,
-            val = server_discovery.run() => val,
+        };
+
+        // The server proxy keeps listening after its future has been dropped
+        server_proxy.stop(true).await;
+
+        if let Some(manager) = &mut manager {
+            manager.stop().await?;
         }
+
+        result
     }
 
-    async fn run_client_mode(&self) -> anyhow::Result<()> {
-        let mut manager = Manager::new(self.device.clone());
+    /// Runs the client mode until the local Ollama comes up.
+    ///
+    /// # Returns
+    /// The mode to switch to, `None` if the mode has stopped otherwise.
+    ///
+    async fn run_client_mode(&self) -> anyhow::Result<Option<Mode>> {
+        let mut manager = self.manager(self.config.client_proxy.socket_addr())?;
+        let balancer = manager.balancer();
+
+        self.control
+            .set_mode(ModeState::Client(Self::client_state(&manager)));
 
         info!("Running in Client Mode");
 
-        // Prepare signal futures
-        let mut sigterm = signal(SignalKind::terminate())?;
+        let result = tokio::select! {
+            val = manager.run() => val.map(|_| None),
+            _ = self.wait_for_local_ollama_up(&balancer) => {
+                let mode = self.server_mode();
 
-        tokio::select! {
-            // Cross-platform ctrl_c support
-            _ = tokio::signal::ctrl_c().map_err(anyhow::Error::new) => {
-                info!("Received Ctrl-c (SIGINT), shutting down Client Mode...");
-                Ok(())
+                info!("Local Ollama is up, switching to {:?} Mode", mode);
+                Ok(Some(mode))
+            }
+        };
+
+        // Frees the address of Ollama and stops checking the servers
+        manager.stop().await?;
+
+        result
+    }
+
+    /// Creates a manager discovering servers and forwarding requests to them.
+    ///
+    /// # Arguments
+    /// * `proxy_address` - The address the client proxy listens on.
+    ///
+    fn manager(&self, proxy_address: SocketAddr) -> anyhow::Result<Manager> {
+        let mut discovery_backends = Vec::new();
+
+        if self.discovery.uses_udp() {
+            discovery_backends.push(DiscoveryBackend::Udp(ClientDiscovery::new(
+                self.device.clone(),
+                self.ipv4_multicast_group,
+                &self.config.discovery,
+            )));
+        }
+
+        if self.discovery.uses_mdns() {
+            discovery_backends.push(DiscoveryBackend::Mdns(MdnsBrowser::new(
+                self.device.clone(),
+                &self.config.discovery,
+            )));
+        }
+
+        Ok(Manager::new(
+            self.device.clone(),
+            discovery_backends,
+            ManagerOptions {
+                static_servers: self.static_servers.clone(),
+                balancing_strategy: self.balancing,
+                preferences: self.server_preferences.clone(),
+                liveness_interval: self.config.manager.liveness_interval(),
+                static_servers_interval: self.config.manager.static_servers_interval(),
             },
-            // Unix: SIGTERM
-            _ = sigterm.recv() => {
-                info!("Received SIGTERM, shutting down Client Mode...");
-                Ok(())
+            ClientProxyOptions {
+                address: proxy_address,
+                workers: self.config.client_proxy.workers,
+                annotate_models: self.annotate_models,
+                header_rules: self.header_rules.clone(),
+            },
+            Arc::new(KnownServers::new()?),
+        ))
+    }
+
+    fn client_state(manager: &Manager) -> ClientState {
+        ClientState {
+            balancer: manager.balancer(),
+            proxy_address: manager.proxy_address(),
+        }
+    }
+
+    /// Resolves once the local Ollama has failed several checks in a row.
+    ///
+    /// Never resolves with `--force-server-mode`.
+    ///
+    async fn wait_for_local_ollama_down(&self) {
+        if self.force_server_mode {
+            return std::future::pending().await;
+        }
+
+        let down_checks = self.config.ollama.down_checks;
+        let mut interval = time::interval(self.config.ollama.check_interval());
+        let mut failed_checks = 0;
+
+        // The first tick completes immediately, Ollama has just been checked
+        interval.tick().await;
+
+        // Several checks have to fail, so that a restart of Ollama (e.g. to update it) doesn't
+        // switch modes back and forth
+        while failed_checks < down_checks {
+            interval.tick().await;
+
+            match self.local_ollama.get_version().await {
+                Ok(_) => failed_checks = 0,
+                Err(error) => {
+                    failed_checks += 1;
+                    debug!(
+                        "Local Ollama check failed ({}/{}): {}",
+                        failed_checks, down_checks, error
+                    );
+                }
             }
-            val = manager.run() => val,
+        }
+    }
+
+    /// Resolves once the local Ollama responds.
+    ///
+    /// While there are servers the client proxy listens on the address of Ollama, so Ollama can't
+    /// be running there and it isn't checked, as the proxy would answer instead.
+    ///
+    /// # Arguments
+    /// * `balancer` - The balancer of the manager, telling whether there are servers.
+    ///
+    async fn wait_for_local_ollama_up(&self, balancer: &Balancer) {
+        let mut interval = time::interval(self.config.ollama.check_interval());
+
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if balancer.upstreams().is_empty() && self.local_ollama.get_version().await.is_ok() {
+                return;
+            }
+        }
+    }
+
+    async fn serve_metrics(&self) -> anyhow::Result<()> {
+        match self.metrics_address {
+            Some(address) => metrics::serve(address).await,
+            None => std::future::pending().await,
         }
     }
 
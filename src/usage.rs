@@ -0,0 +1,556 @@
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{get_local_dir, rate_limit::GenerationGuard};
+
+const USAGE_JSONL: &str = "usage.jsonl";
+// Usage is reported in the last, small line of a response, longer lines aren't looked into
+const MAX_METERED_LINE_LEN: usize = 1024 * 1024;
+// Streamed responses of the OpenAI compatible API are server-sent events
+const SSE_DATA_PREFIX: &[u8] = b"data: ";
+
+/// The usage of a single generation, as reported by Ollama at the end of its response.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UsageRecord {
+    /// When the generation finished, in seconds since the Unix epoch
+    pub at: u64,
+    /// The Device ID of the device that requested the generation
+    pub device: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub duration_ms: u64,
+}
+
+/// A log of the generations run on this server, stored in `usage.jsonl`.
+///
+/// Records are appended as JSON lines, so that the log doesn't have to be rewritten on every
+/// generation. Remove the file to start over.
+pub struct UsageLog {
+    dir: PathBuf,
+    // Records are written by `run`, so that responses never wait for the file
+    records_tx: mpsc::UnboundedSender<UsageRecord>,
+    records_rx: Mutex<Option<mpsc::UnboundedReceiver<UsageRecord>>>,
+}
+
+/// Records the usage of a generation from its response body as it is streamed.
+///
+/// Ollama reports it in `prompt_eval_count`, `eval_count` and `total_duration` of the last JSON
+/// line of a response, the OpenAI compatible API in `usage`.
+pub struct UsageMeter {
+    device_id: String,
+    usage_log: Arc<UsageLog>,
+    // Counts the generated tokens against the device's limits
+    generation: Option<GenerationGuard>,
+    started_at: Instant,
+    line: Vec<u8>,
+    is_line_skipped: bool,
+    is_recorded: bool,
+}
+
+/// How to group usage records in statistics.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum UsageGrouping {
+    Device,
+    Model,
+    Hour,
+    Day,
+}
+
+/// Which usage records to include in statistics.
+#[derive(Clone, Debug, Default)]
+pub struct UsageFilter {
+    /// Records from this time on, in seconds since the Unix epoch
+    pub since: Option<u64>,
+    /// Records before this time, in seconds since the Unix epoch
+    pub until: Option<u64>,
+    pub device: Option<String>,
+    pub model: Option<String>,
+}
+
+/// The summed up usage of a group of records.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct UsageSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Start of the hour or day, in seconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub duration_ms: u64,
+}
+
+impl UsageLog {
+    pub fn new() -> anyhow::Result<Self> {
+        let dir = get_local_dir()?;
+        let (records_tx, records_rx) = mpsc::unbounded_channel();
+
+        Ok(Self {
+            dir,
+            records_tx,
+            records_rx: Mutex::new(Some(records_rx)),
+        })
+    }
+
+    /// Queues the usage of a generation to be appended to the log by [`UsageLog::run`].
+    pub fn record(&self, record: UsageRecord) {
+        // The receiver lives as long as the log
+        let _ = self.records_tx.send(record);
+    }
+
+    /// Appends the recorded usage to the log until cancelled.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let mut records_rx = self
+            .records_rx
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| anyhow::Error::msg("The usage log is written already"))?;
+        let path = self.dir.join(USAGE_JSONL);
+
+        while let Some(record) = records_rx.recv().await {
+            let path = path.clone();
+            let result = tokio::task::spawn_blocking(move || Self::append(&path, &record)).await?;
+
+            if let Err(error) = result {
+                error!("Couldn't record usage: {}", error);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn append(path: &Path, record: &UsageRecord) -> anyhow::Result<()> {
+        let mut usage_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to create/open a usage file: {}", e))?;
+        let mut line = serde_json::to_vec(record)?;
+
+        line.push(b'\n');
+        usage_file.write_all(&line)?;
+
+        Ok(())
+    }
+
+    /// Returns all records of the log, oldest first.
+    ///
+    /// # Errors
+    /// Returns an error if the log can't be read. Malformed records are skipped.
+    ///
+    pub fn records(&self) -> anyhow::Result<Vec<UsageRecord>> {
+        let path = self.dir.join(USAGE_JSONL);
+
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut records = Vec::new();
+
+        for (number, line) in BufReader::new(std::fs::File::open(path)?)
+            .lines()
+            .enumerate()
+        {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(error) => warn!("Skipping usage record on line {}: {}", number + 1, error),
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+impl UsageMeter {
+    /// Creates a meter for a generation of a device.
+    ///
+    /// # Arguments
+    /// * `device_id` - The Device ID of the requesting device.
+    /// * `usage_log` - Where to record the usage.
+    /// * `generation` - Counts the generated tokens against the device's limits, if set.
+    ///
+    pub fn new(
+        device_id: String,
+        usage_log: Arc<UsageLog>,
+        generation: Option<GenerationGuard>,
+    ) -> Self {
+        Self {
+            device_id,
+            usage_log,
+            generation,
+            started_at: Instant::now(),
+            line: Vec::new(),
+            is_line_skipped: false,
+            is_recorded: false,
+        }
+    }
+
+    /// Looks for the usage in the next chunk of the response body.
+    pub fn feed(&mut self, chunk: &[u8]) {
+        for part in chunk.split_inclusive(|b| *b == b'\n') {
+            if self.line.len() + part.len() > MAX_METERED_LINE_LEN {
+                self.line.clear();
+                self.is_line_skipped = true;
+            } else if !self.is_line_skipped {
+                self.line.extend_from_slice(part);
+            }
+
+            if part.ends_with(b"\n") {
+                self.end_line();
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        if !self.is_line_skipped && !self.is_recorded {
+            if let Some(record) = self.parse_usage() {
+                self.is_recorded = true;
+
+                if let Some(generation) = &self.generation {
+                    generation.record_tokens(record.completion_tokens);
+                }
+
+                self.usage_log.record(record);
+            }
+        }
+
+        self.line.clear();
+        self.is_line_skipped = false;
+    }
+
+    fn parse_usage(&self) -> Option<UsageRecord> {
+        let line = self.line.trim_ascii();
+        let line = line.strip_prefix(SSE_DATA_PREFIX).unwrap_or(line);
+        let mentions = |key: &[u8]| line.windows(key.len()).any(|w| w == key);
+
+        if !mentions(b"\"eval_count\"")
+            && !mentions(b"\"prompt_eval_count\"")
+            && !mentions(b"\"usage\"")
+        {
+            return None;
+        }
+
+        let json = serde_json::from_slice::<serde_json::Value>(line).ok()?;
+        let count = |keys: [&str; 2]| {
+            keys.iter()
+                .find_map(|key| json.pointer(key).and_then(|v| v.as_u64()))
+        };
+        let prompt_tokens = count(["/prompt_eval_count", "/usage/prompt_tokens"]);
+        let completion_tokens = count(["/eval_count", "/usage/completion_tokens"]);
+
+        if prompt_tokens.is_none() && completion_tokens.is_none() {
+            return None;
+        }
+
+        // The OpenAI compatible API doesn't report durations
+        let duration_ms = json
+            .get("total_duration")
+            .and_then(|v| v.as_u64())
+            .map_or(self.started_at.elapsed().as_millis() as u64, |ns| {
+                ns / 1_000_000
+            });
+
+        Some(UsageRecord {
+            at: unix_now(),
+            device: self.device_id.clone(),
+            model: json.get("model").and_then(|v| v.as_str()).map(String::from),
+            prompt_tokens: prompt_tokens.unwrap_or_default(),
+            completion_tokens: completion_tokens.unwrap_or_default(),
+            duration_ms,
+        })
+    }
+}
+
+impl Drop for UsageMeter {
+    fn drop(&mut self) {
+        // Responses that aren't streamed don't end with a new line
+        self.end_line();
+    }
+}
+
+impl UsageGrouping {
+    fn period(&self, at: u64) -> Option<u64> {
+        match self {
+            UsageGrouping::Hour => Some(at - at % 3600),
+            UsageGrouping::Day => Some(at - at % 86400),
+            _ => None,
+        }
+    }
+}
+
+impl UsageFilter {
+    fn matches(&self, record: &UsageRecord) -> bool {
+        self.since.is_none_or(|since| record.at >= since)
+            && self.until.is_none_or(|until| record.at < until)
+            && self
+                .device
+                .as_ref()
+                .is_none_or(|device| device.eq_ignore_ascii_case(&record.device))
+            && self
+                .model
+                .as_ref()
+                .is_none_or(|model| record.model.as_ref() == Some(model))
+    }
+}
+
+/// Sums up usage records by the given groupings.
+///
+/// # Arguments
+/// * `records` - The usage records, e.g. from [`UsageLog::records`].
+/// * `filter` - Which records to include.
+/// * `groupings` - What to group the records by, everything is summed up together if empty.
+///
+/// # Returns
+/// The usage of each group, ordered by device, model and period.
+///
+pub fn summarize(
+    records: &[UsageRecord],
+    filter: &UsageFilter,
+    groupings: &[UsageGrouping],
+) -> Vec<UsageSummary> {
+    let mut summaries = BTreeMap::new();
+
+    for record in records.iter().filter(|record| filter.matches(record)) {
+        let device = groupings
+            .contains(&UsageGrouping::Device)
+            .then(|| record.device.clone());
+        let model = groupings
+            .contains(&UsageGrouping::Model)
+            .then(|| record.model.clone().unwrap_or_else(|| "-".to_string()));
+        let period = groupings.iter().find_map(|g| g.period(record.at));
+
+        let summary = summaries
+            .entry((device.clone(), model.clone(), period))
+            .or_insert_with(|| UsageSummary {
+                device,
+                model,
+                period,
+                ..UsageSummary::default()
+            });
+
+        summary.requests += 1;
+        summary.prompt_tokens += record.prompt_tokens;
+        summary.completion_tokens += record.completion_tokens;
+        summary.duration_ms += record.duration_ms;
+    }
+
+    summaries.into_values().collect()
+}
+
+/// Parses a point in time given as RFC 3339, a date (`2025-06-01`) or a duration ago (`30m`, `24h`,
+/// `7d`).
+///
+/// # Returns
+/// The time in seconds since the Unix epoch.
+///
+pub fn parse_time(s: &str) -> Result<u64, String> {
+    use time::{format_description::well_known::Rfc3339, macros::format_description};
+
+    if let Some(secs) = parse_duration_ago(s)? {
+        return Ok(unix_now().saturating_sub(secs));
+    }
+
+    let at = time::OffsetDateTime::parse(s, &Rfc3339)
+        .or_else(|_| {
+            time::Date::parse(s, format_description!("[year]-[month]-[day]"))
+                .map(|date| date.midnight().assume_utc())
+        })
+        .map_err(|_| {
+            format!(
+                "'{}' is neither an RFC 3339 time, a date nor a duration like 24h",
+                s
+            )
+        })?;
+
+    u64::try_from(at.unix_timestamp()).map_err(|_| format!("'{}' is before 1970", s))
+}
+
+/// Parses a duration like `24h` into seconds, `None` if it isn't a duration.
+fn parse_duration_ago(s: &str) -> Result<Option<u64>, String> {
+    let unit = match s.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 86400,
+        Some('w') => 7 * 86400,
+        _ => return Ok(None),
+    };
+
+    let Ok(n) = s[..s.len() - 1].parse::<u64>() else {
+        return Ok(None);
+    };
+
+    n.checked_mul(unit)
+        .map(Some)
+        .ok_or_else(|| format!("'{}' is too long ago", s))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+    const DAY: u64 = 86400;
+
+    fn record(at: u64, device: &str, model: Option<&str>, completion_tokens: u64) -> UsageRecord {
+        UsageRecord {
+            at,
+            device: device.to_string(),
+            model: model.map(String::from),
+            prompt_tokens: 10,
+            completion_tokens,
+            duration_ms: 100,
+        }
+    }
+
+    fn records() -> Vec<UsageRecord> {
+        vec![
+            record(DAY, "a", Some("llama3"), 1),
+            record(DAY + HOUR, "b", Some("llama3"), 2),
+            record(DAY + HOUR + 1, "a", Some("mistral"), 4),
+            record(2 * DAY, "a", None, 8),
+        ]
+    }
+
+    #[test]
+    fn summarize_everything_together() {
+        let summaries = summarize(&records(), &UsageFilter::default(), &[]);
+
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].device, None);
+        assert_eq!(summaries[0].requests, 4);
+        assert_eq!(summaries[0].prompt_tokens, 40);
+        assert_eq!(summaries[0].completion_tokens, 15);
+        assert_eq!(summaries[0].duration_ms, 400);
+    }
+
+    #[test]
+    fn summarize_by_device_and_model() {
+        let summaries = summarize(
+            &records(),
+            &UsageFilter::default(),
+            &[UsageGrouping::Device, UsageGrouping::Model],
+        );
+        let groups: Vec<_> = summaries
+            .iter()
+            .map(|s| {
+                (
+                    s.device.as_deref().unwrap(),
+                    s.model.as_deref().unwrap(),
+                    s.completion_tokens,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            groups,
+            [
+                ("a", "-", 8),
+                ("a", "llama3", 1),
+                ("a", "mistral", 4),
+                ("b", "llama3", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn summarize_by_period() {
+        let by_hour = summarize(&records(), &UsageFilter::default(), &[UsageGrouping::Hour]);
+        let periods: Vec<_> = by_hour.iter().map(|s| (s.period, s.requests)).collect();
+
+        assert_eq!(
+            periods,
+            [(Some(DAY), 1), (Some(DAY + HOUR), 2), (Some(2 * DAY), 1)]
+        );
+
+        let by_day = summarize(&records(), &UsageFilter::default(), &[UsageGrouping::Day]);
+        let periods: Vec<_> = by_day.iter().map(|s| (s.period, s.requests)).collect();
+
+        assert_eq!(periods, [(Some(DAY), 3), (Some(2 * DAY), 1)]);
+    }
+
+    #[test]
+    fn summarize_filtered_records() {
+        let filter = UsageFilter {
+            since: Some(DAY + HOUR),
+            until: Some(2 * DAY),
+            device: Some("A".to_string()),
+            model: None,
+        };
+        let summaries = summarize(&records(), &filter, &[]);
+
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].requests, 1);
+        assert_eq!(summaries[0].completion_tokens, 4);
+
+        let filter = UsageFilter {
+            model: Some("llama3".to_string()),
+            ..UsageFilter::default()
+        };
+
+        assert_eq!(summarize(&records(), &filter, &[])[0].requests, 2);
+        assert!(summarize(&[], &UsageFilter::default(), &[]).is_empty());
+    }
+
+    #[test]
+    fn parse_time_formats() {
+        assert_eq!(parse_time("1970-01-02"), Ok(DAY));
+        assert_eq!(parse_time("1970-01-02T01:00:00Z"), Ok(DAY + HOUR));
+        assert_eq!(parse_time("1970-01-02T03:00:00+02:00"), Ok(DAY + HOUR));
+        assert!(parse_time("1969-12-31").is_err());
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("").is_err());
+    }
+
+    #[test]
+    fn parse_time_ago() {
+        for (s, secs) in [
+            ("30s", 30),
+            ("30m", 30 * 60),
+            ("24h", DAY),
+            ("7d", 7 * DAY),
+            ("2w", 14 * DAY),
+        ] {
+            let at = parse_time(s).unwrap();
+            let expected = unix_now() - secs;
+
+            assert!(at.abs_diff(expected) <= 1, "{}", s);
+        }
+
+        assert_eq!(
+            parse_time("0s").map(|at| at.abs_diff(unix_now()) <= 1),
+            Ok(true)
+        );
+        assert!(parse_time("99999999999999999w").is_err());
+        assert!(parse_time("-1d").is_err());
+        assert!(parse_time("d").is_err());
+    }
+}